@@ -8,17 +8,130 @@
 #define MAX_DEVS 4
 
 #define SIGHVI 10
+#define HVISOR_ZONE_CONFIG_MAGIC 0x4e5a5648 // "HVZN"
+#define HVISOR_ZONE_CONFIG_VERSION 5
+#define CONFIG_MAX_MEMORY_REGIONS 16
+#define CONFIG_MAX_MMIO_REGIONS 16
+#define CONFIG_MAX_INTERRUPTS 32
+#define CONFIG_MAX_SHMEMS 4
+#define CONFIG_SHMEM_NAME_LEN 32
+#define CONFIG_MAX_IRQ_MAPS 32
+#define CONFIG_MAX_ZONES 64
+// memory region backed by the hypervisor's guest memory pool
+#define HVISOR_CONFIG_ALLOC_PA (~0ULL)
+// entry_point taken from the header of the image, an arm64 Image
+#define HVISOR_CONFIG_IMAGE_ENTRY (~0ULL)
+// the zone gets a software GIC distributor and redistributors
+#define HVISOR_ZONE_FLAG_VGIC_EMUL (1U << 0)
+
+// flags of a memory region, bits of MemFlags in src/memory/mod.rs
+#define HVISOR_MEM_READ (1ULL << 0)
+#define HVISOR_MEM_WRITE (1ULL << 1)
+#define HVISOR_MEM_EXECUTE (1ULL << 2)
+
+// must be kept in sync with src/config.rs
+struct hvisor_memory_region {
+	__u64 ipa;
+	__u64 pa;
+	__u64 size;
+	__u64 flags;
+};
+
+// shared memory device attached to a zone, zones with the same name share it.
+// the doorbell page has IVPOSITION at 0x0 and DOORBELL at 0x4.
+struct hvisor_shmem_config {
+	char name[CONFIG_SHMEM_NAME_LEN];
+	__u64 ipa;
+	__u64 size;
+	__u64 doorbell_ipa;
+	__u32 irq; // raised in this zone when a peer rings it
+	__u32 padding;
+};
+
+// virtual SPI of a zone with HVISOR_ZONE_FLAG_VGIC_EMUL
+struct hvisor_irq_map {
+	__u32 virq; // INTID seen by the guest
+	__u32 pirq; // SPI raising it, 0 if only raised by the hypervisor
+};
+
 // used when start a zone.
-struct hvisor_zone_info {
+struct hvisor_zone_config {
+	__u32 magic;
+	__u32 version;
 	__u64 zone_id;
+	__u64 cpus;
+	__u64 entry_point;
 	__u64 image_phys_addr;
+	__u64 image_size;
 	__u64 dtb_phys_addr;
+	__u64 dtb_ipa;
+	__u32 num_memory_regions;
+	__u32 num_mmio_regions;
+	__u32 num_interrupts;
+	__u32 num_shmems;
+	struct hvisor_memory_region memory_regions[CONFIG_MAX_MEMORY_REGIONS];
+	struct hvisor_memory_region mmio_regions[CONFIG_MAX_MMIO_REGIONS];
+	__u32 interrupts[CONFIG_MAX_INTERRUPTS];
+	struct hvisor_shmem_config shmems[CONFIG_MAX_SHMEMS];
+	__u64 msg_peers; // bitmap of the zones it may send messages to
+	__u32 msg_irq; // raised when a message arrives, 0 for none
+	__u32 flags; // HVISOR_ZONE_FLAG_*
+	__u32 num_irq_maps;
+	__u32 padding;
+	// virtual SPIs other than interrupts[], which keep their INTID
+	struct hvisor_irq_map irq_maps[CONFIG_MAX_IRQ_MAPS];
+};
+// one entry of HVISOR_ZONE_LIST, state is one of HVISOR_ZONE_STATE_*
+struct hvisor_zone_info {
+	__u32 zone_id;
+	__u32 state;
+	__u64 cpus;
+	__u32 num_memory_regions;
+	__u32 num_irqs;
+	struct hvisor_memory_region memory_regions[CONFIG_MAX_MEMORY_REGIONS];
 };
+#define HVISOR_ZONE_STATE_CREATED 0
+#define HVISOR_ZONE_STATE_RUNNING 1
+#define HVISOR_ZONE_STATE_PAUSED 2
+#define HVISOR_ZONE_STATE_SHUTTING_DOWN 3
+#define HVISOR_ZONE_STATE_FAILED 4
+
+struct hvisor_zone_list_args {
+	__u64 max_zones;
+	struct hvisor_zone_info* zones;
+};
+// a message to or from another zone's mailbox, of at most HVISOR_MSG_MAX_SIZE bytes.
+// zone_id is the receiver for HVISOR_MSG_SEND and the sender for HVISOR_MSG_RECV,
+// which also sets len to the size of the message.
+struct hvisor_msg_args {
+	__u64 zone_id;
+	__u64 len;
+	void* buf;
+};
+#define HVISOR_MSG_MAX_SIZE 4096
+// size of the buffer the core dumps of failed zones are written to, 0 to free
+// it, and the irq raised in the root zone after each one, 0 for none. the
+// driver allocates the buffer, mapped at HVISOR_COREDUMP_MMAP_OFFSET.
+struct hvisor_coredump_args {
+	__u64 size;
+	__u32 irq;
+	__u32 padding;
+};
+#define HVISOR_COREDUMP_MMAP_OFFSET (1ULL << 32)
+// start of the core dump buffer, followed by an ELF core file of `size` bytes.
+// the hypervisor writes no dump until size is set back to 0.
+struct hvisor_coredump_header {
+	__u32 magic;
+	__u32 zone_id;
+	__u64 size;
+};
+#define HVISOR_COREDUMP_MAGIC 0x44435648 // "HVCD"
 struct hvisor_zone_load {
 	__u64 zone_id;
 	__u32 images_num;
 	__u32 padding;
 	struct hvisor_image_desc* images;
+	struct hvisor_zone_config* config;
 };
 struct hvisor_image_desc {
 	__u64 source_address; // image address in user space
@@ -63,6 +176,13 @@ struct virtio_bridge {
 #define HVISOR_FINISH_REQ _IO(1, 2)		  // finish one virtio req
 #define HVISOR_ZONE_START _IOW(1, 3, struct hvisor_zone_load*)
 #define HVISOR_ZONE_SHUTDOWN _IOW(1, 4, __u64)
+#define HVISOR_ZONE_LIST _IOR(1, 5, struct hvisor_zone_list_args*)
+#define HVISOR_ZONE_PAUSE _IOW(1, 6, __u64)
+#define HVISOR_ZONE_RESUME _IOW(1, 7, __u64)
+#define HVISOR_ZONE_REBOOT _IOW(1, 8, __u64)
+#define HVISOR_MSG_SEND _IOW(1, 9, struct hvisor_msg_args*)
+#define HVISOR_MSG_RECV _IOWR(1, 10, struct hvisor_msg_args*)
+#define HVISOR_COREDUMP_SETUP _IOW(1, 11, struct hvisor_coredump_args*)
 // hypercall
 #define HVISOR_CALL_HVC        "hvc #0x4856"
 
@@ -70,6 +190,13 @@ struct virtio_bridge {
 #define HVISOR_HC_FINISH_REQ 1
 #define HVISOR_HC_START_ZONE 2
 #define HVISOR_HC_SHUTDOWN_ZONE 3
+#define HVISOR_HC_ZONE_LIST 4
+#define HVISOR_HC_PAUSE_ZONE 5
+#define HVISOR_HC_RESUME_ZONE 6
+#define HVISOR_HC_REBOOT_ZONE 7
+#define HVISOR_HC_MSG_SEND 8
+#define HVISOR_HC_MSG_RECV 9
+#define HVISOR_HC_COREDUMP_SETUP 10
 
 static inline __u64 hvisor_call(__u64 code)
 {
@@ -97,5 +224,33 @@ static inline __u64 hvisor_call_arg0(__u64 code, __u64 arg0)
 }
 
 
+static inline __u64 hvisor_call_arg2(__u64 code, __u64 arg0, __u64 arg1)
+{
+	register __u64 code_result asm("x0") = code;
+	register __u64 __arg0 asm("x1") = arg0;
+	register __u64 __arg1 asm("x2") = arg1;
+
+	asm volatile(
+		HVISOR_CALL_HVC
+		: "=r" (code_result)
+		: "r" (code_result), "r" (__arg0), "r" (__arg1)
+		: "memory");
+	return code_result;
+}
+
+static inline __u64 hvisor_call_arg3(__u64 code, __u64 arg0, __u64 arg1, __u64 arg2)
+{
+	register __u64 code_result asm("x0") = code;
+	register __u64 __arg0 asm("x1") = arg0;
+	register __u64 __arg1 asm("x2") = arg1;
+	register __u64 __arg2 asm("x3") = arg2;
+
+	asm volatile(
+		HVISOR_CALL_HVC
+		: "=r" (code_result)
+		: "r" (code_result), "r" (__arg0), "r" (__arg1), "r" (__arg2)
+		: "memory");
+	return code_result;
+}
 
 #endif /* __HVISOR_H */
@@ -16,10 +16,14 @@
 #include <linux/vmalloc.h>
 #include <asm/cacheflush.h>
 #include <linux/string.h>
+#include <linux/mutex.h>
 
 struct virtio_bridge *virtio_bridge;
 int hvisor_irq;
 static struct task_struct *task = NULL;
+static DEFINE_MUTEX(coredump_lock);
+static void *coredump_buf;
+static size_t coredump_size;
 
 // initial virtio el2 shared region
 static int hvisor_init_virtio(void)
@@ -47,7 +51,7 @@ static int hvisor_finish_req(void)
     return 0;
 }
 
-static int load_image(struct hvisor_image_desc __user *arg, __u64 *phys_addr) {
+static int load_image(struct hvisor_image_desc __user *arg, __u64 *phys_addr, __u64 *image_size) {
     struct hvisor_image_desc image;
     struct vm_struct *vma;
     int err = 0;
@@ -56,6 +60,8 @@ static int load_image(struct hvisor_image_desc __user *arg, __u64 *phys_addr) {
     if (copy_from_user(&image, arg, sizeof(struct hvisor_image_desc)))
         return -EFAULT;
     *phys_addr = image.target_address;
+    if (image_size)
+        *image_size = image.size;
     phys_start = image.target_address & PAGE_MASK;
     offset_in_page = image.target_address & ~PAGE_MASK;
     size = PAGE_ALIGN(image.size + offset_in_page);
@@ -87,25 +93,132 @@ out_unmap_vma:
 
 static int hvisor_zone_start(struct hvisor_zone_load __user* arg) {
     struct hvisor_zone_load zone_load;
-    struct hvisor_image_desc __user *images = arg->images;
-    struct hvisor_zone_info *zone_info;
-    zone_info = kmalloc(sizeof(struct hvisor_zone_info), GFP_KERNEL);
+    struct hvisor_image_desc __user *images;
+    struct hvisor_zone_config *zone_config;
+    zone_config = kmalloc(sizeof(struct hvisor_zone_config), GFP_KERNEL);
 
-    if (zone_info == NULL) {
-        pr_err("hvisor: failed to allocate memory for zone_info\n");
+    if (zone_config == NULL) {
+        pr_err("hvisor: failed to allocate memory for zone_config\n");
         return -ENOMEM;
     }
     int err = 0;
-    if (copy_from_user(&zone_load, arg, sizeof(zone_load)))
-        return -EFAULT;
-	zone_info->zone_id = zone_load.zone_id;
+    if (copy_from_user(&zone_load, arg, sizeof(zone_load)) ||
+        copy_from_user(zone_config, zone_load.config, sizeof(*zone_config))) {
+        err = -EFAULT;
+        goto out;
+    }
+    // the kernel, then the device tree
+    if (zone_load.images_num != 2) {
+        err = -EINVAL;
+        goto out;
+    }
+    images = zone_load.images;
+	zone_config->zone_id = zone_load.zone_id;
     // load image
-    err = load_image(images, &zone_info->image_phys_addr);
+    err = load_image(images, &zone_config->image_phys_addr, &zone_config->image_size);
+    if (err)
+        goto out;
     // load dtb
-    err = load_image(++images, &zone_info->dtb_phys_addr);
+    err = load_image(++images, &zone_config->dtb_phys_addr, NULL);
     if (err)
-        return err;
-    err = hvisor_call_arg1(HVISOR_HC_START_ZONE, __pa(zone_info));
+        goto out;
+    err = hvisor_call_arg1(HVISOR_HC_START_ZONE, __pa(zone_config));
+out:
+    kfree(zone_config);
+    return err;
+}
+
+// copy the zone list from el2 to user space, return the number of zones.
+static int hvisor_zone_list(struct hvisor_zone_list_args __user* arg) {
+    struct hvisor_zone_list_args args;
+    struct hvisor_zone_info *zones;
+    int ret;
+    if (copy_from_user(&args, arg, sizeof(args)))
+        return -EFAULT;
+    args.max_zones = min_t(__u64, args.max_zones, CONFIG_MAX_ZONES);
+    zones = kmalloc_array(args.max_zones, sizeof(*zones), GFP_KERNEL);
+    if (zones == NULL)
+        return -ENOMEM;
+    ret = hvisor_call_arg2(HVISOR_HC_ZONE_LIST, __pa(zones), args.max_zones);
+    if (ret > 0 && copy_to_user(args.zones, zones, min_t(size_t, ret, args.max_zones) * sizeof(*zones)))
+        ret = -EFAULT;
+    kfree(zones);
+    return ret;
+}
+
+// send a message to the mailbox of another zone.
+static int hvisor_msg_send(struct hvisor_msg_args __user* arg) {
+    struct hvisor_msg_args args;
+    void *buf;
+    int ret;
+    if (copy_from_user(&args, arg, sizeof(args)))
+        return -EFAULT;
+    if (args.len > HVISOR_MSG_MAX_SIZE)
+        return -E2BIG;
+    buf = kmalloc(HVISOR_MSG_MAX_SIZE, GFP_KERNEL);
+    if (buf == NULL)
+        return -ENOMEM;
+    if (copy_from_user(buf, args.buf, args.len)) {
+        kfree(buf);
+        return -EFAULT;
+    }
+    ret = hvisor_call_arg3(HVISOR_HC_MSG_SEND, args.zone_id, __pa(buf), args.len);
+    kfree(buf);
+    return ret;
+}
+
+// take the oldest message of this zone's mailbox, set the sender and the size.
+static int hvisor_msg_recv(struct hvisor_msg_args __user* arg) {
+    struct hvisor_msg_args args;
+    void *buf;
+    long ret;
+    if (copy_from_user(&args, arg, sizeof(args)))
+        return -EFAULT;
+    buf = kmalloc(HVISOR_MSG_MAX_SIZE, GFP_KERNEL);
+    if (buf == NULL)
+        return -ENOMEM;
+    ret = hvisor_call_arg2(HVISOR_HC_MSG_RECV, __pa(buf),
+                           min_t(__u64, args.len, HVISOR_MSG_MAX_SIZE));
+    if (ret >= 0) {
+        args.zone_id = ret >> 32;
+        args.len = ret & 0xffffffff;
+        if (copy_to_user(args.buf, buf, args.len) || copy_to_user(arg, &args, sizeof(args)))
+            ret = -EFAULT;
+        else
+            ret = 0;
+    }
+    kfree(buf);
+    return ret;
+}
+
+// let the hypervisor write core dumps to a new zeroed buffer, or to none if
+// the size is 0, and free the previous one.
+static int hvisor_coredump_setup(struct hvisor_coredump_args __user* arg) {
+    struct hvisor_coredump_args args;
+    void *buf = NULL;
+    size_t size;
+    int err;
+    if (copy_from_user(&args, arg, sizeof(args)))
+        return -EFAULT;
+    size = PAGE_ALIGN(args.size);
+    if (size) {
+        buf = alloc_pages_exact(size, GFP_KERNEL | __GFP_ZERO);
+        if (buf == NULL)
+            return -ENOMEM;
+    }
+    mutex_lock(&coredump_lock);
+    err = hvisor_call_arg3(HVISOR_HC_COREDUMP_SETUP, buf ? __pa(buf) : 0, size, args.irq);
+    if (err) {
+        if (buf)
+            free_pages_exact(buf, size);
+    } else {
+        // pages still mapped by user space are freed once unmapped
+        if (coredump_buf)
+            free_pages_exact(coredump_buf, coredump_size);
+        coredump_buf = buf;
+        coredump_size = size;
+    }
+    mutex_unlock(&coredump_lock);
     return err;
 }
 
@@ -125,6 +238,27 @@ static long hvisor_ioctl(struct file *file, unsigned int ioctl,
     case HVISOR_ZONE_SHUTDOWN:
         err = hvisor_call_arg1(HVISOR_HC_SHUTDOWN_ZONE, arg);
         break;
+    case HVISOR_ZONE_PAUSE:
+        err = hvisor_call_arg1(HVISOR_HC_PAUSE_ZONE, arg);
+        break;
+    case HVISOR_ZONE_RESUME:
+        err = hvisor_call_arg1(HVISOR_HC_RESUME_ZONE, arg);
+        break;
+    case HVISOR_ZONE_REBOOT:
+        err = hvisor_call_arg1(HVISOR_HC_REBOOT_ZONE, arg);
+        break;
+    case HVISOR_ZONE_LIST:
+        err = hvisor_zone_list((struct hvisor_zone_list_args __user*) arg);
+        break;
+    case HVISOR_MSG_SEND:
+        err = hvisor_msg_send((struct hvisor_msg_args __user*) arg);
+        break;
+    case HVISOR_MSG_RECV:
+        err = hvisor_msg_recv((struct hvisor_msg_args __user*) arg);
+        break;
+    case HVISOR_COREDUMP_SETUP:
+        err = hvisor_coredump_setup((struct hvisor_coredump_args __user*) arg);
+        break;
     case HVISOR_FINISH_REQ:
         err = hvisor_finish_req();
         break;
@@ -135,11 +269,28 @@ static long hvisor_ioctl(struct file *file, unsigned int ioctl,
     return err;
 }
 
+// map the core dump buffer, page by page so that it outlives the mapping.
+static int hvisor_map_coredump(struct vm_area_struct *vma)
+{
+    unsigned long offset;
+    int err = 0;
+    mutex_lock(&coredump_lock);
+    if (coredump_buf == NULL || vma->vm_end - vma->vm_start > coredump_size)
+        err = -EINVAL;
+    for (offset = 0; !err && vma->vm_start + offset < vma->vm_end; offset += PAGE_SIZE)
+        err = vm_insert_page(vma, vma->vm_start + offset, virt_to_page(coredump_buf + offset));
+    mutex_unlock(&coredump_lock);
+    return err;
+}
+
 // Kernel mmap handler
 static int hvisor_map(struct file * filp, struct vm_area_struct *vma)
 {
     unsigned long phys;
 
+    if (vma->vm_pgoff == HVISOR_COREDUMP_MMAP_OFFSET >> PAGE_SHIFT)
+        return hvisor_map_coredump(vma);
+
     // virtio_bridge must be aligned to one page.
     phys = virt_to_phys(virtio_bridge);
     // vma->vm_flags |= (VM_IO | VM_LOCKED | (VM_DONTEXPAND | VM_DONTDUMP)); Not sure should we add this line.
@@ -226,6 +377,11 @@ static void __exit hvisor_exit(void)
 
     free_irq(hvisor_irq,(void *)(irq_handler));
 
+    if (coredump_buf) {
+        hvisor_call_arg3(HVISOR_HC_COREDUMP_SETUP, 0, 0, 0);
+        free_pages_exact(coredump_buf, coredump_size);
+    }
+
     ClearPageReserved(virt_to_page(virtio_bridge));
 
     free_pages((unsigned long)virtio_bridge, 0);
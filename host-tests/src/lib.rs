@@ -0,0 +1,72 @@
+//! Host-side tests of hvisor.
+//!
+//! The modules under test are included from `../src` by path. The few crate
+//! items they use besides each other are stood in for here.
+
+// the included modules are only partly used by the tests, and are linted
+// with the hypervisor
+#![allow(dead_code)]
+
+#[macro_use]
+extern crate alloc;
+#[macro_use]
+extern crate log;
+
+#[macro_use]
+#[allow(clippy::all)]
+#[path = "../../src/error.rs"]
+mod error;
+
+mod consts {
+    pub use crate::memory::PAGE_SIZE;
+    pub const MAX_CPU_NUM: usize = 4;
+    pub const GUEST_MEM_POOL_SIZE: usize = 0x800_0000;
+
+    pub fn hv_start() -> usize {
+        0x4000_0000
+    }
+
+    pub fn hv_end() -> usize {
+        0x4040_0000
+    }
+
+    pub fn guest_mem_pool_start() -> usize {
+        hv_end()
+    }
+}
+
+mod percpu {
+    pub struct CpuSet(u64);
+
+    impl CpuSet {
+        pub fn new(_max_cpu_id: usize, bitmap: u64) -> Self {
+            Self(bitmap)
+        }
+
+        pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+            (0..64).filter(|id| self.0 & (1 << id) != 0)
+        }
+    }
+}
+
+#[allow(clippy::all)]
+#[path = "../../src/config.rs"]
+mod config;
+#[allow(clippy::all)]
+#[path = "../../src/dtb.rs"]
+mod dtb;
+#[cfg(test)]
+mod loader;
+mod memory;
+#[allow(clippy::all)]
+#[path = "../../src/platform/mod.rs"]
+mod platform;
+
+#[cfg(test)]
+mod tests {
+    mod config;
+    mod dtb;
+    mod elf;
+    mod image;
+    mod ram;
+}
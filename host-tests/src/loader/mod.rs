@@ -0,0 +1,19 @@
+// the readers of src/loader/mod.rs, whose loading needs a zone
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+#[allow(clippy::all)]
+#[path = "../../../src/loader/elf.rs"]
+pub mod elf;
+#[allow(clippy::all)]
+#[path = "../../../src/loader/image.rs"]
+pub mod image;
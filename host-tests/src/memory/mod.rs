@@ -0,0 +1,45 @@
+use alloc::vec::Vec;
+
+use crate::error::HvResult;
+
+pub const PAGE_SIZE: usize = 0x1000;
+
+bitflags::bitflags! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub struct MemFlags: u64 {
+        const READ          = 1 << 0;
+        const WRITE         = 1 << 1;
+        const EXECUTE       = 1 << 2;
+        const DMA           = 1 << 3;
+        const IO            = 1 << 4;
+        const COMMUNICATION = 1 << 5;
+        const LOADABLE      = 1 << 6;
+        const ROOTSHARED    = 1 << 7;
+        const NO_HUGEPAGES  = 1 << 8;
+        const USER          = 1 << 9;
+    }
+}
+
+/// Guest memory pool frames, from the heap.
+pub struct Frame(Vec<u8>);
+
+impl Frame {
+    pub fn new_guest(frame_count: usize, _align_log2: usize) -> HvResult<Self> {
+        Ok(Self(vec![0; frame_count * PAGE_SIZE]))
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn as_slice_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+}
+
+#[allow(clippy::all)]
+#[path = "../../../src/memory/addr.rs"]
+pub mod addr;
+#[allow(clippy::all)]
+#[path = "../../../src/memory/ram.rs"]
+pub mod ram;
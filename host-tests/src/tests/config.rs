@@ -0,0 +1,61 @@
+use crate::config::*;
+use crate::memory::MemFlags;
+
+/// A zone on cpu 0 with 256 MB of RAM at 0x5000_0000 and its dtb right above.
+fn zone() -> HvZoneConfig {
+    // all zero is a valid, empty config
+    let mut config: HvZoneConfig = unsafe { core::mem::zeroed() };
+    config.magic = HV_ZONE_CONFIG_MAGIC;
+    config.version = HV_ZONE_CONFIG_VERSION;
+    config.cpus = 1;
+    config.memory_regions[0] = HvConfigMemoryRegion {
+        ipa: 0x5000_0000,
+        pa: 0x5000_0000,
+        size: 0x1000_0000,
+        flags: (MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE).bits(),
+    };
+    config.num_memory_regions = 1;
+    config.dtb_ipa = 0x6000_0000;
+    config.interrupts[0] = 40;
+    config.num_interrupts = 1;
+    config
+}
+
+fn with_shmem(mut config: HvZoneConfig, irq: u32) -> HvZoneConfig {
+    let shmem = &mut config.shmems[0];
+    shmem.name[..3].copy_from_slice(b"net");
+    shmem.ipa = 0x7000_0000;
+    shmem.size = 0x10_0000;
+    shmem.doorbell_ipa = 0x7010_0000;
+    shmem.irq = irq;
+    config.num_shmems = 1;
+    config
+}
+
+#[test]
+fn distinct_doorbells_are_accepted() {
+    let mut config = with_shmem(zone(), 41);
+    config.msg_irq = 42;
+    assert!(config.validate(0x1000).is_ok());
+    assert_eq!(config.doorbell_irqs().collect::<Vec<_>>(), [41, 42]);
+}
+
+#[test]
+fn shmem_doorbell_on_a_device_irq_is_refused() {
+    assert!(with_shmem(zone(), 40).validate(0x1000).is_err());
+}
+
+#[test]
+fn message_irq_on_a_device_irq_is_refused() {
+    let mut config = zone();
+    config.msg_irq = 40;
+    assert!(config.validate(0x1000).is_err());
+}
+
+#[test]
+fn doorbell_must_be_an_spi() {
+    assert!(with_shmem(zone(), 27).validate(0x1000).is_err());
+    let mut config = zone();
+    config.msg_irq = 1020;
+    assert!(config.validate(0x1000).is_err());
+}
@@ -0,0 +1,257 @@
+// the reserved memory lists are lists of ranges, even with a single one
+#![allow(clippy::single_range_in_vec_init)]
+
+use fdt::writer::FdtWriter;
+use fdt::{Fdt, FdtError};
+
+use crate::config::{HvConfigMemoryRegion, HvZoneConfig};
+use crate::dtb::{edit, root_zone_dtb, RootDtbEditor, ZoneDtbEditor};
+use crate::platform::{BoardDevice, ZoneReservation};
+
+fn device(w: &mut FdtWriter, name: &str, addr: u32) -> Result<(), FdtError> {
+    w.begin_node(&format!("{}@{:x}", name, addr))?;
+    w.property_cells("reg", &[0, addr, 0, 0x1000])?;
+    w.end_node()
+}
+
+/// A board with ram at 0x4000_0000, two cpus, an uart, an rtc, the gic, a
+/// virtio device, a bus holding a serial port and a gpio controller, and an
+/// initrd. With `reserved_memory`, a pool at 0x5000_0000, another one at
+/// 0x5800_0000 and a dynamically placed one are reserved.
+fn board(buf: &mut [u8], reserved_memory: bool) -> usize {
+    let mut w = FdtWriter::new(buf).unwrap();
+    w.begin_node("").unwrap();
+    w.property_u32("#address-cells", 2).unwrap();
+    w.property_u32("#size-cells", 2).unwrap();
+    w.begin_node("memory@40000000").unwrap();
+    w.property_str("device_type", "memory").unwrap();
+    w.property_cells("reg", &[0, 0x4000_0000, 0, 0x2000_0000])
+        .unwrap();
+    w.end_node().unwrap();
+    w.begin_node("cpus").unwrap();
+    w.property_u32("#address-cells", 1).unwrap();
+    w.property_u32("#size-cells", 0).unwrap();
+    for id in 0..2 {
+        w.begin_node(&format!("cpu@{}", id)).unwrap();
+        w.property_str("device_type", "cpu").unwrap();
+        w.property_u32("reg", id).unwrap();
+        w.end_node().unwrap();
+    }
+    w.begin_node("cpu-map").unwrap();
+    w.end_node().unwrap();
+    w.end_node().unwrap();
+    device(&mut w, "uart", 0x900_0000).unwrap();
+    device(&mut w, "rtc", 0x901_0000).unwrap();
+    w.begin_node("intc@8000000").unwrap();
+    w.property_cells("reg", &[0, 0x800_0000, 0, 0x10000])
+        .unwrap();
+    w.property_empty("interrupt-controller").unwrap();
+    w.end_node().unwrap();
+    device(&mut w, "virtio_mmio", 0xa00_0000).unwrap();
+    w.begin_node("soc").unwrap();
+    w.property_str("compatible", "simple-bus").unwrap();
+    w.property_u32("#address-cells", 2).unwrap();
+    w.property_u32("#size-cells", 2).unwrap();
+    w.property_empty("ranges").unwrap();
+    device(&mut w, "serial", 0x1c09_0000).unwrap();
+    device(&mut w, "gpio", 0x1c0a_0000).unwrap();
+    w.end_node().unwrap();
+    if reserved_memory {
+        w.begin_node("reserved-memory").unwrap();
+        w.property_u32("#address-cells", 2).unwrap();
+        w.property_u32("#size-cells", 2).unwrap();
+        w.property_empty("ranges").unwrap();
+        for addr in [0x5000_0000, 0x5800_0000] {
+            w.begin_node(&format!("pool@{:x}", addr)).unwrap();
+            w.property_cells("reg", &[0, addr, 0, 0x10_0000]).unwrap();
+            w.end_node().unwrap();
+        }
+        w.begin_node("linux,cma").unwrap();
+        w.property_cells("size", &[0, 0x100_0000]).unwrap();
+        w.property_empty("reusable").unwrap();
+        w.end_node().unwrap();
+        w.end_node().unwrap();
+    }
+    w.begin_node("chosen").unwrap();
+    w.property_str("bootargs", "console=ttyAMA0").unwrap();
+    w.property_cells("linux,initrd-start", &[0, 0x4800_0000])
+        .unwrap();
+    w.property_cells("linux,initrd-end", &[0, 0x4880_0000])
+        .unwrap();
+    w.end_node().unwrap();
+    w.end_node().unwrap();
+    w.finish().unwrap()
+}
+
+fn region(ipa: u64, size: u64) -> HvConfigMemoryRegion {
+    HvConfigMemoryRegion {
+        ipa,
+        pa: ipa,
+        size,
+        flags: 0,
+    }
+}
+
+/// A zone on the second cpu with 16 MB of RAM at 0x5000_0000, the uart and
+/// the serial port of the bus.
+fn zone() -> HvZoneConfig {
+    let mut config: HvZoneConfig = unsafe { core::mem::zeroed() };
+    config.cpus = 0b10;
+    config.num_memory_regions = 1;
+    config.memory_regions[0] = region(0x5000_0000, 0x100_0000);
+    config.num_mmio_regions = 2;
+    config.mmio_regions[0] = region(0x900_0000, 0x1000);
+    config.mmio_regions[1] = region(0x1c09_0000, 0x1000);
+    config
+}
+
+const RTC: BoardDevice = BoardDevice {
+    name: "rtc",
+    paddr: 0x901_0000,
+    size: 0x1000,
+    irqs: &[34],
+};
+
+const GPIO: BoardDevice = BoardDevice {
+    name: "gpio",
+    paddr: 0x1c0a_0000,
+    size: 0x1000,
+    irqs: &[39],
+};
+
+static BOARD_DEVICES: &[BoardDevice] = &[RTC, GPIO];
+
+#[test]
+fn zone_dtb_keeps_assigned_resources() {
+    let mut buf = vec![0; 0x2000];
+    let size = board(&mut buf, false);
+    let fdt = Fdt::new(&buf[..size]).unwrap();
+    let config = zone();
+
+    let mut out = vec![0; 0x2000];
+    let size = edit(&fdt, &mut out, &mut ZoneDtbEditor::new(&config, &fdt)).unwrap();
+    let zone = Fdt::new(&out[..size]).unwrap();
+    let ram = zone.memory().regions().next().unwrap();
+    assert_eq!(ram.starting_address as usize, 0x5000_0000);
+    assert_eq!(ram.size, Some(0x100_0000));
+    assert!(zone.find_node("/memory@40000000").is_none());
+    assert_eq!(zone.cpus().count(), 1);
+    assert!(zone.find_node("/cpus/cpu-map").is_none());
+    let kept = [
+        "/uart@9000000",
+        "/intc@8000000",
+        "/virtio_mmio@a000000",
+        "/soc/serial@1c090000",
+        "/chosen",
+    ];
+    for kept in kept {
+        assert!(zone.find_node(kept).is_some(), "{} dropped", kept);
+    }
+    for dropped in ["/rtc@9010000", "/soc/gpio@1c0a0000"] {
+        assert!(zone.find_node(dropped).is_none(), "{} kept", dropped);
+    }
+}
+
+#[test]
+fn zone_dtb_drops_root_initrd_and_reserved_memory() {
+    let mut buf = vec![0; 0x2000];
+    let size = board(&mut buf, true);
+    let fdt = Fdt::new(&buf[..size]).unwrap();
+    let config = zone();
+
+    let mut out = vec![0; 0x2000];
+    let size = edit(&fdt, &mut out, &mut ZoneDtbEditor::new(&config, &fdt)).unwrap();
+    let zone = Fdt::new(&out[..size]).unwrap();
+    let chosen = zone.find_node("/chosen").unwrap();
+    assert_eq!(
+        chosen.property("bootargs").and_then(|p| p.as_str()),
+        Some("console=ttyAMA0")
+    );
+    assert!(chosen.property("linux,initrd-start").is_none());
+    assert!(chosen.property("linux,initrd-end").is_none());
+    assert!(zone.find_node("/reserved-memory/pool@50000000").is_some());
+    assert!(zone.find_node("/reserved-memory/linux,cma").is_some());
+    assert!(zone.find_node("/reserved-memory/pool@58000000").is_none());
+}
+
+#[test]
+fn root_dtb_reserves_hypervisor_memory() {
+    for reserved_memory in [false, true] {
+        let mut buf = vec![0; 0x2000];
+        let size = board(&mut buf, reserved_memory);
+        let fdt = Fdt::new(&buf[..size]).unwrap();
+        let reserved = vec![(String::from("hvisor"), 0x4000_0000..0x4040_0000)];
+
+        let mut out = vec![0; 0x2000];
+        let mut editor = RootDtbEditor::new(&fdt, reserved, Vec::new());
+        let size = edit(&fdt, &mut out, &mut editor).unwrap();
+        let root = Fdt::new(&out[..size]).unwrap();
+        let node = root.find_node("/reserved-memory/hvisor@40000000").unwrap();
+        let reg = node.reg().unwrap().next().unwrap();
+        assert_eq!(reg.starting_address as usize, 0x4000_0000);
+        assert_eq!(reg.size, Some(0x40_0000));
+        assert!(node.property("no-map").is_some());
+        assert_eq!(root.find_all_nodes("/reserved-memory").count(), 1);
+        assert_eq!(root.cpus().count(), 2);
+        assert!(root.find_node("/rtc@9010000").is_some());
+        let chosen = root.find_node("/chosen").unwrap();
+        assert!(chosen.property("linux,initrd-start").is_some());
+    }
+}
+
+#[test]
+fn root_dtb_hides_devices_of_other_zones() {
+    let mut buf = vec![0; 0x2000];
+    let size = board(&mut buf, false);
+    let fdt = Fdt::new(&buf[..size]).unwrap();
+
+    let mut out = vec![0; 0x2000];
+    let mut editor = RootDtbEditor::new(&fdt, Vec::new(), vec![&RTC, &GPIO]);
+    let size = edit(&fdt, &mut out, &mut editor).unwrap();
+    let root = Fdt::new(&out[..size]).unwrap();
+    for kept in ["/uart@9000000", "/soc/serial@1c090000"] {
+        assert!(root.find_node(kept).is_some(), "{} dropped", kept);
+    }
+    for dropped in ["/rtc@9010000", "/soc/gpio@1c0a0000"] {
+        assert!(root.find_node(dropped).is_none(), "{} kept", dropped);
+    }
+}
+
+#[test]
+fn root_zone_dtb_reserves_other_zones() {
+    let mut buf = vec![0; 0x2000];
+    let size = board(&mut buf, true);
+    let fdt = Fdt::new(&buf[..size]).unwrap();
+    let zones = [ZoneReservation {
+        zone_id: 1,
+        devices: &["rtc"],
+        memory: &[0x5000_0000..0x5800_0000],
+    }];
+
+    let frame = root_zone_dtb(&fdt, BOARD_DEVICES, &zones).unwrap();
+    let root = Fdt::new(frame.as_slice()).unwrap();
+    let reserved = [
+        "/reserved-memory/hvisor@40000000",
+        "/reserved-memory/hvisor-guest-pool@40400000",
+        "/reserved-memory/hvisor-zone1@50000000",
+    ];
+    for reserved in reserved {
+        let node = root.find_node(reserved).unwrap();
+        assert!(node.property("no-map").is_some(), "{} mapped", reserved);
+    }
+    assert!(root.find_node("/rtc@9010000").is_none());
+    assert!(root.find_node("/soc/gpio@1c0a0000").is_some());
+}
+
+#[test]
+fn root_zone_dtb_rejects_unknown_devices() {
+    let mut buf = vec![0; 0x2000];
+    let size = board(&mut buf, false);
+    let fdt = Fdt::new(&buf[..size]).unwrap();
+    let zones = [ZoneReservation {
+        zone_id: 1,
+        devices: &["pl011"],
+        memory: &[],
+    }];
+    assert!(root_zone_dtb(&fdt, BOARD_DEVICES, &zones).is_err());
+}
@@ -0,0 +1,172 @@
+use crate::config::*;
+use crate::loader::elf::*;
+use crate::memory::MemFlags;
+
+/// A `PT_LOAD` segment: `(paddr, filesz, memsz, flags)`.
+type Load = (u64, u64, u64, u32);
+
+/// An aarch64 ELF64 executable entered at `entry` with the data of each
+/// segment following the headers.
+fn elf(entry: u64, loads: &[Load]) -> Vec<u8> {
+    let phoff = ELF64_EHDR_SIZE;
+    let mut offset = (phoff + loads.len() * ELF64_PHDR_SIZE) as u64;
+    let mut image = vec![0u8; offset as usize];
+    image[..4].copy_from_slice(ELF_MAGIC);
+    image[4] = ELFCLASS64;
+    image[5] = ELFDATA2LSB;
+    image[0x10..0x12].copy_from_slice(&2u16.to_le_bytes()); // ET_EXEC
+    image[0x12..0x14].copy_from_slice(&EM_HOST.to_le_bytes());
+    image[0x18..0x20].copy_from_slice(&entry.to_le_bytes());
+    image[0x20..0x28].copy_from_slice(&(phoff as u64).to_le_bytes());
+    image[0x36..0x38].copy_from_slice(&(ELF64_PHDR_SIZE as u16).to_le_bytes());
+    image[0x38..0x3a].copy_from_slice(&(loads.len() as u16).to_le_bytes());
+    for (i, &(paddr, filesz, memsz, flags)) in loads.iter().enumerate() {
+        let phdr = &mut image[phoff + i * ELF64_PHDR_SIZE..][..ELF64_PHDR_SIZE];
+        phdr[..4].copy_from_slice(&PT_LOAD.to_le_bytes());
+        phdr[0x4..0x8].copy_from_slice(&flags.to_le_bytes());
+        phdr[0x8..0x10].copy_from_slice(&offset.to_le_bytes());
+        phdr[0x18..0x20].copy_from_slice(&paddr.to_le_bytes());
+        phdr[0x20..0x28].copy_from_slice(&filesz.to_le_bytes());
+        phdr[0x28..0x30].copy_from_slice(&memsz.to_le_bytes());
+        offset += filesz;
+    }
+    image.resize(offset as usize, 0xaa);
+    image
+}
+
+/// A zone with 1 MB of executable RAM at 0x4000_0000 and 1 MB of data RAM
+/// above it.
+fn zone() -> HvZoneConfig {
+    let mut config: HvZoneConfig = unsafe { core::mem::zeroed() };
+    config.entry_point = HV_CONFIG_IMAGE_ENTRY;
+    let rw = MemFlags::READ | MemFlags::WRITE;
+    for (i, (ipa, flags)) in [(0x4000_0000, rw | MemFlags::EXECUTE), (0x4010_0000, rw)]
+        .into_iter()
+        .enumerate()
+    {
+        config.memory_regions[i] = HvConfigMemoryRegion {
+            ipa,
+            pa: ipa,
+            size: 0x10_0000,
+            flags: flags.bits(),
+        };
+    }
+    config.num_memory_regions = 2;
+    config
+}
+
+fn set_u64(image: &mut [u8], offset: usize, value: u64) {
+    image[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+}
+
+#[test]
+fn segments_are_read() {
+    let image = elf(
+        0x4000_0000,
+        &[
+            (0x4000_0000, 0x100, 0x100, PF_X),
+            (0x4010_0000, 0x10, 0x1000, 0),
+        ],
+    );
+    let elf = Elf64::parse(&image).unwrap().unwrap();
+    assert_eq!(elf.entry, 0x4000_0000);
+    let segments: Vec<_> = elf
+        .segments()
+        .map(|s| (s.ipa, s.data.len(), s.bss, s.executable))
+        .collect();
+    assert_eq!(
+        segments,
+        [
+            (0x4000_0000, 0x100, 0, true),
+            (0x4010_0000, 0x10, 0xff0, false)
+        ]
+    );
+    assert!(elf.check(&zone()).is_ok());
+}
+
+#[test]
+fn other_formats_are_not_elf() {
+    assert!(Elf64::parse(b"MZ\0\0").unwrap().is_none());
+    assert!(Elf64::parse(&[]).unwrap().is_none());
+}
+
+#[test]
+fn truncated_header_is_refused() {
+    let image = elf(0x4000_0000, &[]);
+    assert!(Elf64::parse(&image[..4]).is_err());
+    assert!(Elf64::parse(&image[..ELF64_EHDR_SIZE - 1]).is_err());
+}
+
+#[test]
+fn other_class_or_machine_is_refused() {
+    let mut image = elf(0x4000_0000, &[]);
+    image[4] = 1; // ELFCLASS32
+    assert!(Elf64::parse(&image).is_err());
+    let mut image = elf(0x4000_0000, &[]);
+    image[0x12] = 62; // EM_X86_64
+    assert!(Elf64::parse(&image).is_err());
+}
+
+#[test]
+fn program_headers_past_the_file_are_refused() {
+    let image = elf(0x4000_0000, &[(0x4000_0000, 0x100, 0x100, PF_X)]);
+    assert!(Elf64::parse(&image[..ELF64_EHDR_SIZE + ELF64_PHDR_SIZE - 1]).is_err());
+    let mut image = image;
+    set_u64(&mut image, 0x20, u64::MAX - 8);
+    assert!(Elf64::parse(&image).is_err());
+}
+
+#[test]
+fn malformed_segments_are_refused() {
+    let image = elf(0x4000_0000, &[(0x4000_0000, 0x100, 0x100, PF_X)]);
+    let phdr = ELF64_EHDR_SIZE;
+    // data past the end of the file
+    assert!(Elf64::parse(&image[..image.len() - 1]).is_err());
+    let mut bad = image.clone();
+    set_u64(&mut bad, phdr + 0x8, u64::MAX);
+    assert!(Elf64::parse(&bad).is_err());
+    // more data than memory
+    let mut bad = image.clone();
+    set_u64(&mut bad, phdr + 0x28, 0xff);
+    assert!(Elf64::parse(&bad).is_err());
+    // memory wrapping around
+    let mut bad = image;
+    set_u64(&mut bad, phdr + 0x18, u64::MAX - 0x10);
+    assert!(Elf64::parse(&bad).is_err());
+}
+
+#[test]
+fn overlapping_segments_are_refused() {
+    let overlapping = elf(
+        0x4000_0000,
+        &[
+            (0x4000_0000, 0x100, 0x2000, PF_X),
+            (0x4000_1000, 0x100, 0x100, 0),
+        ],
+    );
+    assert!(Elf64::parse(&overlapping).is_err());
+    let adjacent = elf(
+        0x4000_0000,
+        &[
+            (0x4000_0000, 0x100, 0x1000, PF_X),
+            (0x4000_1000, 0x100, 0x100, 0),
+        ],
+    );
+    assert!(Elf64::parse(&adjacent).is_ok());
+}
+
+#[test]
+fn segments_outside_of_the_zone_memory_are_refused() {
+    let outside = elf(0x4000_0000, &[(0x4020_0000, 0x100, 0x100, 0)]);
+    assert!(Elf64::parse(&outside)
+        .unwrap()
+        .unwrap()
+        .check(&zone())
+        .is_err());
+    let code_in_data = elf(0x4010_0000, &[(0x4010_0000, 0x100, 0x100, PF_X)]);
+    assert!(Elf64::parse(&code_in_data)
+        .unwrap()
+        .unwrap()
+        .check(&zone())
+        .is_err());
+}
@@ -0,0 +1,101 @@
+use crate::config::*;
+use crate::loader::image::Arm64Image;
+use crate::memory::MemFlags;
+
+/// The 64 byte header of an arm64 `Image`, followed by `len - 64` bytes.
+fn image(text_offset: u64, image_size: u64, flags: u64, len: usize) -> Vec<u8> {
+    let mut image = vec![0u8; len.max(64)];
+    image[0x8..0x10].copy_from_slice(&text_offset.to_le_bytes());
+    image[0x10..0x18].copy_from_slice(&image_size.to_le_bytes());
+    image[0x18..0x20].copy_from_slice(&flags.to_le_bytes());
+    image[0x38..0x3c].copy_from_slice(b"ARM\x64");
+    image.truncate(len);
+    image
+}
+
+/// A zone with 64 MB of executable RAM at `ram_ipa`.
+fn zone(ram_ipa: u64, entry_point: u64) -> HvZoneConfig {
+    let mut config: HvZoneConfig = unsafe { core::mem::zeroed() };
+    config.entry_point = entry_point;
+    config.memory_regions[0] = HvConfigMemoryRegion {
+        ipa: ram_ipa,
+        pa: ram_ipa,
+        size: 0x400_0000,
+        flags: (MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE).bits(),
+    };
+    config.num_memory_regions = 1;
+    config
+}
+
+#[test]
+fn bad_magic_is_not_an_image() {
+    let mut data = image(0, 0x10_0000, 0, 0x1000);
+    data[0x3b] = 0;
+    assert!(Arm64Image::parse(&data).is_none());
+}
+
+#[test]
+fn truncated_header_is_not_an_image() {
+    let data = image(0, 0x10_0000, 0, 0x1000);
+    assert!(Arm64Image::parse(&data[..63]).is_none());
+    assert!(Arm64Image::parse(&data[..64]).is_some());
+}
+
+#[test]
+fn text_offset_is_read_with_an_image_size() {
+    let header = Arm64Image::parse(&image(0x1_0000, 0x10_0000, 0, 64)).unwrap();
+    assert_eq!(header.text_offset, 0x1_0000);
+    assert_eq!(header.image_size, 0x10_0000);
+}
+
+#[test]
+fn legacy_image_without_size_has_the_old_text_offset() {
+    let header = Arm64Image::parse(&image(0x1_0000, 0, 0, 64)).unwrap();
+    assert_eq!(header.text_offset, 0x8_0000);
+}
+
+#[test]
+fn default_entry_is_above_the_first_2m_boundary() {
+    let header = Arm64Image::parse(&image(0x8_0000, 0x10_0000, 0, 0x1000)).unwrap();
+    let config = zone(0x4010_0000, HV_CONFIG_IMAGE_ENTRY);
+    assert_eq!(header.entry(&config, 0x1000).unwrap(), 0x4028_0000);
+}
+
+#[test]
+fn entry_off_the_text_offset_is_refused() {
+    let header = Arm64Image::parse(&image(0x8_0000, 0x10_0000, 0, 0x1000)).unwrap();
+    assert_eq!(
+        header
+            .entry(&zone(0x4000_0000, 0x4008_0000), 0x1000)
+            .unwrap(),
+        0x4008_0000
+    );
+    assert!(header
+        .entry(&zone(0x4000_0000, 0x4000_0000), 0x1000)
+        .is_err());
+    assert!(header
+        .entry(&zone(0x4000_0000, 0x4010_0000), 0x1000)
+        .is_err());
+}
+
+#[test]
+fn image_size_must_fit_in_the_region() {
+    // 64 MB of RAM, the kernel gets what is above the entry
+    let config = zone(0x4000_0000, 0x4008_0000);
+    let fits = Arm64Image::parse(&image(0x8_0000, 0x3f8_0000, 0, 0x1000)).unwrap();
+    assert!(fits.entry(&config, 0x1000).is_ok());
+    let too_big = Arm64Image::parse(&image(0x8_0000, 0x3f8_1000, 0, 0x1000)).unwrap();
+    assert!(too_big.entry(&config, 0x1000).is_err());
+    // a legacy image is only as big as its file
+    let legacy = Arm64Image::parse(&image(0, 0, 0, 0x1000)).unwrap();
+    assert!(legacy.entry(&config, 0x3f8_0000).is_ok());
+    assert!(legacy.entry(&config, 0x3f8_1000).is_err());
+}
+
+#[test]
+fn big_endian_image_is_refused() {
+    let header = Arm64Image::parse(&image(0x8_0000, 0x10_0000, 1, 0x1000)).unwrap();
+    assert!(header
+        .entry(&zone(0x4000_0000, 0x4008_0000), 0x1000)
+        .is_err());
+}
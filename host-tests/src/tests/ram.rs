@@ -0,0 +1,168 @@
+// the bank lists are lists of ranges, even when they hold a single bank
+#![allow(clippy::single_range_in_vec_init)]
+
+use core::ops::Range;
+
+use fdt::{writer::FdtWriter, Fdt};
+
+use crate::memory::ram::{check_no_map, fdt_ram_regions, remove_range, RamRegion};
+use crate::memory::MemFlags;
+
+const RWX: MemFlags = MemFlags::READ
+    .union(MemFlags::WRITE)
+    .union(MemFlags::EXECUTE);
+const RW: MemFlags = MemFlags::READ.union(MemFlags::WRITE);
+
+fn reg(range: &Range<usize>) -> [u32; 4] {
+    let (start, size) = (range.start as u64, range.len() as u64);
+    [
+        (start >> 32) as u32,
+        start as u32,
+        (size >> 32) as u32,
+        size as u32,
+    ]
+}
+
+/// A tree with a memory node per bank and a `/reserved-memory` child per
+/// `(range, no-map)` pair.
+fn tree(buf: &mut [u8], banks: &[Range<usize>], reserved: &[(Range<usize>, bool)]) {
+    let mut w = FdtWriter::new(buf).unwrap();
+    w.begin_node("").unwrap();
+    w.property_u32("#address-cells", 2).unwrap();
+    w.property_u32("#size-cells", 2).unwrap();
+    for bank in banks {
+        w.begin_node(&format!("memory@{:x}", bank.start)).unwrap();
+        w.property_str("device_type", "memory").unwrap();
+        w.property_cells("reg", &reg(bank)).unwrap();
+        w.end_node().unwrap();
+    }
+    w.begin_node("reserved-memory").unwrap();
+    w.property_u32("#address-cells", 2).unwrap();
+    w.property_u32("#size-cells", 2).unwrap();
+    w.property_empty("ranges").unwrap();
+    for (range, no_map) in reserved {
+        w.begin_node(&format!("buffer@{:x}", range.start)).unwrap();
+        w.property_cells("reg", &reg(range)).unwrap();
+        if *no_map {
+            w.property_empty("no-map").unwrap();
+        }
+        w.end_node().unwrap();
+    }
+    w.end_node().unwrap();
+    w.end_node().unwrap();
+    w.finish().unwrap();
+}
+
+fn regions(
+    banks: &[Range<usize>],
+    reserved: &[(Range<usize>, bool)],
+) -> Vec<(Range<usize>, MemFlags)> {
+    let mut buf = vec![0u8; 0x1000];
+    tree(&mut buf, banks, reserved);
+    let fdt = Fdt::new(&buf).unwrap();
+    fdt_ram_regions(&fdt)
+        .into_iter()
+        .map(|region| (region.range, region.flags))
+        .collect()
+}
+
+#[test]
+fn banks_are_sorted() {
+    assert_eq!(
+        regions(&[0x8000_0000..0x9000_0000, 0x4000_0000..0x5000_0000], &[]),
+        [
+            (0x4000_0000..0x5000_0000, RWX),
+            (0x8000_0000..0x9000_0000, RWX)
+        ]
+    );
+}
+
+#[test]
+fn overlapping_and_adjacent_banks_are_merged() {
+    assert_eq!(
+        regions(
+            &[
+                0x5000_0000..0x7000_0000,
+                0x4000_0000..0x6000_0000,
+                0x7000_0000..0x7800_0000,
+                0x9000_0000..0x9100_0000,
+            ],
+            &[]
+        ),
+        [
+            (0x4000_0000..0x7800_0000, RWX),
+            (0x9000_0000..0x9100_0000, RWX)
+        ]
+    );
+}
+
+#[test]
+fn no_map_cuts_a_hole_in_merged_banks() {
+    assert_eq!(
+        regions(
+            &[0x4000_0000..0x6000_0000, 0x5000_0000..0x8000_0000],
+            &[(0x5fff_f800..0x6000_0800, true)]
+        ),
+        [
+            (0x4000_0000..0x5fff_f000, RWX),
+            (0x6000_1000..0x8000_0000, RWX)
+        ]
+    );
+}
+
+#[test]
+fn reserved_outside_of_ram_is_shared_once() {
+    assert_eq!(
+        regions(
+            &[0x4000_0000..0x5000_0000],
+            &[
+                (0x3000_0000..0x3000_2000, false),
+                (0x3000_1000..0x3000_4000, false),
+                (0x4fff_0000..0x5001_0000, false),
+            ]
+        ),
+        [
+            (0x3000_0000..0x3000_4000, RW),
+            (0x4000_0000..0x5000_0000, RWX),
+            (0x5000_0000..0x5001_0000, RW),
+        ]
+    );
+}
+
+#[test]
+fn no_map_over_a_kept_range_is_refused() {
+    let mut buf = vec![0u8; 0x1000];
+    tree(
+        &mut buf,
+        &[0x4000_0000..0x5000_0000],
+        &[(0x4010_0000..0x4020_0000, true)],
+    );
+    let fdt = Fdt::new(&buf).unwrap();
+    assert!(check_no_map(&fdt, 0x4000_0000..0x4010_0000).is_ok());
+    assert!(check_no_map(&fdt, 0x401f_f000..0x4030_0000).is_err());
+}
+
+#[test]
+fn removed_range_is_widened_to_pages() {
+    let ram = vec![
+        RamRegion {
+            range: 0x4000_0000..0x5000_0000,
+            flags: RWX,
+        },
+        RamRegion {
+            range: 0x6000_0000..0x6100_0000,
+            flags: RW,
+        },
+    ];
+    let left: Vec<_> = remove_range(ram, &(0x4800_0800..0x6000_0800))
+        .into_iter()
+        .map(|region| (region.range, region.flags))
+        .collect();
+    assert_eq!(
+        left,
+        [
+            (0x4000_0000..0x4800_0000, RWX),
+            (0x6000_1000..0x6100_0000, RW)
+        ]
+    );
+}
@@ -0,0 +1,58 @@
+#!/usr/bin/env python3
+"""Embed the symbol table of the hypervisor into its `.ksyms` section.
+
+usage: ksyms.py <nm> <objcopy> <hvisor elf>
+
+The section is reserved by `src/symbols.rs`, whose doc comment describes the
+layout written here. It keeps its size so that nothing else moves.
+"""
+
+import struct
+import subprocess
+import sys
+
+MAGIC = 0x59535648  # "HVSY"
+HEADER = struct.Struct("<II")
+ENTRY = struct.Struct("<QII")
+
+
+def main():
+    nm, objcopy, elf = sys.argv[1:4]
+    out = subprocess.run(
+        [nm, "--defined-only", "--numeric-sort", "--demangle", elf],
+        check=True,
+        capture_output=True,
+        text=True,
+    ).stdout
+
+    symbols = []
+    bounds = {}
+    for line in out.splitlines():
+        fields = line.split(" ", 2)
+        if len(fields) != 3:
+            continue
+        addr, kind, name = int(fields[0], 16), fields[1], fields[2]
+        if name in ("__ksyms_start", "__ksyms_end"):
+            bounds[name] = addr
+        if kind in "tT" and not name.startswith("$"):
+            symbols.append((addr, name.encode()))
+    size = bounds["__ksyms_end"] - bounds["__ksyms_start"]
+
+    names = b""
+    entries = b""
+    for addr, name in symbols:
+        entries += ENTRY.pack(addr, len(names), len(name))
+        names += name
+    table = HEADER.pack(MAGIC, len(symbols)) + entries + names
+    if len(table) > size:
+        sys.exit(f"ksyms: {len(table)} bytes of symbols, KSYMS_SIZE is {size}")
+
+    path = elf + ".ksyms"
+    with open(path, "wb") as f:
+        f.write(table.ljust(size, b"\0"))
+    subprocess.run([objcopy, "--update-section", f".ksyms={path}", elf], check=True)
+    print(f"ksyms: {len(symbols)} symbols, {len(table)} of {size} bytes")
+
+
+if __name__ == "__main__":
+    main()
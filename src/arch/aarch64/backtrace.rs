@@ -0,0 +1,77 @@
+//! EL2 backtraces. hvisor is built with frame pointers: x29 points to a frame
+//! record {x29 of the caller, return address}, the records of a cpu chaining
+//! up its per-cpu stack.
+
+use core::arch::asm;
+use core::ops::Range;
+
+use super::cpu::GeneralRegisters;
+use crate::consts::{MAX_CPU_NUM, PER_CPU_ARRAY_PTR, PER_CPU_SIZE};
+use crate::percpu::PerCpu;
+use crate::symbols::lookup;
+
+const MAX_DEPTH: usize = 32;
+const FRAME_RECORD_SIZE: usize = 16;
+
+/// The per-cpu stack holding the frame record at `fp`, if any.
+fn stack_of(fp: usize) -> Option<Range<usize>> {
+    let start = PER_CPU_ARRAY_PTR as usize;
+    if !(start..start + MAX_CPU_NUM * PER_CPU_SIZE).contains(&fp) {
+        return None;
+    }
+    let per_cpu = start + (fp - start) / PER_CPU_SIZE * PER_CPU_SIZE;
+    Some(per_cpu + core::mem::size_of::<PerCpu>()..per_cpu + PER_CPU_SIZE)
+}
+
+fn print_frame(depth: usize, pc: usize) {
+    match lookup(pc) {
+        Some((name, offset)) => error!("  #{:<2} {:#018x} {}+{:#x}", depth, pc, name, offset),
+        None => error!("  #{:<2} {:#018x}", depth, pc),
+    }
+}
+
+/// Print the callers from the frame record at `fp`, numbered from `depth`.
+fn walk(mut fp: usize, mut depth: usize) {
+    let stack = match stack_of(fp) {
+        Some(stack) => stack,
+        None => {
+            error!("  no stack at fp {:#x}", fp);
+            return;
+        }
+    };
+    while depth < MAX_DEPTH {
+        if fp % 8 != 0 || fp < stack.start || fp + FRAME_RECORD_SIZE > stack.end {
+            return;
+        }
+        let (next, lr) = unsafe { (*(fp as *const usize), *((fp + 8) as *const usize)) };
+        if lr == 0 {
+            return;
+        }
+        // lr follows the call
+        print_frame(depth, lr - 4);
+        if next <= fp {
+            // the records of a stack go up
+            return;
+        }
+        fp = next;
+        depth += 1;
+    }
+    error!("  ...");
+}
+
+/// Print the backtrace of the caller.
+#[inline(never)]
+pub fn print_backtrace() {
+    let fp: usize;
+    unsafe { asm!("mov {}, x29", out(reg) fp) };
+    error!("backtrace:");
+    walk(fp, 0);
+}
+
+/// Print the backtrace of EL2 code which trapped, `regs` being its registers
+/// and `elr` the faulting address.
+pub fn print_trap_backtrace(regs: &GeneralRegisters, elr: usize) {
+    error!("backtrace:");
+    print_frame(0, elr);
+    walk(regs.usr[29] as usize, 1);
+}
@@ -0,0 +1,242 @@
+//! Fatal guest exceptions.
+//!
+//! The cpu which hits one marks its zone as failed and asks the other cpus of
+//! the zone for the state of their vcpus with `IPI_EVENT_DUMP`. Every cpu of
+//! the zone then leaves the guest, and the vcpus are written to the core dump
+//! of the zone, see [`crate::coredump`].
+
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use aarch64_cpu::registers::{Readable, ESR_EL2, FAR_EL2};
+
+use super::cpu::GeneralRegisters;
+use super::panic::current_time_ms;
+use super::sysreg::read_sysreg;
+use super::vcpu::VcpuContext;
+use crate::consts::{MAX_CPU_NUM, ROOT_ZONE_ID};
+use crate::coredump::{self, push_note};
+use crate::event::{send_event, IPI_EVENT_DUMP};
+use crate::hypercall::SGI_IPI_ID;
+use crate::percpu::this_cpu_data;
+use crate::scheduler::schedule;
+use crate::zone::ZoneState;
+
+pub const SIGILL: u32 = 4;
+pub const SIGABRT: u32 = 6;
+pub const SIGBUS: u32 = 7;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_FPREGSET: u32 = 2;
+/// Note of the `HVISOR` namespace: the u64 sysregs SCTLR_EL1, TTBR0_EL1,
+/// TTBR1_EL1, TCR_EL1, MAIR_EL1, VBAR_EL1, ESR_EL1, FAR_EL1, ELR_EL1,
+/// SPSR_EL1, SP_EL0, SP_EL1, TPIDR_EL0, TPIDR_EL1, CONTEXTIDR_EL1, PAR_EL1,
+/// CNTV_CTL_EL0, CNTV_CVAL_EL0, CNTVOFF_EL2 and VMPIDR_EL2, then ESR_EL2,
+/// FAR_EL2 and HPFAR_EL2 of the fatal exception, zero for the other vcpus.
+const NT_HVISOR_SYSREGS: u32 = 1;
+
+/// `struct elf_prstatus`, with its registers at `PRSTATUS_REGS`: x0-x30, sp,
+/// pc and pstate, then `pr_fpvalid`.
+const PRSTATUS_SIZE: usize = 392;
+const PRSTATUS_REGS: usize = 112;
+/// `struct user_fpsimd_state`
+const FPREGSET_SIZE: usize = 528;
+/// How long the dumping cpu waits for the state of another vcpu.
+const DUMP_TIMEOUT_MS: u64 = 100;
+
+/// A vcpu state asked by the dumping cpu.
+struct DumpSlot {
+    requested: bool,
+    done: bool,
+    /// None if the vcpu was off.
+    context: Option<VcpuContext>,
+}
+
+static DUMP_SLOTS: [Mutex<DumpSlot>; MAX_CPU_NUM] = [const {
+    Mutex::new(DumpSlot {
+        requested: false,
+        done: false,
+        context: None,
+    })
+}; MAX_CPU_NUM];
+
+/// Leave the guest for good: a shared cpu goes on with the other zones, a
+/// dedicated one is parked until the zone is shut down or rebooted.
+fn stop_vcpu() -> ! {
+    let cpu_data = this_cpu_data();
+    cpu_data.arch_cpu.psci_on = false;
+    schedule();
+    cpu_data.arch_cpu.idle()
+}
+
+/// Fill the slot of the current cpu, once asked to, and stop.
+fn answer_dump_request(context: Option<VcpuContext>) -> ! {
+    let mut slot = DUMP_SLOTS[this_cpu_data().id].lock();
+    slot.requested = false;
+    slot.context = context;
+    slot.done = true;
+    drop(slot);
+    stop_vcpu()
+}
+
+/// Whether the dumping cpu waits for the state of the vcpu of the current cpu.
+pub fn dump_requested() -> bool {
+    DUMP_SLOTS[this_cpu_data().id].lock().requested
+}
+
+/// Called on a cpu which received `IPI_EVENT_DUMP`, or paused while the dump
+/// was requested.
+pub fn handle_dump_request() {
+    let cpu_data = this_cpu_data();
+    if !dump_requested() {
+        // stale, the cpu already answered
+        return;
+    }
+    let context = match cpu_data.arch_cpu.psci_on {
+        true => Some(cpu_data.arch_cpu.save_context()),
+        false => None,
+    };
+    answer_dump_request(context)
+}
+
+/// The state of the vcpu on `cpu`, None if it was off or didn't answer in
+/// time. The request stays pending, so the cpu leaves the guest whenever it
+/// takes it.
+fn request_context(cpu: usize) -> Option<VcpuContext> {
+    {
+        let mut slot = DUMP_SLOTS[cpu].lock();
+        slot.requested = true;
+        slot.done = false;
+        slot.context = None;
+    }
+    send_event(cpu, SGI_IPI_ID as _, IPI_EVENT_DUMP);
+    let deadline = current_time_ms() + DUMP_TIMEOUT_MS;
+    loop {
+        let mut slot = DUMP_SLOTS[cpu].lock();
+        if slot.done {
+            slot.done = false;
+            return slot.context.take();
+        }
+        drop(slot);
+        if current_time_ms() >= deadline {
+            warn!("cpu {} didn't answer the core dump request", cpu);
+            return None;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+fn push_u64s(bytes: &mut Vec<u8>, values: &[u64]) {
+    values
+        .iter()
+        .for_each(|value| bytes.extend_from_slice(&value.to_le_bytes()));
+}
+
+/// Append the notes of a vcpu, its thread being `pid` in the core file.
+fn push_vcpu_notes(notes: &mut Vec<u8>, pid: u32, ctx: &VcpuContext, signal: u32, fault: [u64; 3]) {
+    let mut prstatus = Vec::with_capacity(PRSTATUS_SIZE);
+    prstatus.extend_from_slice(&signal.to_le_bytes()); // pr_info.si_signo
+    prstatus.resize(12, 0);
+    prstatus.extend_from_slice(&(signal as u16).to_le_bytes()); // pr_cursig
+    prstatus.resize(32, 0);
+    prstatus.extend_from_slice(&pid.to_le_bytes()); // pr_pid
+    prstatus.resize(PRSTATUS_REGS, 0);
+    // the guest runs on SP_EL1 in EL1h only
+    let sp = match ctx.spsr_el2 & 0xf {
+        0b0101 => ctx.sp_el1,
+        _ => ctx.sp_el0,
+    };
+    push_u64s(&mut prstatus, &ctx.usr);
+    push_u64s(&mut prstatus, &[sp, ctx.elr_el2, ctx.spsr_el2]);
+    prstatus.extend_from_slice(&1u32.to_le_bytes()); // pr_fpvalid
+    prstatus.resize(PRSTATUS_SIZE, 0);
+    push_note(notes, "CORE", NT_PRSTATUS, &prstatus);
+
+    let mut fpregs = Vec::with_capacity(FPREGSET_SIZE);
+    ctx.fp_q
+        .iter()
+        .for_each(|q| fpregs.extend_from_slice(&q.to_le_bytes()));
+    fpregs.extend_from_slice(&(ctx.fpsr as u32).to_le_bytes());
+    fpregs.extend_from_slice(&(ctx.fpcr as u32).to_le_bytes());
+    fpregs.resize(FPREGSET_SIZE, 0);
+    push_note(notes, "CORE", NT_FPREGSET, &fpregs);
+
+    let mut sysregs = Vec::new();
+    push_u64s(
+        &mut sysregs,
+        &[
+            ctx.sctlr_el1,
+            ctx.ttbr0_el1,
+            ctx.ttbr1_el1,
+            ctx.tcr_el1,
+            ctx.mair_el1,
+            ctx.vbar_el1,
+            ctx.esr_el1,
+            ctx.far_el1,
+            ctx.elr_el1,
+            ctx.spsr_el1,
+            ctx.sp_el0,
+            ctx.sp_el1,
+            ctx.tpidr_el0,
+            ctx.tpidr_el1,
+            ctx.contextidr_el1,
+            ctx.par_el1,
+            ctx.cntv_ctl_el0,
+            ctx.cntv_cval_el0,
+            ctx.cntvoff_el2,
+            ctx.vmpidr_el2,
+        ],
+    );
+    push_u64s(&mut sysregs, &fault);
+    push_note(notes, "HVISOR", NT_HVISOR_SYSREGS, &sysregs);
+}
+
+/// Stop the current cpu, whose guest hit a fatal exception, `regs` being its
+/// trap frame. The zone fails and, unless it's the root zone, is dumped with
+/// the current vcpu first, reported as killed by `signal`.
+pub fn zone_fatal(regs: &GeneralRegisters, signal: u32) -> ! {
+    let cpu_data = this_cpu_data();
+    let zone = match &cpu_data.zone {
+        Some(zone) => zone.clone(),
+        None => {
+            error!("cpu {}: fatal exception outside of any zone", cpu_data.id);
+            cpu_data.arch_cpu.idle()
+        }
+    };
+    let fault = [ESR_EL2.get(), FAR_EL2.get(), read_sysreg!(HPFAR_EL2)];
+    let context = VcpuContext::save(regs);
+    let failed = zone.write().set_state(ZoneState::Failed);
+    if failed.is_err() {
+        if zone.read().state == ZoneState::Failed {
+            // another cpu of the zone failed first and is dumping it
+            while !DUMP_SLOTS[cpu_data.id].lock().requested {
+                core::hint::spin_loop();
+            }
+            answer_dump_request(Some(context));
+        }
+        stop_vcpu();
+    }
+
+    let cpu_set = zone.read().cpu_set;
+    error!("zone {} failed on cpu {}", zone.read().id, cpu_data.id);
+    let others: Vec<_> = cpu_set
+        .iter_except(cpu_data.id)
+        .map(|cpu| (cpu, request_context(cpu)))
+        .collect();
+
+    let zone_id = zone.read().id;
+    let pid = |cpu| cpu_set.vcpu_id(cpu).unwrap() as u32 + 1;
+    let mut notes = Vec::new();
+    push_vcpu_notes(&mut notes, pid(cpu_data.id), &context, signal, fault);
+    for (cpu, context) in &others {
+        if let Some(context) = context {
+            push_vcpu_notes(&mut notes, pid(*cpu), context, 0, [0; 3]);
+        }
+    }
+    if zone_id == ROOT_ZONE_ID {
+        error!("the root zone failed, no one to take its core dump");
+    } else if let Err(e) = coredump::write(&zone, &notes) {
+        error!("zone {}: no core dump: {:?}", zone_id, e);
+    }
+    stop_vcpu()
+}
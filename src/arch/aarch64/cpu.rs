@@ -5,7 +5,7 @@ use crate::{
         addr::PHYS_VIRT_OFFSET, mm::PARKING_MEMORY_SET, GuestPhysAddr, HostPhysAddr, MemFlags,
         MemoryRegion, VirtAddr, PARKING_INST_PAGE,
     },
-    percpu::this_cpu_data,
+    percpu::{this_cpu_data, this_zone},
 };
 use aarch64_cpu::registers::{
     Readable, Writeable, ELR_EL2, HCR_EL2, MAIR_EL2, MPIDR_EL1, SCTLR_EL1, SCTLR_EL2, SPSR_EL2,
@@ -14,9 +14,15 @@ use aarch64_cpu::registers::{
 
 use super::{
     mm::{get_parange, get_parange_bits, is_s2_pt_level3},
-    trap::vmreturn
+    trap::vmreturn,
+    vcpu::VcpuContext,
 };
 
+/// CPTR_EL2 of a running guest: FP/SIMD doesn't trap, as its registers are
+/// switched with the vcpu, while SVE (TZ) and SME (TSM), whose state isn't,
+/// trap. Bits 13, 9 and 7:0 are RES1.
+const CPTR_EL2_GUEST: u64 = (1 << 13) | (1 << 12) | (1 << 9) | (1 << 8) | 0xff;
+
 pub fn cpu_start(cpuid: usize, start_addr: usize, opaque: usize) {
     psci::cpu_on(cpuid as u64 | 0x80000000, start_addr as _, opaque as _).unwrap_or_else(|err| {
         if let psci::error::Error::AlreadyOn = err {
@@ -45,6 +51,8 @@ impl GeneralRegisters {
 pub struct ArchCpu {
     pub cpuid: usize,
     pub psci_on: bool,
+    /// PSCI CPU_ON was accepted, but the cpu hasn't entered the guest yet.
+    pub psci_on_pending: bool,
 }
 
 impl ArchCpu {
@@ -52,17 +60,19 @@ impl ArchCpu {
         Self {
             cpuid,
             psci_on: false,
+            psci_on_pending: false,
         }
     }
 
     pub fn reset(&mut self, entry: usize, dtb: usize) {
+        // first, as the wipe needs the FP/SIMD registers
+        self.activate_vmm();
+        let regs = self.guest_reg();
+        VcpuContext::wipe(regs);
         ELR_EL2.set(entry as _);
         SPSR_EL2.set(0x3c5);
-        let regs = self.guest_reg();
-        regs.clear();
         regs.usr[0] = dtb as _; // dtb addr
         self.reset_vm_regs();
-        self.activate_vmm();
     }
 
     fn activate_vmm(&self) {
@@ -87,8 +97,11 @@ impl ArchCpu {
                 + HCR_EL2::TSC::EnableTrapEl1SmcToEl2
                 + HCR_EL2::VM::SET
                 + HCR_EL2::IMO::SET
-                + HCR_EL2::FMO::SET,
+                + HCR_EL2::FMO::SET
+                + HCR_EL2::TID3::SET
+                + HCR_EL2::TSW::SET,
         );
+        write_sysreg!(cptr_el2, CPTR_EL2_GUEST);
     }
 
     fn stack_top(&self) -> VirtAddr {
@@ -100,44 +113,8 @@ impl ArchCpu {
     }
 
     fn reset_vm_regs(&self) {
-        /* put the cpu in a reset state */
+        /* put the cpu in a reset state, on top of VcpuContext::wipe */
         /* AARCH64_TODO: handle big endian support */
-        write_sysreg!(CNTKCTL_EL1, 0);
-        write_sysreg!(PMCR_EL0, 0);
-
-        // /* AARCH64_TODO: wipe floating point registers */
-        // /* wipe special registers */
-        write_sysreg!(SP_EL0, 0);
-        write_sysreg!(SP_EL1, 0);
-        write_sysreg!(SPSR_EL1, 0);
-
-        // /* wipe the system registers */
-        write_sysreg!(AFSR0_EL1, 0);
-        write_sysreg!(AFSR1_EL1, 0);
-        write_sysreg!(AMAIR_EL1, 0);
-        write_sysreg!(CONTEXTIDR_EL1, 0);
-        write_sysreg!(CPACR_EL1, 0);
-        write_sysreg!(CSSELR_EL1, 0);
-        write_sysreg!(ESR_EL1, 0);
-        write_sysreg!(FAR_EL1, 0);
-        write_sysreg!(MAIR_EL1, 0);
-        write_sysreg!(PAR_EL1, 0);
-        write_sysreg!(TCR_EL1, 0);
-        write_sysreg!(TPIDRRO_EL0, 0);
-        write_sysreg!(TPIDR_EL0, 0);
-        write_sysreg!(TPIDR_EL1, 0);
-        write_sysreg!(TTBR0_EL1, 0);
-        write_sysreg!(TTBR1_EL1, 0);
-        write_sysreg!(VBAR_EL1, 0);
-
-        /* wipe timer registers */
-        write_sysreg!(CNTVOFF_EL2, 0);
-        write_sysreg!(CNTP_CTL_EL0, 0);
-        write_sysreg!(CNTP_CVAL_EL0, 0);
-        write_sysreg!(CNTP_TVAL_EL0, 0);
-        write_sysreg!(CNTV_CTL_EL0, 0);
-        write_sysreg!(CNTV_CVAL_EL0, 0);
-        write_sysreg!(CNTV_TVAL_EL0, 0);
         // //disable stage 1
         // write_sysreg!(SCTLR_EL1, 0);
 
@@ -146,10 +123,42 @@ impl ArchCpu {
 
     pub fn run(&mut self) -> ! {
         assert!(this_cpu_id() == self.cpuid);
-        self.reset(this_cpu_data().cpu_on_entry, self.cpuid, DTB_IPA);
+        let (dtb_ipa, vcpu_id) = {
+            let zone = this_zone();
+            let zone = zone.read();
+            (zone.dtb_ipa, zone.cpu_set.vcpu_id(self.cpuid).unwrap())
+        };
         this_cpu_data().activate_gpm();
-        self.reset(this_cpu_data().cpu_on_entry, DTB_IPA);
+        self.reset(this_cpu_data().cpu_on_entry, dtb_ipa);
+        set_vmpidr(vcpu_id);
+        self.psci_on = true;
+        self.psci_on_pending = false;
+        unsafe {
+            vmreturn(self.guest_reg() as *mut _ as usize);
+        }
+    }
+
+    /// The guest registers saved on the last trap of the cpu.
+    pub fn trap_frame(&self) -> &GeneralRegisters {
+        self.guest_reg()
+    }
+
+    /// Save the guest state of the current cpu, see [`VcpuContext::save`].
+    pub fn save_context(&self) -> VcpuContext {
+        VcpuContext::save(self.guest_reg())
+    }
+
+    /// Load a guest state saved by `save_context`, the zone's stage-2 page
+    /// table being active. The guest continues on [`ArchCpu::resume`].
+    pub fn restore_context(&mut self, context: &VcpuContext) {
+        assert!(this_cpu_id() == self.cpuid);
+        self.activate_vmm();
+        context.restore(self.guest_reg());
         self.psci_on = true;
+        self.psci_on_pending = false;
+    }
+
+    pub fn resume(&mut self) -> ! {
         unsafe {
             vmreturn(self.guest_reg() as *mut _ as usize);
         }
@@ -160,6 +169,7 @@ impl ArchCpu {
         let cpu_data = this_cpu_data();
         let _lock = cpu_data.ctrl_lock.lock();
         self.psci_on = false;
+        self.psci_on_pending = false;
         drop(_lock);
 
         PARKING_MEMORY_SET.call_once(|| {
@@ -186,8 +196,16 @@ impl ArchCpu {
     }
 }
 
+/// Aff3 and Aff2..Aff0 of MPIDR_EL1.
+pub const MPIDR_AFF_MASK: u64 = 0xff00ffffff;
+
 pub fn mpidr_to_cpuid(mpidr: u64) -> u64 {
-    mpidr & 0xff00ffffff
+    mpidr & MPIDR_AFF_MASK
+}
+
+/// Make the guest read `vcpu_id`, its index in the zone, as Aff0 of MPIDR_EL1.
+fn set_vmpidr(vcpu_id: usize) {
+    write_sysreg!(VMPIDR_EL2, (MPIDR_EL1.get() & !MPIDR_AFF_MASK) | vcpu_id as u64);
 }
 
 pub fn this_cpu_id() -> usize {
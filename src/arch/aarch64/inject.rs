@@ -0,0 +1,106 @@
+//! Synchronous exceptions injected into the guest, taken by its EL1 as if the
+//! hardware raised them: the guest state goes to ELR_EL1/SPSR_EL1, the
+//! syndrome to ESR_EL1/FAR_EL1, and the guest resumes at its vector in EL1h
+//! with DAIF masked.
+
+use aarch64_cpu::registers::{Readable, Writeable, ELR_EL2, ESR_EL2, SPSR_EL2};
+
+use super::coredump::{zone_fatal, SIGBUS, SIGILL};
+use super::cpu::GeneralRegisters;
+use super::sysreg::{read_sysreg, write_sysreg};
+
+const ESR_EC_SHIFT: u64 = 26;
+const ESR_IL: u64 = 1 << 25;
+const EC_UNKNOWN: u64 = 0x00;
+const EC_IABT_LOW: u64 = 0x20;
+const EC_IABT_CUR: u64 = 0x21;
+const EC_DABT_LOW: u64 = 0x24;
+const EC_DABT_CUR: u64 = 0x25;
+/// Data abort ISS: the access was a write.
+const ISS_WNR: u64 = 1 << 6;
+/// Fault status code of a synchronous external abort.
+const FSC_SEA: u64 = 0x10;
+
+const PSR_MODE_MASK: u64 = 0xf;
+const PSR_MODE_EL0T: u64 = 0b0000;
+const PSR_MODE_EL1T: u64 = 0b0100;
+const PSR_MODE_EL1H: u64 = 0b0101;
+const PSR_DAIF: u64 = 0xf << 6;
+const PSR_PAN: u64 = 1 << 22;
+const PSR_AARCH32: u64 = 1 << 4;
+/// SCTLR_EL1.SPAN: PAN left as it is when taking an exception to EL1.
+const SCTLR_SPAN: u64 = 1 << 23;
+
+/// Offsets of the synchronous vectors from VBAR_EL1.
+const VECTOR_CUR_SP0: u64 = 0x000;
+const VECTOR_CUR_SPX: u64 = 0x200;
+const VECTOR_LOW_A64: u64 = 0x400;
+const VECTOR_LOW_A32: u64 = 0x600;
+const VECTORS_SIZE: u64 = 0x800;
+
+/// Raise `esr` and `far` in the guest. A fault in its own vectors can't be
+/// handed to it without looping: the zone fails instead, killed by `signal`.
+fn inject_sync(regs: &GeneralRegisters, esr: u64, far: u64, signal: u32) {
+    let pstate = SPSR_EL2.get();
+    let pc = ELR_EL2.get();
+    let vbar = read_sysreg!(VBAR_EL1);
+    if (vbar..vbar + VECTORS_SIZE).contains(&pc) {
+        error!("guest fault in its vectors at {:#x}, esr {:#x}", pc, esr);
+        zone_fatal(regs, signal);
+    }
+
+    let vector = match pstate & PSR_MODE_MASK {
+        _ if pstate & PSR_AARCH32 != 0 => VECTOR_LOW_A32,
+        PSR_MODE_EL0T => VECTOR_LOW_A64,
+        PSR_MODE_EL1T => VECTOR_CUR_SP0,
+        _ => VECTOR_CUR_SPX,
+    };
+    let pan = match read_sysreg!(SCTLR_EL1) & SCTLR_SPAN {
+        0 => PSR_PAN,
+        _ => pstate & PSR_PAN,
+    };
+    write_sysreg!(ESR_EL1, esr);
+    write_sysreg!(FAR_EL1, far);
+    write_sysreg!(ELR_EL1, pc);
+    write_sysreg!(SPSR_EL1, pstate);
+    ELR_EL2.set(vbar + vector);
+    SPSR_EL2.set(PSR_MODE_EL1H | PSR_DAIF | pan);
+}
+
+/// Whether the guest trapped from EL0.
+fn from_el0() -> bool {
+    let pstate = SPSR_EL2.get();
+    pstate & PSR_AARCH32 != 0 || pstate & PSR_MODE_MASK == PSR_MODE_EL0T
+}
+
+/// The IL bit of the trapped instruction.
+fn trapped_il() -> u64 {
+    ESR_EL2.get() & ESR_IL
+}
+
+/// Make the trapped instruction UNDEFINED to the guest.
+pub fn inject_undef(regs: &GeneralRegisters) {
+    inject_sync(regs, EC_UNKNOWN << ESR_EC_SHIFT | trapped_il(), 0, SIGILL);
+}
+
+/// Raise a synchronous external abort on the instruction fetch at `far`.
+pub fn inject_iabt(regs: &GeneralRegisters, far: u64) {
+    let ec = match from_el0() {
+        true => EC_IABT_LOW,
+        false => EC_IABT_CUR,
+    };
+    inject_sync(regs, ec << ESR_EC_SHIFT | trapped_il() | FSC_SEA, far, SIGBUS);
+}
+
+/// Raise a synchronous external abort on the data access at `far`.
+pub fn inject_dabt(regs: &GeneralRegisters, far: u64, is_write: bool) {
+    let ec = match from_el0() {
+        true => EC_DABT_LOW,
+        false => EC_DABT_CUR,
+    };
+    let wnr = match is_write {
+        true => ISS_WNR,
+        false => 0,
+    };
+    inject_sync(regs, ec << ESR_EC_SHIFT | trapped_il() | wnr | FSC_SEA, far, SIGBUS);
+}
@@ -2,14 +2,36 @@ use crate::percpu::get_cpu_data;
 
 use super::sysreg::write_sysreg;
 
+/// ICC_SGI1R_EL1: the SGI id.
+pub const SGI1R_INTID_MASK: u64 = 0xf << 24;
+/// ICC_SGI1R_EL1: interrupt routing mode, to all the cpus but the sender.
+pub const SGI1R_IRM: u64 = 1 << 40;
+
+/// The affinity fields and target list of ICC_SGI1R_EL1 routing an SGI to the
+/// cpu whose MPIDR affinity is `cpu_id`.
+pub fn sgi1r_target(cpu_id: u64) -> u64 {
+    let aff3 = (cpu_id >> 32 & 0xff) << 48;
+    let aff2 = (cpu_id >> 16 & 0xff) << 32;
+    let aff1 = (cpu_id >> 8 & 0xff) << 16;
+    // Aff0 is a range selector and a bit in the target list
+    let rs = (cpu_id >> 4 & 0xf) << 44;
+    let target_list = 1 << (cpu_id & 0xf);
+    aff3 | aff2 | aff1 | rs | target_list
+}
+
+/// The MPIDR affinities of the cpus targeted by a write of `val` to
+/// ICC_SGI1R_EL1 without IRM.
+pub fn sgi1r_targets(val: u64) -> impl Iterator<Item = u64> {
+    let aff = (val >> 48 & 0xff) << 32 | (val >> 32 & 0xff) << 16 | (val >> 16 & 0xff) << 8;
+    let rs = val >> 44 & 0xf;
+    (0..16)
+        .filter(move |bit| val & (1 << bit) != 0)
+        .map(move |bit| aff | rs << 4 | bit)
+}
+
 pub fn arch_send_event(cpu_id: u64, sgi_num: u64) {
-    let aff3: u64 = 0 << 48;
-    let aff2: u64 = 0 << 32;
-    let aff1: u64 = 0 << 16;
-    let irm: u64 = 0 << 40;
     let sgi_id: u64 = sgi_num << 24;
-    let target_list: u64 = 1 << cpu_id;
-    let val: u64 = aff1 | aff2 | aff3 | irm | sgi_id | target_list;
+    let val: u64 = sgi_id | sgi1r_target(cpu_id);
     write_sysreg!(icc_sgi1r_el1, val);
     debug!("write sgi sys value = {:#x}", val);
 }
@@ -4,11 +4,12 @@ use spin::RwLock;
 
 use crate::{
     arch::{s1pt::Stage1PageTable, Stage2PageTable},
-    consts::{MAX_CPU_NUM, PAGE_SIZE},
+    consts::{hv_end, hv_start, MAX_CPU_NUM, PAGE_SIZE},
     device::irqchip::gicv3::{host_gicd_base, host_gicd_size, host_gicr_base, host_gicr_size},
     error::HvResult,
     memory::{
         addr::{align_down, align_up},
+        ram::{check_no_map, fdt_ram_regions},
         GuestPhysAddr, HostPhysAddr, MemFlags, MemoryRegion, MemorySet, HV_PT,
     },
     wait_for,
@@ -25,15 +26,17 @@ pub fn init_hv_page_table(fdt: &fdt::Fdt) -> HvResult {
     //     MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
     // ));
     trace!("fdt: {:?}", fdt);
-    // The first memory region is used to map the guest physical memory.
-    let mem_region = fdt.memory().regions().next().unwrap();
-    debug!("map mem_region: {:#x?}", mem_region);
-    hv_pt.insert(MemoryRegion::new_with_offset_mapper(
-        mem_region.starting_address as GuestPhysAddr,
-        mem_region.starting_address as HostPhysAddr,
-        mem_region.size.unwrap(),
-        MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
-    ))?;
+    // the hypervisor runs from the identity mapping of its own image
+    check_no_map(fdt, hv_start()..hv_end())?;
+    for region in fdt_ram_regions(fdt) {
+        debug!("map mem_region: {:#x?}", region);
+        hv_pt.insert(MemoryRegion::new_with_offset_mapper(
+            region.range.start as GuestPhysAddr,
+            region.range.start as HostPhysAddr,
+            region.range.len(),
+            region.flags,
+        ))?;
+    }
 
     // probe virtio mmio device
     let mut last_mmio_addr: Option<usize> = None;
@@ -1,13 +1,19 @@
 pub mod ipi;
+pub mod backtrace;
+pub mod coredump;
 pub mod cpu;
 pub mod entry;
+pub mod inject;
 pub mod ipi;
 pub mod mm;
+pub mod panic;
 pub mod paging;
 pub mod s1pt;
 pub mod s2pt;
 pub mod sysreg;
+pub mod sysreg_emul;
 pub mod trap;
+pub mod vcpu;
 pub mod zone;
 
 pub use s1pt::Stage1PageTable;
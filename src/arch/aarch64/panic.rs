@@ -0,0 +1,92 @@
+//! Cpu side of a hypervisor panic, see [`crate::panic`].
+
+use core::arch::asm;
+
+use aarch64_cpu::registers::{Readable, ELR_EL2, ESR_EL2, FAR_EL2, SPSR_EL2};
+
+use super::backtrace::print_backtrace;
+use super::ipi::arch_send_event;
+use super::sysreg::read_sysreg;
+use crate::hypercall::SGI_IPI_ID;
+use crate::percpu::this_cpu_data;
+
+/// Raise the stop SGI on `cpu`. Unlike events, it takes no lock, which the
+/// panicking cpu may hold.
+pub fn send_stop(cpu: usize) {
+    arch_send_event(cpu as _, SGI_IPI_ID);
+}
+
+/// Milliseconds since boot.
+pub fn current_time_ms() -> u64 {
+    read_sysreg!(CNTPCT_EL0) * 1000 / read_sysreg!(CNTFRQ_EL0)
+}
+
+/// Print the EL2 and guest registers of the current cpu, and its zone. The
+/// heap and the locks of the zones may be held by a stopped cpu, so nothing is
+/// allocated and no lock is waited for.
+pub fn dump_cpu_state() {
+    let cpu_data = this_cpu_data();
+    let (sp, fp, lr): (u64, u64, u64);
+    unsafe { asm!("mov {}, sp", "mov {}, x29", "mov {}, x30", out(reg) sp, out(reg) fp, out(reg) lr) };
+    match cpu_data.zone.as_ref().map(|zone| zone.try_read().map(|zone| zone.id)) {
+        Some(Some(id)) => error!("cpu {} in zone {}:", cpu_data.id, id),
+        Some(None) => error!("cpu {} in a locked zone:", cpu_data.id),
+        None => error!("cpu {} in no zone:", cpu_data.id),
+    }
+    error!("  EL2: sp {:#018x} fp {:#018x} lr {:#018x}", sp, fp, lr);
+    error!(
+        "  last trap: ESR_EL2 {:#010x} FAR_EL2 {:#018x} HPFAR_EL2 {:#018x}",
+        ESR_EL2.get(),
+        FAR_EL2.get(),
+        read_sysreg!(HPFAR_EL2)
+    );
+    error!(
+        "  guest: pc {:#018x} pstate {:#010x} sp_el0 {:#018x} sp_el1 {:#018x}",
+        ELR_EL2.get(),
+        SPSR_EL2.get(),
+        read_sysreg!(SP_EL0),
+        read_sysreg!(SP_EL1)
+    );
+    error!(
+        "  guest: ESR_EL1 {:#010x} FAR_EL1 {:#018x} ELR_EL1 {:#018x} SPSR_EL1 {:#010x}",
+        read_sysreg!(ESR_EL1),
+        read_sysreg!(FAR_EL1),
+        read_sysreg!(ELR_EL1),
+        read_sysreg!(SPSR_EL1)
+    );
+    let usr = &cpu_data.arch_cpu.trap_frame().usr;
+    for i in (0..28).step_by(4) {
+        error!(
+            "  guest: x{:<2} {:#018x} x{:<2} {:#018x} x{:<2} {:#018x} x{:<2} {:#018x}",
+            i,
+            usr[i],
+            i + 1,
+            usr[i + 1],
+            i + 2,
+            usr[i + 2],
+            i + 3,
+            usr[i + 3]
+        );
+    }
+    error!(
+        "  guest: x28 {:#018x} x29 {:#018x} x30 {:#018x}",
+        usr[28], usr[29], usr[30]
+    );
+    print_backtrace();
+}
+
+/// Stop the current cpu for good.
+pub fn halt() -> ! {
+    unsafe { asm!("msr daifset, #0xf") };
+    loop {
+        aarch64_cpu::asm::wfi();
+    }
+}
+
+/// Reset the board through PSCI, or halt if that fails.
+pub fn reset_board() -> ! {
+    if let Err(e) = psci::system_reset() {
+        error!("board reset failed: {:?}", e);
+    }
+    halt()
+}
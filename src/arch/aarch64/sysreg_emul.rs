@@ -0,0 +1,201 @@
+//! Emulation of the system registers and instructions guests trap on.
+//!
+//! Each emulated register has an entry in `SYSREG_TABLE`, keyed by its
+//! encoding, with a callback per direction. An access without an entry or a
+//! callback is UNDEFINED to the guest.
+
+use core::arch::asm;
+
+use super::ipi::{sgi1r_target, sgi1r_targets, SGI1R_INTID_MASK, SGI1R_IRM};
+use super::sysreg::{read_sysreg, write_sysreg};
+use crate::percpu::{this_cpu_data, this_zone};
+
+/// Encoding of a system register or instruction, as `sys_reg()` in Linux.
+pub const fn sys_reg(op0: u32, op1: u32, crn: u32, crm: u32, op2: u32) -> u32 {
+    op0 << 19 | op1 << 16 | crn << 12 | crm << 8 | op2 << 5
+}
+
+/// Value returned by the read of an emulated register.
+pub type SysRegRead = fn() -> u64;
+/// Takes the value written to an emulated register, or the operand of an
+/// instruction.
+pub type SysRegWrite = fn(u64);
+
+pub struct SysRegEmul {
+    pub name: &'static str,
+    pub encoding: u32,
+    pub read: Option<SysRegRead>,
+    pub write: Option<SysRegWrite>,
+}
+
+const fn read_only(name: &'static str, encoding: u32, read: SysRegRead) -> SysRegEmul {
+    SysRegEmul {
+        name,
+        encoding,
+        read: Some(read),
+        write: None,
+    }
+}
+
+const fn write_only(name: &'static str, encoding: u32, write: SysRegWrite) -> SysRegEmul {
+    SysRegEmul {
+        name,
+        encoding,
+        read: None,
+        write: Some(write),
+    }
+}
+
+const ICC_SGI1R_EL1: u32 = sys_reg(3, 0, 12, 11, 5);
+const ICC_ASGI1R_EL1: u32 = sys_reg(3, 1, 12, 11, 6);
+const ICC_SGI0R_EL1: u32 = sys_reg(3, 2, 12, 11, 7);
+
+const ID_PFR0_EL1: u32 = sys_reg(3, 0, 0, 1, 0);
+const ID_PFR1_EL1: u32 = sys_reg(3, 0, 0, 1, 1);
+const ID_DFR0_EL1: u32 = sys_reg(3, 0, 0, 1, 2);
+const ID_AFR0_EL1: u32 = sys_reg(3, 0, 0, 1, 3);
+const ID_MMFR0_EL1: u32 = sys_reg(3, 0, 0, 1, 4);
+const ID_MMFR1_EL1: u32 = sys_reg(3, 0, 0, 1, 5);
+const ID_MMFR2_EL1: u32 = sys_reg(3, 0, 0, 1, 6);
+const ID_MMFR3_EL1: u32 = sys_reg(3, 0, 0, 1, 7);
+const ID_ISAR0_EL1: u32 = sys_reg(3, 0, 0, 2, 0);
+const ID_ISAR1_EL1: u32 = sys_reg(3, 0, 0, 2, 1);
+const ID_ISAR2_EL1: u32 = sys_reg(3, 0, 0, 2, 2);
+const ID_ISAR3_EL1: u32 = sys_reg(3, 0, 0, 2, 3);
+const ID_ISAR4_EL1: u32 = sys_reg(3, 0, 0, 2, 4);
+const ID_ISAR5_EL1: u32 = sys_reg(3, 0, 0, 2, 5);
+const ID_MMFR4_EL1: u32 = sys_reg(3, 0, 0, 2, 6);
+const ID_ISAR6_EL1: u32 = sys_reg(3, 0, 0, 2, 7);
+const MVFR0_EL1: u32 = sys_reg(3, 0, 0, 3, 0);
+const MVFR1_EL1: u32 = sys_reg(3, 0, 0, 3, 1);
+const MVFR2_EL1: u32 = sys_reg(3, 0, 0, 3, 2);
+const ID_AA64PFR0_EL1: u32 = sys_reg(3, 0, 0, 4, 0);
+const ID_AA64PFR1_EL1: u32 = sys_reg(3, 0, 0, 4, 1);
+const ID_AA64ZFR0_EL1: u32 = sys_reg(3, 0, 0, 4, 4);
+const ID_AA64DFR0_EL1: u32 = sys_reg(3, 0, 0, 5, 0);
+const ID_AA64DFR1_EL1: u32 = sys_reg(3, 0, 0, 5, 1);
+const ID_AA64AFR0_EL1: u32 = sys_reg(3, 0, 0, 5, 4);
+const ID_AA64AFR1_EL1: u32 = sys_reg(3, 0, 0, 5, 5);
+const ID_AA64ISAR0_EL1: u32 = sys_reg(3, 0, 0, 6, 0);
+const ID_AA64ISAR1_EL1: u32 = sys_reg(3, 0, 0, 6, 1);
+const ID_AA64ISAR2_EL1: u32 = sys_reg(3, 0, 0, 6, 2);
+const ID_AA64MMFR0_EL1: u32 = sys_reg(3, 0, 0, 7, 0);
+const ID_AA64MMFR1_EL1: u32 = sys_reg(3, 0, 0, 7, 1);
+const ID_AA64MMFR2_EL1: u32 = sys_reg(3, 0, 0, 7, 2);
+
+/// ID_AA64PFR0_EL1: SVE, MPAM and AMU, which guests aren't given.
+const PFR0_HIDDEN: u64 = 0xf << 32 | 0xff << 40;
+/// ID_AA64PFR1_EL1: MTE and SME, which guests aren't given.
+const PFR1_HIDDEN: u64 = 0xf << 24 | 0xf << 8;
+
+const DC_ISW: u32 = sys_reg(1, 0, 7, 6, 2);
+const DC_CSW: u32 = sys_reg(1, 0, 7, 10, 2);
+const DC_CISW: u32 = sys_reg(1, 0, 7, 14, 2);
+
+static SYSREG_TABLE: &[SysRegEmul] = &[
+    // SGIs, trapped by HCR_EL2.IMO and FMO. The interrupts of a zone are all
+    // non-secure group 1, whichever register the guest raises them with.
+    write_only("ICC_SGI1R_EL1", ICC_SGI1R_EL1, send_virtual_sgi),
+    write_only("ICC_ASGI1R_EL1", ICC_ASGI1R_EL1, send_virtual_sgi),
+    write_only("ICC_SGI0R_EL1", ICC_SGI0R_EL1, send_virtual_sgi),
+    // ID registers, trapped by HCR_EL2.TID3. The AArch32 ones are read by
+    // AArch64 guests as well, for their 32-bit EL0.
+    read_only("ID_PFR0_EL1", ID_PFR0_EL1, || read_sysreg!(id_pfr0_el1)),
+    read_only("ID_PFR1_EL1", ID_PFR1_EL1, || read_sysreg!(id_pfr1_el1)),
+    read_only("ID_DFR0_EL1", ID_DFR0_EL1, || read_sysreg!(id_dfr0_el1)),
+    read_only("ID_AFR0_EL1", ID_AFR0_EL1, || read_sysreg!(id_afr0_el1)),
+    read_only("ID_MMFR0_EL1", ID_MMFR0_EL1, || read_sysreg!(id_mmfr0_el1)),
+    read_only("ID_MMFR1_EL1", ID_MMFR1_EL1, || read_sysreg!(id_mmfr1_el1)),
+    read_only("ID_MMFR2_EL1", ID_MMFR2_EL1, || read_sysreg!(id_mmfr2_el1)),
+    read_only("ID_MMFR3_EL1", ID_MMFR3_EL1, || read_sysreg!(id_mmfr3_el1)),
+    read_only("ID_ISAR0_EL1", ID_ISAR0_EL1, || read_sysreg!(id_isar0_el1)),
+    read_only("ID_ISAR1_EL1", ID_ISAR1_EL1, || read_sysreg!(id_isar1_el1)),
+    read_only("ID_ISAR2_EL1", ID_ISAR2_EL1, || read_sysreg!(id_isar2_el1)),
+    read_only("ID_ISAR3_EL1", ID_ISAR3_EL1, || read_sysreg!(id_isar3_el1)),
+    read_only("ID_ISAR4_EL1", ID_ISAR4_EL1, || read_sysreg!(id_isar4_el1)),
+    read_only("ID_ISAR5_EL1", ID_ISAR5_EL1, || read_sysreg!(id_isar5_el1)),
+    read_only("ID_MMFR4_EL1", ID_MMFR4_EL1, || read_sysreg!(s3_0_c0_c2_6)),
+    read_only("ID_ISAR6_EL1", ID_ISAR6_EL1, || read_sysreg!(s3_0_c0_c2_7)),
+    read_only("MVFR0_EL1", MVFR0_EL1, || read_sysreg!(mvfr0_el1)),
+    read_only("MVFR1_EL1", MVFR1_EL1, || read_sysreg!(mvfr1_el1)),
+    read_only("MVFR2_EL1", MVFR2_EL1, || read_sysreg!(mvfr2_el1)),
+    read_only("ID_AA64PFR0_EL1", ID_AA64PFR0_EL1, || {
+        read_sysreg!(id_aa64pfr0_el1) & !PFR0_HIDDEN
+    }),
+    read_only("ID_AA64PFR1_EL1", ID_AA64PFR1_EL1, || {
+        read_sysreg!(id_aa64pfr1_el1) & !PFR1_HIDDEN
+    }),
+    read_only("ID_AA64ZFR0_EL1", ID_AA64ZFR0_EL1, || 0),
+    read_only("ID_AA64DFR0_EL1", ID_AA64DFR0_EL1, || read_sysreg!(id_aa64dfr0_el1)),
+    read_only("ID_AA64DFR1_EL1", ID_AA64DFR1_EL1, || read_sysreg!(id_aa64dfr1_el1)),
+    read_only("ID_AA64AFR0_EL1", ID_AA64AFR0_EL1, || read_sysreg!(id_aa64afr0_el1)),
+    read_only("ID_AA64AFR1_EL1", ID_AA64AFR1_EL1, || read_sysreg!(id_aa64afr1_el1)),
+    read_only("ID_AA64ISAR0_EL1", ID_AA64ISAR0_EL1, || read_sysreg!(id_aa64isar0_el1)),
+    read_only("ID_AA64ISAR1_EL1", ID_AA64ISAR1_EL1, || read_sysreg!(id_aa64isar1_el1)),
+    read_only("ID_AA64ISAR2_EL1", ID_AA64ISAR2_EL1, || read_sysreg!(s3_0_c0_c6_2)),
+    read_only("ID_AA64MMFR0_EL1", ID_AA64MMFR0_EL1, || read_sysreg!(id_aa64mmfr0_el1)),
+    read_only("ID_AA64MMFR1_EL1", ID_AA64MMFR1_EL1, || read_sysreg!(id_aa64mmfr1_el1)),
+    read_only("ID_AA64MMFR2_EL1", ID_AA64MMFR2_EL1, || read_sysreg!(s3_0_c0_c7_2)),
+    // set/way cache maintenance, trapped by HCR_EL2.TSW. It doesn't go
+    // through stage 2: cleaning as well keeps the data of other zones
+    write_only("DC ISW", DC_ISW, dc_cisw),
+    write_only("DC CSW", DC_CSW, dc_cisw),
+    write_only("DC CISW", DC_CISW, dc_cisw),
+];
+
+/// The rest of the ID register space trapped by HCR_EL2.TID3, which is
+/// reserved or describes features guests aren't given.
+static ID_RAZ: SysRegEmul = read_only("ID register", 0, || 0);
+
+/// Whether `encoding` is in the ID register space trapped by HCR_EL2.TID3:
+/// op0 3, op1 0, CRn 0 and CRm 1 to 7.
+fn is_id_reg(encoding: u32) -> bool {
+    let crm = encoding >> 8 & 0xf;
+    encoding & !(0xf << 8 | 0x7 << 5) == sys_reg(3, 0, 0, 0, 0) && (1..=7).contains(&crm)
+}
+
+/// The emulation of the register or instruction `encoding`.
+pub fn find_sysreg(encoding: u32) -> Option<&'static SysRegEmul> {
+    SYSREG_TABLE
+        .iter()
+        .find(|emul| emul.encoding == encoding)
+        .or_else(|| is_id_reg(encoding).then_some(&ID_RAZ))
+}
+
+fn dc_cisw(set_way: u64) {
+    unsafe { asm!("dc cisw, {}", "dsb sy", in(reg) set_way) };
+}
+
+/// Forward a guest write of an SGI register to the physical cpus of the zone,
+/// through `ICC_SGI1R_EL1`. Guests only know virtual MPIDRs, the affinity of a
+/// vcpu being its id: targets are translated to the cpus of the zone, the
+/// others are dropped.
+fn send_virtual_sgi(val: u64) {
+    let sgi = val & SGI1R_INTID_MASK;
+    if !this_cpu_data().arch_cpu.psci_on {
+        warn!("skip send sgi {:#x?}", sgi >> 24);
+        return;
+    }
+    trace!("send sgi {:#x?}", sgi >> 24);
+    let (cpu_set, vgic) = {
+        let zone = this_zone();
+        let zone = zone.read();
+        (zone.cpu_set, zone.vgic.clone())
+    };
+    // with a software GIC, SGIs stay virtual
+    let send = |cpu: usize| match &vgic {
+        Some(vgic) => vgic.raise_sgi(cpu, (sgi >> 24) as _),
+        None => write_sysreg!(icc_sgi1r_el1, sgi | sgi1r_target(cpu as _)),
+    };
+
+    if val & SGI1R_IRM != 0 {
+        cpu_set.iter_except(this_cpu_data().id).for_each(send);
+        return;
+    }
+    for vcpu_id in sgi1r_targets(val) {
+        match cpu_set.cpu_of_vcpu(vcpu_id as _) {
+            Some(cpu) => send(cpu),
+            None => warn!("sgi {:#x?} to unknown vcpu {:#x?}", sgi >> 24, vcpu_id),
+        }
+    }
+}
@@ -4,17 +4,24 @@ use core::arch::global_asm;
 use crate::{
     arch::{
         cpu::mpidr_to_cpuid,
-        sysreg::{read_sysreg, write_sysreg},
+        sysreg::read_sysreg,
     },
+    consts::MAX_CPU_NUM,
+    control::{reset_current_cpu, wait_for_poweron},
     device::irqchip::gicv3::gicv3_handle_irq_el1,
-    event::{send_event, IPI_EVENT_SHUTDOWN, IPI_EVENT_WAKEUP},
+    event::{send_event, IPI_EVENT_WAKEUP},
     hypercall::HyperCall,
     memory::{mmio_handle_access, MMIOAccess},
-    percpu::{get_cpu_data, this_cpu_data, PerCpu},
-    zone::{is_this_root_zone, remove_zone},
+    percpu::{get_cpu_data, this_cpu_data, this_zone, PerCpu},
+    scheduler::schedule,
+    zone::{is_this_root_zone, zone_reboot, zone_shutdown},
 };
 
+use super::backtrace::print_trap_backtrace;
+use super::coredump::{zone_fatal, SIGABRT};
 use super::cpu::GeneralRegisters;
+use super::inject::{inject_dabt, inject_iabt, inject_undef};
+use super::sysreg_emul::{find_sysreg, sys_reg};
 
 global_asm!(
     include_str!("./trap.S"),
@@ -61,6 +68,7 @@ pub mod PsciFnId {
     pub const PSCI_AFFINITY_INFO_32: u64 = 0x84000004;
     pub const PSCI_MIG_INFO_TYPE: u64 = 0x84000006;
     pub const PSCI_SYSTEM_OFF: u64 = 0x84000008;
+    pub const PSCI_SYSTEM_RESET: u64 = 0x84000009;
     pub const PSCI_FEATURES: u64 = 0x8400000a;
 
     pub const PSCI_CPU_SUSPEND_64: u64 = 0xc4000001;
@@ -69,6 +77,21 @@ pub mod PsciFnId {
     pub const PSCI_AFFINITY_INFO_64: u64 = 0xc4000004;
 }
 #[allow(non_snake_case)]
+pub mod PsciRet {
+    pub const SUCCESS: u64 = 0;
+    pub const NOT_SUPPORTED: u64 = -1i64 as u64;
+    pub const INVALID_PARAMETERS: u64 = -2i64 as u64;
+    pub const DENIED: u64 = -3i64 as u64;
+    pub const ALREADY_ON: u64 = -4i64 as u64;
+    pub const ON_PENDING: u64 = -5i64 as u64;
+}
+#[allow(non_snake_case)]
+pub mod PsciAffinityState {
+    pub const ON: u64 = 0;
+    pub const OFF: u64 = 1;
+    pub const ON_PENDING: u64 = 2;
+}
+#[allow(non_snake_case)]
 pub mod SMCccFnId {
     pub const SMCCC_VERSION: u64 = 0x80000000;
     pub const SMCCC_ARCH_FEATURES: u64 = 0x80000001;
@@ -91,7 +114,7 @@ pub fn arch_handle_exit(regs: &mut GeneralRegisters) -> ! {
         ExceptionType::EXIT_REASON_EL1_ABORT => arch_handle_trap_el1(regs),
         ExceptionType::EXIT_REASON_EL2_ABORT => arch_handle_trap_el2(regs),
         ExceptionType::EXIT_REASON_EL2_IRQ => irqchip_handle_irq2(),
-        _ => arch_dump_exit(regs.exit_reason),
+        _ => arch_dump_exit(regs),
     }
     unsafe { vmreturn(regs as *const _ as usize) }
 }
@@ -127,45 +150,30 @@ fn arch_handle_trap_el1(regs: &mut GeneralRegisters) {
                 ESR_EL2.read(ESR_EL2::EC)
             );
             error!("esr_el2: iss {:#x?}", ESR_EL2.read(ESR_EL2::ISS));
-            loop {}
-            // ret = TrapReturn::TrapUnhandled;
+            inject_undef(regs);
         }
     }
 }
 
-fn arch_handle_trap_el2(_regs: &mut GeneralRegisters) {
-    let elr = ELR_EL2.get();
-    let esr = ESR_EL2.get();
-    let far = FAR_EL2.get();
-    match ESR_EL2.read_as_enum(ESR_EL2::EC) {
-        Some(ESR_EL2::EC::Value::HVC64) => {
-            println!("EL2 Exception: HVC64 call, ELR_EL2: {:#x?}", ELR_EL2.get());
-        }
-        Some(ESR_EL2::EC::Value::SMC64) => {
-            println!("EL2 Exception: SMC64 call, ELR_EL2: {:#x?}", ELR_EL2.get());
-        }
-        Some(ESR_EL2::EC::Value::DataAbortCurrentEL) => {
-            loop {}
-            println!(
-                "EL2 Exception: Data Abort, ELR_EL2: {:#x?}, FAR_EL2: {:#x?}",
-                elr, esr
-            );
-        }
-        Some(ESR_EL2::EC::Value::InstrAbortCurrentEL) => {
-            println!(
-                "EL2 Exception: Instruction Abort, ELR_EL2: {:#x?}, FAR_EL2: {:#x?}",
-                ELR_EL2.get(),
-                FAR_EL2.get()
-            )
-        }
-        _ => {
-            println!("Unhandled EL2 Exception: EC={:#x?}", 1);
-        }
-    }
-    loop {}
+/// A fault of the hypervisor itself, which panics.
+fn arch_handle_trap_el2(regs: &mut GeneralRegisters) -> ! {
+    let (elr, esr, far) = (ELR_EL2.get(), ESR_EL2.get(), FAR_EL2.get());
+    let what = match ESR_EL2.read_as_enum(ESR_EL2::EC) {
+        Some(ESR_EL2::EC::Value::HVC64) => "HVC64 call",
+        Some(ESR_EL2::EC::Value::SMC64) => "SMC64 call",
+        Some(ESR_EL2::EC::Value::DataAbortCurrentEL) => "data abort",
+        Some(ESR_EL2::EC::Value::InstrAbortCurrentEL) => "instruction abort",
+        _ => "unhandled exception",
+    };
+    // the backtrace of the panic starts in the trap handler, not at the fault
+    print_trap_backtrace(regs, elr as _);
+    panic!(
+        "EL2 {}, ELR_EL2: {:#x}, ESR_EL2: {:#x}, FAR_EL2: {:#x}",
+        what, elr, esr, far
+    )
 }
 
-fn handle_iabt(_regs: &mut GeneralRegisters) {
+fn handle_iabt(regs: &mut GeneralRegisters) {
     let iss = ESR_EL2.read(ESR_EL2::ISS);
     let op = iss >> 6 & 0x1;
     let hpfar = read_sysreg!(HPFAR_EL2);
@@ -174,9 +182,7 @@ fn handle_iabt(_regs: &mut GeneralRegisters) {
     address |= hdfar & 0xfff;
     error!("error ins access {} at {:#x?}!", op, address);
     error!("esr_el2: iss {:#x?}", iss);
-    loop {}
-    //TODO finish dabt handle
-    // arch_skip_instruction(frame);
+    inject_iabt(regs, hdfar);
 }
 fn handle_dabt(regs: &mut GeneralRegisters) {
     let iss = ESR_EL2.read(ESR_EL2::ISS);
@@ -214,7 +220,9 @@ fn handle_dabt(regs: &mut GeneralRegisters) {
             }
         }
         Err(e) => {
-            panic!("mmio_handle_access: {:#x?}", e);
+            error!("mmio_handle_access: {:#x?}", e);
+            inject_dabt(regs, far, is_write);
+            return;
         }
     }
     //TODO finish dabt handle
@@ -222,21 +230,48 @@ fn handle_dabt(regs: &mut GeneralRegisters) {
 }
 
 fn handle_sysreg(regs: &mut GeneralRegisters) {
-    //TODO check sysreg type
-    //send sgi
-    trace!("esr_el2: iss {:#x?}", ESR_EL2.read(ESR_EL2::ISS));
-    let rt = (ESR_EL2.get() >> 5) & 0x1f;
-    let val = regs.usr[rt as usize];
-    trace!("esr_el2 rt{}: {:#x?}", rt, val);
-    let sgi_id: u64 = (val & (0xf << 24)) >> 24;
-    if !this_cpu_data().arch_cpu.psci_on {
-        warn!("skip send sgi {:#x?}", sgi_id);
-    } else {
-        trace!("send sgi {:#x?}", sgi_id);
-        write_sysreg!(icc_sgi1r_el1, val);
+    let iss = ESR_EL2.read(ESR_EL2::ISS);
+    let (op0, op2, op1) = ((iss >> 20) & 0x3, (iss >> 17) & 0x7, (iss >> 14) & 0x7);
+    let (crn, rt, crm) = ((iss >> 10) & 0xf, (iss >> 5) & 0x1f, (iss >> 1) & 0xf);
+    let is_read = iss & 0x1 != 0;
+    let encoding = sys_reg(op0 as _, op1 as _, crn as _, crm as _, op2 as _);
+    trace!("esr_el2: iss {:#x?}", iss);
+
+    let emul = match find_sysreg(encoding) {
+        Some(emul) => emul,
+        None => {
+            warn!(
+                "unhandled sysreg s{}_{}_c{}_c{}_{}, read: {}",
+                op0, op1, crn, crm, op2, is_read
+            );
+            inject_undef(regs);
+            return;
+        }
+    };
+    match (is_read, emul.read, emul.write) {
+        (true, Some(read), _) => {
+            let val = read();
+            trace!("{} read: {:#x?}", emul.name, val);
+            // rt 31 is xzr
+            if rt != 31 {
+                regs.usr[rt as usize] = val;
+            }
+        }
+        (false, _, Some(write)) => {
+            let val = match rt {
+                31 => 0,
+                _ => regs.usr[rt as usize],
+            };
+            trace!("{} write: {:#x?}", emul.name, val);
+            write(val);
+        }
+        _ => {
+            warn!("{}: unsupported access, read: {}", emul.name, is_read);
+            inject_undef(regs);
+            return;
+        }
     }
-
-    arch_skip_instruction(regs); //skip sgi write
+    arch_skip_instruction(regs);
 }
 
 fn handle_hvc(regs: &mut GeneralRegisters) {
@@ -245,20 +280,20 @@ fn handle_hvc(regs: &mut GeneralRegisters) {
         return;
     }
     */
-    let (code, arg0, arg1) = (regs.usr[0], regs.usr[1], regs.usr[2]);
+    let (code, arg0, arg1, arg2) = (regs.usr[0], regs.usr[1], regs.usr[2], regs.usr[3]);
     let cpu_data = this_cpu_data();
 
     debug!(
-        "HVC from CPU{},code:{:#x?},arg0:{:#x?},arg1:{:#x?}",
-        cpu_data.id, code, arg0, arg1
+        "HVC from CPU{},code:{:#x?},arg0:{:#x?},arg1:{:#x?},arg2:{:#x?}",
+        cpu_data.id, code, arg0, arg1, arg2
     );
-    let result = HyperCall::new(cpu_data).hypercall(code as _, arg0, arg1) {
+    let result = match HyperCall::new(cpu_data).hypercall(code as _, arg0, arg1, arg2) {
         Ok(ret) => ret as _,
         Err(e) => {
             error!("hypercall error: {:#x?}", e);
             e.code()
         }
-    }
+    };
     debug!("HVC result = {}", result);
     regs.usr[0] = result as _;
 }
@@ -295,37 +330,79 @@ fn psci_emulate_features_info(code: u64) -> u64 {
         | PsciFnId::PSCI_AFFINITY_INFO_32
         | PsciFnId::PSCI_AFFINITY_INFO_64
         | PsciFnId::PSCI_FEATURES
-        | SMCccFnId::SMCCC_VERSION => 0,
-        _ => !0,
+        | PsciFnId::PSCI_SYSTEM_OFF
+        | PsciFnId::PSCI_SYSTEM_RESET
+        | SMCccFnId::SMCCC_VERSION => PsciRet::SUCCESS,
+        _ => PsciRet::NOT_SUPPORTED,
+    }
+}
+
+/// Find the cpu of the calling zone targeted by a PSCI call. Guests name cpus
+/// by virtual MPIDR, whose Aff0 is the index of the cpu in the zone.
+fn psci_zone_cpu(mpidr: u64) -> Result<usize, u64> {
+    let vcpu_id = mpidr_to_cpuid(mpidr) as usize;
+    if vcpu_id >= MAX_CPU_NUM {
+        return Err(PsciRet::INVALID_PARAMETERS);
+    }
+    match this_zone().read().cpu_set.cpu_of_vcpu(vcpu_id) {
+        Some(cpu) => Ok(cpu),
+        None => {
+            warn!("psci: vcpu {} is not in the zone", vcpu_id);
+            Err(PsciRet::DENIED)
+        }
     }
 }
 
 fn psci_emulate_cpu_on(regs: &mut GeneralRegisters) -> u64 {
-    // Todo: Check if `cpu` is in the cpuset of current zone
-    let cpu = mpidr_to_cpuid(regs.usr[1]);
+    let cpu = match psci_zone_cpu(regs.usr[1]) {
+        Ok(cpu) => cpu,
+        Err(ret) => return ret,
+    };
     info!("psci: try to wake up cpu {}", cpu);
 
-    let target_data = get_cpu_data(cpu as _);
+    let target_data = get_cpu_data(cpu);
     let _lock = target_data.ctrl_lock.lock();
 
-    if !target_data.arch_cpu.psci_on {
-        target_data.cpu_on_entry = regs.usr[2] as _;
-        target_data.arch_cpu.psci_on = true;
-        send_event(cpu as _, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
-    } else {
+    if target_data.arch_cpu.psci_on_pending {
+        return PsciRet::ON_PENDING;
+    }
+    if target_data.arch_cpu.psci_on {
         error!("psci: cpu {} already on", cpu);
-        return u64::MAX - 3;
-    };
+        return PsciRet::ALREADY_ON;
+    }
+    target_data.cpu_on_entry = regs.usr[2] as _;
+    target_data.arch_cpu.psci_on_pending = true;
+    send_event(cpu, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
     drop(_lock);
 
-    0
+    PsciRet::SUCCESS
+}
+
+fn psci_emulate_affinity_info(target_affinity: u64, lowest_affinity_level: u64) -> u64 {
+    if lowest_affinity_level != 0 {
+        return PsciRet::INVALID_PARAMETERS;
+    }
+    let cpu = match psci_zone_cpu(target_affinity) {
+        Ok(cpu) => cpu,
+        // other zones' cpus don't exist for the caller
+        Err(_) => return PsciRet::INVALID_PARAMETERS,
+    };
+    let target_data = get_cpu_data(cpu);
+    let _lock = target_data.ctrl_lock.lock();
+    if target_data.arch_cpu.psci_on_pending {
+        PsciAffinityState::ON_PENDING
+    } else if target_data.arch_cpu.psci_on {
+        PsciAffinityState::ON
+    } else {
+        PsciAffinityState::OFF
+    }
 }
 
 fn handle_psci_smc(
     regs: &mut GeneralRegisters,
     code: u64,
     arg0: u64,
-    _arg1: u64,
+    arg1: u64,
     _arg2: u64
 ) -> u64 {
     match code {
@@ -336,42 +413,54 @@ fn handle_psci_smc(
             0
         }
         PsciFnId::PSCI_CPU_OFF_32 | PsciFnId::PSCI_CPU_OFF_64 => {
-            todo!();
+            // the vcpu is off until a later CPU_ON, a shared cpu goes to the
+            // next runnable vcpu
+            let cpu_data = this_cpu_data();
+            info!("psci: cpu {} off", cpu_data.id);
+            let _lock = cpu_data.ctrl_lock.lock();
+            cpu_data.arch_cpu.psci_on = false;
+            drop(_lock);
+            schedule();
+            wait_for_poweron()
         }
         PsciFnId::PSCI_AFFINITY_INFO_32 | PsciFnId::PSCI_AFFINITY_INFO_64 => {
-            !get_cpu_data(arg0 as _).arch_cpu.psci_on as _
+            psci_emulate_affinity_info(arg0, arg1)
         }
         PsciFnId::PSCI_MIG_INFO_TYPE => PSCI_TOS_NOT_PRESENT_MP,
         PsciFnId::PSCI_FEATURES => psci_emulate_features_info(regs.usr[1]),
         PsciFnId::PSCI_CPU_ON_32 | PsciFnId::PSCI_CPU_ON_64 => psci_emulate_cpu_on(regs),
         PsciFnId::PSCI_SYSTEM_OFF => {
-            todo!();
-            // this_zone().read().suspend();
-            // for cpu in this_zone().read().cpu_set.iter_except(this_cpu_data().id) {
-            //     park_cpu(cpu);
-            // }
-            // park_current_cpu();
-            // 0
-            let zone = this_zone();
-            let zone_id = zone.read().id;
-            let is_root = is_this_root_zone();
-
-            for cpu_id in zone.read().cpu_set.iter_except(this_cpu_data().id) {
-                let target_cpu = get_cpu_data(cpu_id);
-                let _lock = target_cpu.ctrl_lock.lock();
-                target_cpu.zone = None;
-                send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
+            if is_this_root_zone() {
+                psci::system_off().unwrap();
             }
-
-            this_cpu_data().zone = None;
+            // only the zone is shut down, its cpus are parked or, if shared,
+            // given to the next vcpu
+            match zone_shutdown(this_zone()) {
+                Ok(_) => {
+                    schedule();
+                    this_cpu_data().arch_cpu.idle()
+                }
+                Err(e) => {
+                    error!("psci: zone shutdown failed: {:?}", e);
+                    !0
+                }
+            }
+        }
+        PsciFnId::PSCI_SYSTEM_RESET => {
+            if is_this_root_zone() {
+                psci::system_reset().unwrap();
+            }
+            // only the zone is restarted, keeping its memory and irqs
+            let zone = this_zone();
+            let rebooted = zone_reboot(&zone);
             drop(zone);
-            remove_zone(zone_id);
-
-            if is_root {
-                psci::system_off().unwrap();
+            match rebooted {
+                Ok(_) => reset_current_cpu(),
+                Err(e) => {
+                    error!("psci: zone reboot failed: {:?}", e);
+                    !0
+                }
             }
-
-            this_cpu_data().arch_cpu.idle();
         }
 
         _ => {
@@ -412,10 +501,9 @@ fn arch_skip_instruction(_regs: &mut GeneralRegisters) {
     ELR_EL2.set(pc);
 }
 
-fn arch_dump_exit(reason: u64) {
-    //TODO hypervisor coredump
-    error!("Unsupported Exit:{:#x?}, elr={:#x?}", reason, ELR_EL2.get());
-    loop {}
+fn arch_dump_exit(regs: &mut GeneralRegisters) -> ! {
+    error!("Unsupported Exit:{:#x?}, elr={:#x?}", regs.exit_reason, ELR_EL2.get());
+    zone_fatal(regs, SIGABRT)
 }
 
 #[naked]
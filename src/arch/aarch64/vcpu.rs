@@ -0,0 +1,292 @@
+//! Guest state of a vcpu switched out of its cpu, and the EL2 timer which
+//! drives the switches.
+//!
+//! The hypervisor itself is built without FP/SIMD, so the guest's FP/SIMD
+//! registers stay live in the cpu across traps and only move on a switch.
+
+use core::arch::asm;
+
+use aarch64_cpu::registers::{Readable, Writeable, ELR_EL2, SPSR_EL2};
+
+use super::cpu::GeneralRegisters;
+use super::sysreg::{read_sysreg, write_sysreg};
+use crate::device::irqchip::gicv3::{apr_num, lr_num, read_apr, read_lr, write_apr, write_lr};
+
+/// PPI of the EL2 physical timer, the scheduler tick.
+pub const SCHED_TIMER_IRQ: usize = 26;
+
+const ICH_LR_HW: u64 = 1 << 61;
+const ICH_LR_PINTID_MASK: u64 = 0x3ff << 32;
+
+#[derive(Debug, Default, Clone)]
+pub struct VcpuContext {
+    pub usr: [u64; 31],
+    pub elr_el2: u64,
+    pub spsr_el2: u64,
+    pub vmpidr_el2: u64,
+
+    pub sp_el0: u64,
+    pub sp_el1: u64,
+    pub elr_el1: u64,
+    pub spsr_el1: u64,
+
+    pub sctlr_el1: u64,
+    pub actlr_el1: u64,
+    pub cpacr_el1: u64,
+    pub ttbr0_el1: u64,
+    pub ttbr1_el1: u64,
+    pub tcr_el1: u64,
+    pub mair_el1: u64,
+    pub amair_el1: u64,
+    pub vbar_el1: u64,
+    pub contextidr_el1: u64,
+    pub tpidr_el0: u64,
+    pub tpidrro_el0: u64,
+    pub tpidr_el1: u64,
+    pub esr_el1: u64,
+    pub far_el1: u64,
+    pub par_el1: u64,
+    pub afsr0_el1: u64,
+    pub afsr1_el1: u64,
+    pub csselr_el1: u64,
+    pub mdscr_el1: u64,
+    pub pmcr_el0: u64,
+
+    pub cntkctl_el1: u64,
+    pub cntvoff_el2: u64,
+    pub cntv_ctl_el0: u64,
+    pub cntv_cval_el0: u64,
+    pub cntp_ctl_el0: u64,
+    pub cntp_cval_el0: u64,
+
+    pub ich_vmcr_el2: u64,
+    pub ich_lr: [u64; 16],
+    pub ich_ap0r: [u64; 4],
+    pub ich_ap1r: [u64; 4],
+
+    pub fp_q: [u128; 32],
+    pub fpcr: u64,
+    pub fpsr: u64,
+}
+
+impl VcpuContext {
+    /// Save the guest state of the current cpu, `regs` being its trap frame.
+    ///
+    /// The guest timers are stopped and the list registers are emptied, so
+    /// nothing of this guest reaches the vcpu loaded next.
+    pub fn save(regs: &GeneralRegisters) -> Self {
+        let mut ctx = Self {
+            usr: regs.usr,
+            elr_el2: ELR_EL2.get(),
+            spsr_el2: SPSR_EL2.get(),
+            vmpidr_el2: read_sysreg!(VMPIDR_EL2),
+
+            sp_el0: read_sysreg!(SP_EL0),
+            sp_el1: read_sysreg!(SP_EL1),
+            elr_el1: read_sysreg!(ELR_EL1),
+            spsr_el1: read_sysreg!(SPSR_EL1),
+
+            sctlr_el1: read_sysreg!(SCTLR_EL1),
+            actlr_el1: read_sysreg!(ACTLR_EL1),
+            cpacr_el1: read_sysreg!(CPACR_EL1),
+            ttbr0_el1: read_sysreg!(TTBR0_EL1),
+            ttbr1_el1: read_sysreg!(TTBR1_EL1),
+            tcr_el1: read_sysreg!(TCR_EL1),
+            mair_el1: read_sysreg!(MAIR_EL1),
+            amair_el1: read_sysreg!(AMAIR_EL1),
+            vbar_el1: read_sysreg!(VBAR_EL1),
+            contextidr_el1: read_sysreg!(CONTEXTIDR_EL1),
+            tpidr_el0: read_sysreg!(TPIDR_EL0),
+            tpidrro_el0: read_sysreg!(TPIDRRO_EL0),
+            tpidr_el1: read_sysreg!(TPIDR_EL1),
+            esr_el1: read_sysreg!(ESR_EL1),
+            far_el1: read_sysreg!(FAR_EL1),
+            par_el1: read_sysreg!(PAR_EL1),
+            afsr0_el1: read_sysreg!(AFSR0_EL1),
+            afsr1_el1: read_sysreg!(AFSR1_EL1),
+            csselr_el1: read_sysreg!(CSSELR_EL1),
+            mdscr_el1: read_sysreg!(MDSCR_EL1),
+            pmcr_el0: read_sysreg!(PMCR_EL0),
+
+            cntkctl_el1: read_sysreg!(CNTKCTL_EL1),
+            cntvoff_el2: read_sysreg!(CNTVOFF_EL2),
+            cntv_ctl_el0: read_sysreg!(CNTV_CTL_EL0),
+            cntv_cval_el0: read_sysreg!(CNTV_CVAL_EL0),
+            cntp_ctl_el0: read_sysreg!(CNTP_CTL_EL0),
+            cntp_cval_el0: read_sysreg!(CNTP_CVAL_EL0),
+
+            ich_vmcr_el2: read_sysreg!(ICH_VMCR_EL2),
+            ..Default::default()
+        };
+        write_sysreg!(CNTV_CTL_EL0, 0);
+        write_sysreg!(CNTP_CTL_EL0, 0);
+
+        for i in 0..lr_num() {
+            let mut lr = read_lr(i);
+            // A PPI like the virtual timer is shared by the vcpus of the cpu:
+            // deactivate it now and let the guest only see the virtual one.
+            let pintid = (lr & ICH_LR_PINTID_MASK) >> 32;
+            if lr & ICH_LR_HW != 0 && pintid < 32 {
+                write_sysreg!(icc_dir_el1, pintid);
+                lr &= !(ICH_LR_HW | ICH_LR_PINTID_MASK);
+            }
+            ctx.ich_lr[i] = lr;
+            write_lr(i, 0);
+        }
+        for i in 0..apr_num() {
+            ctx.ich_ap0r[i] = read_apr(0, i);
+            ctx.ich_ap1r[i] = read_apr(1, i);
+        }
+        ctx.save_fp();
+        ctx
+    }
+
+    /// Load the saved guest state on the current cpu, `regs` being its trap
+    /// frame. The stage-2 page table of the zone must already be active.
+    pub fn restore(&self, regs: &mut GeneralRegisters) {
+        regs.usr = self.usr;
+        ELR_EL2.set(self.elr_el2);
+        SPSR_EL2.set(self.spsr_el2);
+        write_sysreg!(VMPIDR_EL2, self.vmpidr_el2);
+
+        write_sysreg!(SP_EL0, self.sp_el0);
+        write_sysreg!(SP_EL1, self.sp_el1);
+        write_sysreg!(ELR_EL1, self.elr_el1);
+        write_sysreg!(SPSR_EL1, self.spsr_el1);
+
+        write_sysreg!(SCTLR_EL1, self.sctlr_el1);
+        write_sysreg!(ACTLR_EL1, self.actlr_el1);
+        write_sysreg!(CPACR_EL1, self.cpacr_el1);
+        write_sysreg!(TTBR0_EL1, self.ttbr0_el1);
+        write_sysreg!(TTBR1_EL1, self.ttbr1_el1);
+        write_sysreg!(TCR_EL1, self.tcr_el1);
+        write_sysreg!(MAIR_EL1, self.mair_el1);
+        write_sysreg!(AMAIR_EL1, self.amair_el1);
+        write_sysreg!(VBAR_EL1, self.vbar_el1);
+        write_sysreg!(CONTEXTIDR_EL1, self.contextidr_el1);
+        write_sysreg!(TPIDR_EL0, self.tpidr_el0);
+        write_sysreg!(TPIDRRO_EL0, self.tpidrro_el0);
+        write_sysreg!(TPIDR_EL1, self.tpidr_el1);
+        write_sysreg!(ESR_EL1, self.esr_el1);
+        write_sysreg!(FAR_EL1, self.far_el1);
+        write_sysreg!(PAR_EL1, self.par_el1);
+        write_sysreg!(AFSR0_EL1, self.afsr0_el1);
+        write_sysreg!(AFSR1_EL1, self.afsr1_el1);
+        write_sysreg!(CSSELR_EL1, self.csselr_el1);
+        write_sysreg!(MDSCR_EL1, self.mdscr_el1);
+        write_sysreg!(PMCR_EL0, self.pmcr_el0);
+
+        write_sysreg!(CNTKCTL_EL1, self.cntkctl_el1);
+        write_sysreg!(CNTVOFF_EL2, self.cntvoff_el2);
+        write_sysreg!(CNTV_CVAL_EL0, self.cntv_cval_el0);
+        write_sysreg!(CNTV_CTL_EL0, self.cntv_ctl_el0);
+        write_sysreg!(CNTP_CVAL_EL0, self.cntp_cval_el0);
+        write_sysreg!(CNTP_CTL_EL0, self.cntp_ctl_el0);
+
+        write_sysreg!(ICH_VMCR_EL2, self.ich_vmcr_el2);
+        for i in 0..lr_num() {
+            write_lr(i, self.ich_lr[i]);
+        }
+        for i in 0..apr_num() {
+            write_apr(0, i, self.ich_ap0r[i]);
+            write_apr(1, i, self.ich_ap1r[i]);
+        }
+        self.restore_fp();
+        unsafe { asm!("isb") };
+    }
+
+    /// Zero the guest state of the current cpu and its trap frame `regs`, so
+    /// that nothing of the previous guest is visible to the next one. The
+    /// virtual cpu interface control (ICH_VMCR_EL2) is kept.
+    pub fn wipe(regs: &mut GeneralRegisters) {
+        let ctx = Self {
+            ich_vmcr_el2: read_sysreg!(ICH_VMCR_EL2),
+            ..Default::default()
+        };
+        ctx.restore(regs);
+        regs.clear();
+    }
+
+    fn save_fp(&mut self) {
+        let (fpcr, fpsr): (u64, u64);
+        unsafe {
+            asm!(
+                ".arch_extension fp",
+                ".arch_extension simd",
+                "stp q0, q1, [{q}, #0]",
+                "stp q2, q3, [{q}, #32]",
+                "stp q4, q5, [{q}, #64]",
+                "stp q6, q7, [{q}, #96]",
+                "stp q8, q9, [{q}, #128]",
+                "stp q10, q11, [{q}, #160]",
+                "stp q12, q13, [{q}, #192]",
+                "stp q14, q15, [{q}, #224]",
+                "stp q16, q17, [{q}, #256]",
+                "stp q18, q19, [{q}, #288]",
+                "stp q20, q21, [{q}, #320]",
+                "stp q22, q23, [{q}, #352]",
+                "stp q24, q25, [{q}, #384]",
+                "stp q26, q27, [{q}, #416]",
+                "stp q28, q29, [{q}, #448]",
+                "stp q30, q31, [{q}, #480]",
+                "mrs {fpcr}, fpcr",
+                "mrs {fpsr}, fpsr",
+                q = in(reg) self.fp_q.as_mut_ptr(),
+                fpcr = out(reg) fpcr,
+                fpsr = out(reg) fpsr,
+                options(nostack),
+            );
+        }
+        self.fpcr = fpcr;
+        self.fpsr = fpsr;
+    }
+
+    fn restore_fp(&self) {
+        unsafe {
+            asm!(
+                ".arch_extension fp",
+                ".arch_extension simd",
+                "ldp q0, q1, [{q}, #0]",
+                "ldp q2, q3, [{q}, #32]",
+                "ldp q4, q5, [{q}, #64]",
+                "ldp q6, q7, [{q}, #96]",
+                "ldp q8, q9, [{q}, #128]",
+                "ldp q10, q11, [{q}, #160]",
+                "ldp q12, q13, [{q}, #192]",
+                "ldp q14, q15, [{q}, #224]",
+                "ldp q16, q17, [{q}, #256]",
+                "ldp q18, q19, [{q}, #288]",
+                "ldp q20, q21, [{q}, #320]",
+                "ldp q22, q23, [{q}, #352]",
+                "ldp q24, q25, [{q}, #384]",
+                "ldp q26, q27, [{q}, #416]",
+                "ldp q28, q29, [{q}, #448]",
+                "ldp q30, q31, [{q}, #480]",
+                "msr fpcr, {fpcr}",
+                "msr fpsr, {fpsr}",
+                q = in(reg) self.fp_q.as_ptr(),
+                fpcr = in(reg) self.fpcr,
+                fpsr = in(reg) self.fpsr,
+                options(nostack, readonly),
+            );
+        }
+    }
+}
+
+impl Drop for VcpuContext {
+    /// Don't leave the guest's registers behind in freed hypervisor memory.
+    fn drop(&mut self) {
+        unsafe { core::ptr::write_volatile(self, Self::default()) };
+    }
+}
+
+/// Raise [`SCHED_TIMER_IRQ`] on the current cpu in `ms` milliseconds.
+pub fn sched_timer_start(ms: u64) {
+    let ticks = read_sysreg!(CNTFRQ_EL0) * ms / 1000;
+    write_sysreg!(CNTHP_TVAL_EL2, ticks);
+    write_sysreg!(CNTHP_CTL_EL2, 1); // ENABLE, not masked
+}
+
+pub fn sched_timer_stop() {
+    write_sysreg!(CNTHP_CTL_EL2, 0);
+}
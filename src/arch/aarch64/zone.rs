@@ -3,11 +3,17 @@ use core::sync::atomic::{fence, Ordering};
 use alloc::vec::Vec;
 
 use crate::{
+    config::HvZoneConfig,
+    consts::{guest_mem_pool_start, hv_end, hv_start, GUEST_MEM_POOL_SIZE},
     device::virtio_trampoline::{mmio_virtio_handler, HVISOR_BRIDGE},
     error::HvResult,
     memory::{
-        addr::align_up, mmio_generic_handler, GuestPhysAddr, HostPhysAddr, MemFlags, MemoryRegion,
+        addr::align_up,
+        mmio_generic_handler,
+        ram::{fdt_ram_regions, remove_range},
+        Frame, GuestPhysAddr, HostPhysAddr, MemFlags, MemoryRegion, PAGE_SIZE,
     },
+    platform::{assigned_board_devices, ZONE_RESERVATIONS},
     zone::Zone,
 };
 
@@ -20,15 +26,45 @@ impl Zone {
         dtb_ipa: usize,
     ) -> HvResult {
         //debug!("fdt: {:?}", fdt);
-        // The first memory region is used to map the guest physical memory.
-        let mem_region = fdt.memory().regions().next().unwrap();
-        info!("map mem_region: {:#x?}", mem_region);
-        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-            mem_region.starting_address as GuestPhysAddr,
-            mem_region.starting_address as HostPhysAddr,
-            mem_region.size.unwrap(),
-            MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
-        ))?;
+        let dtb_range = dtb_ipa..dtb_ipa + align_up(fdt.total_size());
+        // the root zone never maps the hypervisor nor the guest memory pool,
+        // whatever its device tree says
+        let mut regions = fdt_ram_regions(fdt);
+        for hole in [
+            hv_start()..hv_end(),
+            guest_mem_pool_start()..guest_mem_pool_start() + GUEST_MEM_POOL_SIZE,
+        ] {
+            regions = remove_range(regions, &hole);
+        }
+        for region in regions {
+            if region.range.start < dtb_range.end && dtb_range.start < region.range.end {
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "memory region {:#x?} overlaps guest dtb at {:#x?}",
+                        region.range, dtb_range
+                    )
+                );
+            }
+            info!("map mem_region: {:#x?}", region);
+            self.gpm.insert(MemoryRegion::new_with_offset_mapper(
+                region.range.start as GuestPhysAddr,
+                region.range.start as HostPhysAddr,
+                region.range.len(),
+                region.flags,
+            ))?;
+        }
+        // the memory of the other zones is reserved with no-map, but stays
+        // reachable by the virtio backend of the root zone
+        for range in ZONE_RESERVATIONS.iter().flat_map(|zone| zone.memory) {
+            info!("map zone mem_region: {:#x?}", range);
+            self.gpm.insert(MemoryRegion::new_with_offset_mapper(
+                range.start as GuestPhysAddr,
+                range.start as HostPhysAddr,
+                range.len(),
+                MemFlags::READ | MemFlags::WRITE,
+            ))?;
+        }
         // map guest dtb
         info!("map guest dtb: {:#x?}", dtb_ipa);
         self.gpm.insert(MemoryRegion::new_with_offset_mapper(
@@ -38,171 +74,107 @@ impl Zone {
             MemFlags::READ | MemFlags::WRITE,
         ))?;
 
-        // probe virtio mmio device
-        {
-            let mut mapped_virtio = Vec::new();
-            let dev = HVISOR_BRIDGE.lock();
-            let region = if dev.is_enable {
-                Some(dev.immut_region())
-            } else {
-                None
-            };
-            for node in fdt.find_all_nodes("/virtio_mmio") {
-                if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
-                    let paddr = reg.starting_address as HostPhysAddr;
-                    let size = reg.size.unwrap();
-                    if !mapped_virtio.contains(&paddr) {
-                        info!("map virtio mmio addr: {:#x}, size: {:#x}", paddr, size);
-                        if region.is_some() {
-                            let dev_region = region.clone().unwrap();
-                            while dev_region.mmio_avail == 0 {}
-                            fence(Ordering::Acquire);
-                            if dev_region.mmio_addrs.contains(&(paddr as u64)) {
-                                self.mmio_region_register(paddr, size, mmio_virtio_handler, paddr);
-                            } else {
-                                self.mmio_region_register(paddr, size, mmio_generic_handler, paddr);
-                            }
-                        } else {
-                            self.mmio_region_register(paddr, size, mmio_generic_handler, paddr);
-                        }
-                        mapped_virtio.push(paddr);
-                    }
-                }
-            }
-            drop(dev);
-        }
+        self.virtio_mmio_init(fdt);
 
-        // probe uart device
-        for node in fdt.find_all_nodes("/pl011") {
-            if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
-                let paddr = reg.starting_address as HostPhysAddr;
-                let size = align_up(reg.size.unwrap());
-                info!("map uart addr: {:#x}, size: {:#x}", paddr, size);
-                self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-                    paddr as GuestPhysAddr,
-                    paddr,
-                    size,
-                    MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
-                ))?;
+        // passthrough devices of the board
+        for dev in assigned_board_devices(fdt) {
+            if dev.size == 0 {
+                continue;
             }
+            info!("map {} addr: {:#x}, size: {:#x}", dev.name, dev.paddr, dev.size);
+            self.gpm.insert(MemoryRegion::new_with_offset_mapper(
+                dev.paddr as GuestPhysAddr,
+                dev.paddr as HostPhysAddr,
+                align_up(dev.size),
+                MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
+            ))?;
         }
 
-        // gpio
-        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-            0x30200000 as GuestPhysAddr,
-            0x30200000,
-            0x50000,
-            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
-        ))?;
-
-        // iomuxc-gpr
-        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-            0x30340000 as GuestPhysAddr,
-            0x30340000,
-            0x10000,
-            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
-        ))?;
-
-        // ocotp-ctrl
-        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-            0x30350000 as GuestPhysAddr,
-            0x30350000,
-            0x10000,
-            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
-        ))?;
-
-        // anatop
-        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-            0x30360000 as GuestPhysAddr,
-            0x30360000,
-            0x10000,
-            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
-        ))?;
-
-        // snvs
-        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-            0x30370000 as GuestPhysAddr,
-            0x30370000,
-            0x10000,
-            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
-        ))?;
-
-        // clock-controller
-        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-            0x30380000 as GuestPhysAddr,
-            0x30380000,
-            0x10000,
-            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
-        ))?;
-
-        // imx8mp pinctrl & analog
-        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-            0x30330000 as GuestPhysAddr,
-            0x30330000,
-            0xa0000,
-            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
-        ))?;
-
-        // bus@30400000
-        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-            0x30400000 as GuestPhysAddr,
-            0x30400000,
-            0x400000,
-            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
-        ))?;
-
-        // bus@30a00000
-        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-            0x30a00000 as GuestPhysAddr,
-            0x30a00000,
-            0x200000,
-            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
-        ))?;
+        info!("VM stage 2 memory set: {:#x?}", self.gpm);
+        Ok(())
+    }
 
-        // bus@30c00000
+    pub fn pt_init_from_config(
+        &mut self,
+        config: &HvZoneConfig,
+        fdt: &fdt::Fdt,
+        guest_dtb: HostPhysAddr,
+    ) -> HvResult {
+        for region in config.memory_regions() {
+            let pa = if region.is_pool_backed() {
+                let mut frame = Frame::new_guest(region.size as usize / PAGE_SIZE, 0)?;
+                frame.clear();
+                let pa = frame.start_paddr();
+                self.ram_frames.push(frame);
+                pa
+            } else {
+                region.pa as HostPhysAddr
+            };
+            info!("map mem_region: {:#x?} to pa {:#x}", region, pa);
+            self.gpm.insert(MemoryRegion::new_with_offset_mapper(
+                region.ipa as GuestPhysAddr,
+                pa,
+                region.size as _,
+                region.flags(),
+            ))?;
+        }
+        // passthrough devices
+        for region in config.mmio_regions() {
+            info!("map passthrough mmio: {:#x?}", region);
+            self.gpm.insert(MemoryRegion::new_with_offset_mapper(
+                region.ipa as GuestPhysAddr,
+                region.pa as HostPhysAddr,
+                region.size as _,
+                MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
+            ))?;
+        }
+        // map guest dtb
+        info!("map guest dtb: {:#x?}", config.dtb_ipa);
         self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-            0x30c00000 as GuestPhysAddr,
-            0x30c00000,
-            0x400000,
-            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
+            config.dtb_ipa as GuestPhysAddr,
+            guest_dtb,
+            align_up(fdt.total_size()),
+            MemFlags::READ | MemFlags::WRITE,
         ))?;
 
-        // dma-apbh
-        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-            0x33000000 as GuestPhysAddr,
-            0x33000000,
-            0x2000,
-            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
-        ))?;
+        self.virtio_mmio_init(fdt);
 
-        // bus@32c00000
-        self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-            0x32c00000 as GuestPhysAddr,
-            0x32c00000,
-            0x40000,
-            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
-        ))?;
+        info!("VM stage 2 memory set: {:#x?}", self.gpm);
+        Ok(())
+    }
 
-        for node in fdt
-            .all_nodes()
-            .filter(|node| node.name().starts_with("serial@308"))
-        {
-            info!("ok found! node={:#x?}", node.name());
+    /// Probe virtio mmio devices and register their trap handlers.
+    fn virtio_mmio_init(&mut self, fdt: &fdt::Fdt) {
+        let mut mapped_virtio = Vec::new();
+        let dev = HVISOR_BRIDGE.lock();
+        let region = if dev.is_enable {
+            Some(dev.immut_region())
+        } else {
+            None
+        };
+        for node in fdt.find_all_nodes("/virtio_mmio") {
             if let Some(reg) = node.reg().and_then(|mut reg| reg.next()) {
                 let paddr = reg.starting_address as HostPhysAddr;
-                let size = align_up(reg.size.unwrap());
-                info!("map uart+++ addr: {:#x}, size: {:#x}", paddr, size);
-                self.gpm.insert(MemoryRegion::new_with_offset_mapper(
-                    paddr as GuestPhysAddr,
-                    paddr,
-                    size,
-                    MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
-                ))?;
+                let size = reg.size.unwrap();
+                if !mapped_virtio.contains(&paddr) {
+                    info!("map virtio mmio addr: {:#x}, size: {:#x}", paddr, size);
+                    if region.is_some() {
+                        let dev_region = region.clone().unwrap();
+                        while dev_region.mmio_avail == 0 {}
+                        fence(Ordering::Acquire);
+                        if dev_region.mmio_addrs.contains(&(paddr as u64)) {
+                            self.mmio_region_register(paddr, size, mmio_virtio_handler, paddr);
+                        } else {
+                            self.mmio_region_register(paddr, size, mmio_generic_handler, paddr);
+                        }
+                    } else {
+                        self.mmio_region_register(paddr, size, mmio_generic_handler, paddr);
+                    }
+                    mapped_virtio.push(paddr);
+                }
             }
         }
-
-        info!("VM stage 2 memory set: {:#x?}", self.gpm);
-        Ok(())
+        drop(dev);
     }
 
     pub fn mmio_init(&mut self, fdt: &fdt::Fdt) {
@@ -0,0 +1,447 @@
+//! Zone configuration passed from the root zone with `HvZoneStart`.
+//!
+//! The layout is shared with the root zone driver (`driver/hvisor.h`), so every
+//! structure here is `#[repr(C)]` and only fixed-size arrays are used.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use crate::consts::MAX_CPU_NUM;
+use crate::error::HvResult;
+use crate::memory::addr::{align_up, is_aligned};
+use crate::memory::{MemFlags, PAGE_SIZE};
+use crate::percpu::CpuSet;
+
+pub const HV_ZONE_CONFIG_MAGIC: u32 = 0x4e5a_5648; // "HVZN"
+pub const HV_ZONE_CONFIG_VERSION: u32 = 5;
+
+/// A memory region with this `pa` is backed by the hypervisor's guest memory
+/// pool instead of a fixed range of host memory.
+pub const HV_CONFIG_ALLOC_PA: u64 = u64::MAX;
+
+/// An `entry_point` taken from the header of the zone's image, see
+/// [`crate::loader`].
+pub const HV_CONFIG_IMAGE_ENTRY: u64 = u64::MAX;
+
+pub const CONFIG_MAX_MEMORY_REGIONS: usize = 16;
+pub const CONFIG_MAX_MMIO_REGIONS: usize = 16;
+pub const CONFIG_MAX_INTERRUPTS: usize = 32;
+pub const CONFIG_MAX_SHMEMS: usize = 4;
+pub const CONFIG_SHMEM_NAME_LEN: usize = 32;
+pub const CONFIG_MAX_IRQ_MAPS: usize = 32;
+
+/// The zone gets a software GIC distributor and redistributors, see
+/// [`crate::device::irqchip::gicv3::vgic_emul`].
+pub const HV_ZONE_FLAG_VGIC_EMUL: u32 = 1 << 0;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvConfigMemoryRegion {
+    pub ipa: u64,
+    pub pa: u64,
+    pub size: u64,
+    /// Bits of [`MemFlags`].
+    pub flags: u64,
+}
+
+/// A virtual SPI of a zone with [`HV_ZONE_FLAG_VGIC_EMUL`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvConfigIrqMap {
+    /// INTID seen by the guest.
+    pub virq: u32,
+    /// SPI raising it, or 0 for an irq only raised by the hypervisor.
+    pub pirq: u32,
+}
+
+/// Attachment of the zone to a shared memory device, see
+/// [`crate::device::ivshmem`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct HvConfigShmem {
+    /// Zones naming the same device share its memory, NUL padded.
+    pub name: [u8; CONFIG_SHMEM_NAME_LEN],
+    pub ipa: u64,
+    /// The first zone attached sets the size, the others must agree.
+    pub size: u64,
+    /// Page of the doorbell registers.
+    pub doorbell_ipa: u64,
+    /// SPI raised in this zone when a peer rings it.
+    pub irq: u32,
+    pub padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct HvZoneConfig {
+    pub magic: u32,
+    pub version: u32,
+    pub zone_id: u64,
+    /// Bitmap of the physical cpus owned by the zone.
+    pub cpus: u64,
+    /// Where the image is loaded and the boot cpu enters, or
+    /// [`HV_CONFIG_IMAGE_ENTRY`].
+    pub entry_point: u64,
+    /// Where the root zone put the image. Unless it already sits at the host
+    /// address backing `entry_point`, it's copied there.
+    pub image_phys_addr: u64,
+    pub image_size: u64,
+    /// Where the root zone put the board device tree. The zone gets a copy
+    /// trimmed to its resources.
+    pub dtb_phys_addr: u64,
+    pub dtb_ipa: u64,
+    pub num_memory_regions: u32,
+    pub num_mmio_regions: u32,
+    pub num_interrupts: u32,
+    pub num_shmems: u32,
+    pub memory_regions: [HvConfigMemoryRegion; CONFIG_MAX_MEMORY_REGIONS],
+    /// Passthrough device windows, always mapped as device memory.
+    pub mmio_regions: [HvConfigMemoryRegion; CONFIG_MAX_MMIO_REGIONS],
+    /// SPIs owned by the zone.
+    pub interrupts: [u32; CONFIG_MAX_INTERRUPTS],
+    pub shmems: [HvConfigShmem; CONFIG_MAX_SHMEMS],
+    /// Bitmap of the zone ids the zone may send messages to, see
+    /// [`crate::mailbox`].
+    pub msg_peers: u64,
+    /// SPI raised in the zone when a message arrives, 0 for none.
+    pub msg_irq: u32,
+    /// `HV_ZONE_FLAG_*`
+    pub flags: u32,
+    pub num_irq_maps: u32,
+    pub padding: u32,
+    /// Virtual SPIs other than the `interrupts`, which keep their INTID.
+    /// Only for a zone with [`HV_ZONE_FLAG_VGIC_EMUL`].
+    pub irq_maps: [HvConfigIrqMap; CONFIG_MAX_IRQ_MAPS],
+}
+
+/// One entry of the zone list copied to the root zone by `HvZoneList`.
+#[repr(C)]
+#[derive(Debug, Clone)]
+pub struct HvZoneInfo {
+    pub zone_id: u32,
+    /// A [`ZoneState`](crate::zone::ZoneState).
+    pub state: u32,
+    pub cpus: u64,
+    pub num_memory_regions: u32,
+    pub num_irqs: u32,
+    /// The first RAM regions of the zone, `pa` is the host address.
+    pub memory_regions: [HvConfigMemoryRegion; CONFIG_MAX_MEMORY_REGIONS],
+}
+
+impl HvConfigMemoryRegion {
+    pub fn flags(&self) -> MemFlags {
+        MemFlags::from_bits_truncate(self.flags)
+    }
+
+    pub fn is_pool_backed(&self) -> bool {
+        self.pa == HV_CONFIG_ALLOC_PA
+    }
+
+    fn ipa_range(&self) -> Range<u64> {
+        self.ipa..self.ipa + self.size
+    }
+
+    fn check(&self, kind: &str) -> HvResult {
+        if self.size == 0 {
+            return hv_result_err!(EINVAL, format!("{} region {:#x?} is empty", kind, self));
+        }
+        let pa_aligned = self.is_pool_backed() || is_aligned(self.pa as _);
+        if !is_aligned(self.ipa as _) || !pa_aligned || !is_aligned(self.size as _) {
+            return hv_result_err!(
+                EINVAL,
+                format!("{} region {:#x?} is not page aligned", kind, self)
+            );
+        }
+        let pa_overflow = !self.is_pool_backed() && self.pa.checked_add(self.size).is_none();
+        if self.ipa.checked_add(self.size).is_none() || pa_overflow {
+            return hv_result_err!(ERANGE, format!("{} region {:#x?} overflows", kind, self));
+        }
+        Ok(())
+    }
+}
+
+impl HvConfigShmem {
+    pub fn name(&self) -> &str {
+        let len = self
+            .name
+            .iter()
+            .position(|&c| c == 0)
+            .unwrap_or(self.name.len());
+        core::str::from_utf8(&self.name[..len]).unwrap_or("")
+    }
+
+    fn ipa_range(&self) -> Range<u64> {
+        self.ipa..self.ipa + self.size
+    }
+
+    fn doorbell_range(&self) -> Range<u64> {
+        self.doorbell_ipa..self.doorbell_ipa + PAGE_SIZE as u64
+    }
+
+    fn check(&self) -> HvResult {
+        if self.name().is_empty() {
+            return hv_result_err!(EINVAL, format!("shmem {:#x?} has no valid name", self));
+        }
+        if self.size == 0
+            || !is_aligned(self.ipa as _)
+            || !is_aligned(self.size as _)
+            || !is_aligned(self.doorbell_ipa as _)
+        {
+            return hv_result_err!(
+                EINVAL,
+                format!("shmem {} is empty or not page aligned", self.name())
+            );
+        }
+        if self.ipa.checked_add(self.size).is_none()
+            || self.doorbell_ipa.checked_add(PAGE_SIZE as u64).is_none()
+        {
+            return hv_result_err!(ERANGE, format!("shmem {} overflows", self.name()));
+        }
+        if !(32..1020).contains(&self.irq) {
+            return hv_result_err!(
+                EINVAL,
+                format!("shmem {} irq {} is not an SPI", self.name(), self.irq)
+            );
+        }
+        Ok(())
+    }
+}
+
+fn is_overlap(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+impl HvZoneConfig {
+    pub fn memory_regions(&self) -> &[HvConfigMemoryRegion] {
+        &self.memory_regions[..self.num_memory_regions as usize]
+    }
+
+    pub fn mmio_regions(&self) -> &[HvConfigMemoryRegion] {
+        &self.mmio_regions[..self.num_mmio_regions as usize]
+    }
+
+    pub fn interrupts(&self) -> &[u32] {
+        &self.interrupts[..self.num_interrupts as usize]
+    }
+
+    pub fn shmems(&self) -> &[HvConfigShmem] {
+        &self.shmems[..self.num_shmems as usize]
+    }
+
+    pub fn irq_maps(&self) -> &[HvConfigIrqMap] {
+        &self.irq_maps[..self.num_irq_maps as usize]
+    }
+
+    /// SPIs raised by the hypervisor in the zone: the shmem doorbells and the
+    /// message irq.
+    pub fn doorbell_irqs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.shmems()
+            .iter()
+            .map(|shmem| shmem.irq)
+            .chain(Some(self.msg_irq).filter(|&irq| irq != 0))
+    }
+
+    pub fn vgic_emul(&self) -> bool {
+        self.flags & HV_ZONE_FLAG_VGIC_EMUL != 0
+    }
+
+    pub fn cpu_set(&self) -> CpuSet {
+        CpuSet::new(MAX_CPU_NUM as usize, self.cpus)
+    }
+
+    /// Check the config before any resource of the zone is touched.
+    ///
+    /// `dtb_size` is the size of the zone's device tree, it is mapped at `dtb_ipa`.
+    pub fn validate(&self, dtb_size: usize) -> HvResult {
+        if self.magic != HV_ZONE_CONFIG_MAGIC {
+            return hv_result_err!(EINVAL, format!("bad zone config magic {:#x}", self.magic));
+        }
+        if self.version != HV_ZONE_CONFIG_VERSION {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "zone config version {} unsupported, expect {}",
+                    self.version, HV_ZONE_CONFIG_VERSION
+                )
+            );
+        }
+        if self.num_memory_regions as usize > CONFIG_MAX_MEMORY_REGIONS
+            || self.num_mmio_regions as usize > CONFIG_MAX_MMIO_REGIONS
+            || self.num_interrupts as usize > CONFIG_MAX_INTERRUPTS
+            || self.num_shmems as usize > CONFIG_MAX_SHMEMS
+            || self.num_irq_maps as usize > CONFIG_MAX_IRQ_MAPS
+        {
+            return hv_result_err!(E2BIG, "too many entries in zone config");
+        }
+        if self.cpus == 0 || self.cpus >> MAX_CPU_NUM != 0 {
+            return hv_result_err!(EINVAL, format!("invalid zone cpu bitmap {:#b}", self.cpus));
+        }
+        if !is_aligned(self.dtb_ipa as _) || !is_aligned(self.dtb_phys_addr as _) {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "zone dtb ipa {:#x} or pa {:#x} is not page aligned",
+                    self.dtb_ipa, self.dtb_phys_addr
+                )
+            );
+        }
+
+        for region in self.memory_regions() {
+            region.check("memory")?;
+            if region.flags().contains(MemFlags::IO) {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("memory region {:#x?} has IO flag, use mmio regions", region)
+                );
+            }
+        }
+        for region in self.mmio_regions() {
+            region.check("mmio")?;
+            if region.is_pool_backed() {
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "mmio region {:#x?} can't be backed by the memory pool",
+                        region
+                    )
+                );
+            }
+        }
+
+        for (i, shmem) in self.shmems().iter().enumerate() {
+            shmem.check()?;
+            if self.shmems()[..i]
+                .iter()
+                .any(|other| other.name() == shmem.name())
+            {
+                return hv_result_err!(EINVAL, format!("shmem {} is attached twice", shmem.name()));
+            }
+        }
+
+        let dtb_range = self.dtb_ipa..self.dtb_ipa + align_up(dtb_size) as u64;
+        let ranges: Vec<_> = self
+            .memory_regions()
+            .iter()
+            .chain(self.mmio_regions())
+            .map(|region| region.ipa_range())
+            .chain(
+                self.shmems()
+                    .iter()
+                    .flat_map(|shmem| [shmem.ipa_range(), shmem.doorbell_range()]),
+            )
+            .collect();
+        for (i, range) in ranges.iter().enumerate() {
+            if is_overlap(range, &dtb_range) {
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "region {:#x?} overlaps zone dtb at {:#x?}",
+                        range, dtb_range
+                    )
+                );
+            }
+            if let Some(other) = ranges[i + 1..]
+                .iter()
+                .find(|other| is_overlap(range, other))
+            {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("region {:#x?} overlaps {:#x?}", range, other)
+                );
+            }
+        }
+
+        for &irq in self.interrupts() {
+            if !(32..1020).contains(&irq) {
+                return hv_result_err!(EINVAL, format!("irq {} is not an SPI", irq));
+            }
+        }
+        if self.msg_irq != 0 && !(32..1020).contains(&self.msg_irq) {
+            return hv_result_err!(
+                EINVAL,
+                format!("message irq {} is not an SPI", self.msg_irq)
+            );
+        }
+        if let Some(irq) = self
+            .doorbell_irqs()
+            .find(|irq| self.interrupts().contains(irq))
+        {
+            return hv_result_err!(
+                EINVAL,
+                format!("doorbell irq {} is also a device irq of the zone", irq)
+            );
+        }
+        self.check_irq_maps()?;
+
+        Ok(())
+    }
+
+    fn check_irq_maps(&self) -> HvResult {
+        if self.flags & !HV_ZONE_FLAG_VGIC_EMUL != 0 {
+            return hv_result_err!(EINVAL, format!("unknown zone flags {:#x}", self.flags));
+        }
+        if !self.vgic_emul() && self.num_irq_maps != 0 {
+            return hv_result_err!(EINVAL, "irq maps need an emulated GIC");
+        }
+        let virqs: Vec<u32> = self
+            .interrupts()
+            .iter()
+            .copied()
+            .chain(self.doorbell_irqs())
+            .collect();
+        for (i, map) in self.irq_maps().iter().enumerate() {
+            if !(32..1020).contains(&map.virq) || map.pirq != 0 && !(32..1020).contains(&map.pirq) {
+                return hv_result_err!(EINVAL, format!("irq map {:?} is not between SPIs", map));
+            }
+            let maps = &self.irq_maps()[..i];
+            if virqs.contains(&map.virq) || maps.iter().any(|other| other.virq == map.virq) {
+                return hv_result_err!(EINVAL, format!("virtual irq {} is used twice", map.virq));
+            }
+            if map.pirq != 0
+                && (self.interrupts().contains(&map.pirq)
+                    || maps.iter().any(|other| other.pirq == map.pirq))
+            {
+                return hv_result_err!(EINVAL, format!("irq {} is mapped twice", map.pirq));
+            }
+        }
+        Ok(())
+    }
+
+    /// The memory region holding `size` bytes at `ipa`.
+    pub fn memory_region_of(&self, ipa: u64, size: u64) -> Option<&HvConfigMemoryRegion> {
+        let end = ipa.checked_add(size)?;
+        self.memory_regions().iter().find(|region| {
+            let range = region.ipa_range();
+            range.start <= ipa && end <= range.end
+        })
+    }
+
+    /// Check that an image of `size` bytes loaded at `entry` fits in an
+    /// executable memory region.
+    pub fn check_image_placement(&self, entry: u64, size: u64) -> HvResult {
+        let entry_region = match self.memory_regions().iter().find(|region| {
+            region.ipa_range().contains(&entry) && region.flags().contains(MemFlags::EXECUTE)
+        }) {
+            Some(region) => region,
+            None => {
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "entry point {:#x} is not in an executable memory region",
+                        entry
+                    )
+                )
+            }
+        };
+        if size > entry_region.ipa_range().end - entry {
+            return hv_result_err!(
+                E2BIG,
+                format!(
+                    "image of {:#x} bytes at {:#x} doesn't fit in {:#x?}",
+                    size, entry, entry_region
+                )
+            );
+        }
+        Ok(())
+    }
+}
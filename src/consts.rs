@@ -1,9 +1,12 @@
-use crate::memory::addr::VirtAddr;
+use crate::memory::addr::{align_up, VirtAddr};
 pub use crate::memory::PAGE_SIZE;
 
 /// Size of the hypervisor heap.
 pub const HV_HEAP_SIZE: usize = 1024 * 1024; // 1 MB
 pub const HV_MEM_POOL_SIZE: usize = 16 * 1024 * 1024; // 16 MB
+/// Size of the pool backing guest RAM, it follows the hypervisor memory pool and
+/// must not be handed to any zone directly.
+pub const GUEST_MEM_POOL_SIZE: usize = 128 * 1024 * 1024; // 128 MB
 
 /// Size of the per-CPU data (stack and other CPU-local data).
 pub const PER_CPU_SIZE: usize = 512 * 1024; // 128KB  //may get bigger when dev
@@ -17,6 +20,15 @@ pub const MAX_CPU_NUM: usize = 4;
 
 pub const DTB_IPA: usize = 0xfff00000;
 
+pub const ROOT_ZONE_ID: usize = 0;
+
+/// Zone ids are kept in a `u64` bitmap.
+pub const MAX_ZONE_NUM: usize = 64;
+
+pub fn hv_start() -> VirtAddr {
+    skernel as _
+}
+
 pub fn core_end() -> VirtAddr {
     __core_end as _
 }
@@ -29,6 +41,11 @@ pub fn hv_end() -> VirtAddr {
     mem_pool_start() + HV_MEM_POOL_SIZE
 }
 
+pub fn guest_mem_pool_start() -> VirtAddr {
+    align_up(hv_end())
+}
+
 extern "C" {
+    fn skernel();
     fn __core_end();
 }
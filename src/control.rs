@@ -1,66 +1,96 @@
 #![allow(unused)]
 use alloc::{sync::Arc, vec::Vec};
+use core::sync::atomic::Ordering;
 use spin::RwLock;
 
 use crate::{
+    arch::coredump::{dump_requested, handle_dump_request},
     error::HvResult,
-    percpu::PerCpu,
+    event::{send_event, IPI_EVENT_SUSPEND},
+    hypercall::SGI_IPI_ID,
+    percpu::{get_cpu_data, this_cpu_data, PerCpu},
     zone::{find_zone, root_zone, Zone},
 };
 
+/// Ask `cpu_id` to stop running its guest and wait until it has stopped.
 pub fn suspend_cpu(cpu_id: usize) {
-    todo!();
-    // trace!("suspending cpu {:#x?}", cpu_id);
-    // let cpu_data = get_cpu_data(cpu_id);
-    // let _lock = cpu_data.ctrl_lock.lock();
-    // cpu_data.need_suspend = true;
-    // let target_suspended = cpu_data.suspended;
-    // drop(_lock);
+    trace!("suspending cpu {:#x?}", cpu_id);
+    let cpu_data = get_cpu_data(cpu_id);
+    let _lock = cpu_data.ctrl_lock.lock();
+    cpu_data.need_suspend.store(true, Ordering::Release);
+    let target_suspended = cpu_data.suspended.load(Ordering::Acquire);
+    drop(_lock);
 
-    // if !target_suspended {
-    //     send_event(cpu_id, SGI_EVENT_ID);
-    // }
+    if !target_suspended {
+        send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SUSPEND);
+    }
+    while !cpu_data.suspended.load(Ordering::Acquire) {
+        core::hint::spin_loop();
+    }
 }
 
 pub fn resume_cpu(cpu_id: usize) {
-    todo!();
-    // trace!("resuming cpu {:#x?}", cpu_id);
-    // let cpu_data = get_cpu_data(cpu_id);
-    // let _lock = cpu_data.ctrl_lock.lock();
-    // cpu_data.need_suspend = false;
+    trace!("resuming cpu {:#x?}", cpu_id);
+    let cpu_data = get_cpu_data(cpu_id);
+    let _lock = cpu_data.ctrl_lock.lock();
+    cpu_data.need_suspend.store(false, Ordering::Release);
 }
 
+/// Called on a cpu which received `IPI_EVENT_SUSPEND`. The guest registers are
+/// saved on the stack and nothing else touches the EL1 state, so the guest
+/// continues where it stopped once the cpu is resumed.
+pub fn handle_suspend() {
+    let cpu_data = this_cpu_data();
+    cpu_data.suspended.store(true, Ordering::Release);
+    while cpu_data.need_suspend.load(Ordering::Acquire) {
+        if dump_requested() {
+            // the zone failed on another cpu, which waits for this vcpu
+            cpu_data.suspended.store(false, Ordering::Release);
+            handle_dump_request();
+        }
+        core::hint::spin_loop();
+    }
+    cpu_data.suspended.store(false, Ordering::Release);
+    if cpu_data.need_park.swap(false, Ordering::AcqRel) {
+        wait_for_poweron();
+    }
+    if cpu_data.need_reset.swap(false, Ordering::AcqRel) {
+        reset_current_cpu();
+    }
+}
+
+/// Release `cpu_id` from a pause and turn its vcpu off, as if it had called
+/// PSCI CPU_OFF. A later CPU_ON of its zone sends it back to the guest.
 pub fn park_cpu(cpu_id: usize) {
-    todo!();
-    // trace!("parking cpu {:#x?}", cpu_id);
-    // let cpu_data = get_cpu_data(cpu_id);
-    // let _lock = cpu_data.ctrl_lock.lock();
-    // cpu_data.park = true;
-    // cpu_data.need_suspend = false;
+    trace!("parking cpu {:#x?}", cpu_id);
+    let cpu_data = get_cpu_data(cpu_id);
+    let _lock = cpu_data.ctrl_lock.lock();
+    cpu_data.need_park.store(true, Ordering::Release);
+    cpu_data.need_suspend.store(false, Ordering::Release);
 }
 
-pub fn reset_cpu(cpu_id: u64) {
-    todo!();
-    // trace!("resetting cpu {:#x?}", cpu_id);
-    // let cpu_data = get_cpu_data(cpu_id);
-    // let _lock = cpu_data.ctrl_lock.lock();
-    // cpu_data.reset = true;
-    // cpu_data.need_suspend = false;
+/// Release `cpu_id` from a pause and make it restart its zone: the boot cpu
+/// enters the guest again, the others wait for PSCI CPU_ON.
+pub fn reset_cpu(cpu_id: usize) {
+    trace!("resetting cpu {:#x?}", cpu_id);
+    let cpu_data = get_cpu_data(cpu_id);
+    let _lock = cpu_data.ctrl_lock.lock();
+    cpu_data.need_reset.store(true, Ordering::Release);
+    cpu_data.need_suspend.store(false, Ordering::Release);
 }
 
-pub fn wait_for_poweron() -> ! {
-    todo!();
-    // let cpu_data = this_cpu_data();
-    // let mut _lock = Some(cpu_data.ctrl_lock.lock());
-    // cpu_data.wait_for_poweron = true;
-    // while !cpu_data.reset {
-    //     _lock = None;
-    //     while !cpu_data.reset {}
-    //     _lock = Some(cpu_data.ctrl_lock.lock());
-    // }
-    // cpu_data.reset = false;
-    // cpu_data.wait_for_poweron = false;
-    // drop(_lock);
+pub fn reset_current_cpu() -> ! {
+    let cpu_data = this_cpu_data();
+    if cpu_data.boot_cpu {
+        cpu_data.arch_cpu.run()
+    } else {
+        cpu_data.arch_cpu.idle()
+    }
+}
 
-    // cpu_data.start_zone();
+/// Turn the vcpu of the current cpu off: the cpu waits in `wfi` until PSCI
+/// CPU_ON sends it `IPI_EVENT_WAKEUP`, and then enters the guest at the
+/// entry given to CPU_ON.
+pub fn wait_for_poweron() -> ! {
+    this_cpu_data().arch_cpu.idle()
 }
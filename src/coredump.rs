@@ -0,0 +1,317 @@
+//! Core dumps of failed zones.
+//!
+//! The root zone lends a zeroed buffer of its memory to the hypervisor with
+//! `HvCoreDumpSetup`. When a zone hits a fatal condition, an ELF core file of
+//! it is written there: the notes of its vcpus, built by the arch code, and a
+//! `PT_LOAD` segment per RAM region at its guest physical address. The buffer
+//! starts with a [`CoreDumpHeader`] whose `size` is set last, then the root
+//! zone gets the irq it asked for. It clears `size` once it saved the dump, a
+//! zone failing before that isn't dumped.
+
+use alloc::vec::Vec;
+use core::mem::size_of;
+use core::ops::Range;
+use core::sync::atomic::{fence, AtomicBool, Ordering};
+use spin::{Mutex, RwLock};
+
+use crate::consts::{PAGE_SIZE, ROOT_ZONE_ID};
+use crate::error::HvResult;
+use crate::event::send_zone_irq;
+use crate::loader::elf::{
+    ELF64_EHDR_SIZE, ELF64_PHDR_SIZE, ELFCLASS64, ELFDATA2LSB, ELF_MAGIC, EM_HOST, PF_X, PT_LOAD,
+};
+use crate::memory::addr::align_up;
+use crate::memory::{GuestPhysAddr, MemFlags};
+use crate::zone::{root_zone, Zone};
+
+pub const CORE_DUMP_MAGIC: u32 = 0x44435648; // "HVCD"
+
+const ET_CORE: u16 = 4;
+const EV_CURRENT: u8 = 1;
+const PT_NOTE: u32 = 4;
+const PF_W: u32 = 1 << 1;
+const PF_R: u32 = 1 << 2;
+
+/// Start of the dump buffer, followed by the core file.
+#[repr(C)]
+struct CoreDumpHeader {
+    magic: u32,
+    zone_id: u32,
+    /// Size of the core file, 0 while the buffer is free.
+    size: u64,
+}
+
+const HEADER_SIZE: usize = size_of::<CoreDumpHeader>();
+
+#[derive(Clone, Copy)]
+struct DumpBuffer {
+    ipa: GuestPhysAddr,
+    size: usize,
+    irq: u32,
+}
+
+/// Buffer of the root zone the dumps are written to.
+static DUMP_BUFFER: Mutex<Option<DumpBuffer>> = Mutex::new(None);
+/// A dump is being written, the buffer can't be replaced.
+static WRITING: AtomicBool = AtomicBool::new(false);
+
+fn replace_buffer(buffer: Option<DumpBuffer>) -> HvResult {
+    let mut current = DUMP_BUFFER.lock();
+    if WRITING.load(Ordering::Acquire) {
+        return hv_result_err!(EBUSY, "a core dump is being written");
+    }
+    *current = buffer;
+    Ok(())
+}
+
+/// Write the core dumps to the `size` bytes of `root` at `ipa`, and raise
+/// `irq` in it after each one, 0 for none. A size of 0 stops the dumps.
+pub fn setup(root: &Zone, ipa: GuestPhysAddr, size: usize, irq: u32) -> HvResult {
+    if size == 0 {
+        replace_buffer(None)?;
+        info!("core dumps disabled");
+        return Ok(());
+    }
+    if size < PAGE_SIZE {
+        return hv_result_err!(
+            EINVAL,
+            format!("core dump buffer of {:#x} bytes, at least {:#x}", size, PAGE_SIZE)
+        );
+    }
+    if irq != 0 && !(32..1020).contains(&irq) {
+        return hv_result_err!(EINVAL, format!("core dump irq {} is not an SPI", irq));
+    }
+    // fails unless the whole buffer is RAM of the root zone, which zeroed it
+    let mut checked = 0;
+    while checked < size {
+        let (_, flags, page_size) = unsafe { root.gpm.page_table_query(ipa + checked)? };
+        if !flags.contains(MemFlags::WRITE) || flags.contains(MemFlags::IO) {
+            return hv_result_err!(
+                EFAULT,
+                format!("core dump buffer at {:#x} is not RAM of the root zone", ipa + checked)
+            );
+        }
+        checked += page_size as usize - page_size.page_offset(ipa + checked);
+    }
+    replace_buffer(Some(DumpBuffer { ipa, size, irq }))?;
+    info!("core dumps to {:#x}, {:#x} bytes, irq {}", ipa, size, irq);
+    Ok(())
+}
+
+/// Append an ELF note of `note_type` in the `name` namespace to `notes`.
+pub fn push_note(notes: &mut Vec<u8>, name: &str, note_type: u32, desc: &[u8]) {
+    notes.extend_from_slice(&(name.len() as u32 + 1).to_le_bytes());
+    notes.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    notes.extend_from_slice(&note_type.to_le_bytes());
+    notes.extend_from_slice(name.as_bytes());
+    notes.push(0);
+    notes.resize((notes.len() + 3) & !3, 0);
+    notes.extend_from_slice(desc);
+    notes.resize((notes.len() + 3) & !3, 0);
+}
+
+fn push_phdr(
+    headers: &mut Vec<u8>,
+    p_type: u32,
+    flags: u32,
+    offset: usize,
+    ipa: usize,
+    file_size: usize,
+    mem_size: usize,
+) {
+    headers.extend_from_slice(&p_type.to_le_bytes());
+    headers.extend_from_slice(&flags.to_le_bytes());
+    for field in [offset, ipa, ipa, file_size, mem_size] {
+        headers.extend_from_slice(&(field as u64).to_le_bytes());
+    }
+    let align = if p_type == PT_LOAD { PAGE_SIZE } else { 4 };
+    headers.extend_from_slice(&(align as u64).to_le_bytes());
+}
+
+fn segment_flags(flags: MemFlags) -> u32 {
+    [(MemFlags::READ, PF_R), (MemFlags::WRITE, PF_W), (MemFlags::EXECUTE, PF_X)]
+        .iter()
+        .filter(|(flag, _)| flags.contains(*flag))
+        .fold(0, |acc, (_, pf)| acc | pf)
+}
+
+/// Host memory behind the `len` bytes of RAM of `zone` at `ipa`, which must be
+/// mapped with `flags`.
+fn host_ranges(
+    zone: &Zone,
+    ipa: GuestPhysAddr,
+    len: usize,
+    flags: MemFlags,
+) -> HvResult<Vec<Range<usize>>> {
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    let mut done = 0;
+    while done < len {
+        let (pa, page_flags, page_size) = unsafe { zone.gpm.page_table_query(ipa + done)? };
+        if !page_flags.contains(flags) || page_flags.contains(MemFlags::IO) {
+            return hv_result_err!(
+                EFAULT,
+                format!("zone {} ipa {:#x} is not RAM", zone.id, ipa + done)
+            );
+        }
+        let chunk = (page_size as usize - page_size.page_offset(ipa + done)).min(len - done);
+        let pa = pa as usize;
+        match ranges.last_mut() {
+            Some(last) if last.end == pa => last.end += chunk,
+            _ => ranges.push(pa..pa + chunk),
+        }
+        done += chunk;
+    }
+    Ok(ranges)
+}
+
+/// Copy `data` to `offset` in the host memory `ranges`, seen as one buffer.
+fn copy_to_ranges(ranges: &[Range<usize>], mut offset: usize, mut data: &[u8]) {
+    for range in ranges {
+        if data.is_empty() {
+            break;
+        }
+        if offset >= range.len() {
+            offset -= range.len();
+            continue;
+        }
+        let len = (range.len() - offset).min(data.len());
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), (range.start + offset) as *mut u8, len);
+        }
+        data = &data[len..];
+        offset = 0;
+    }
+}
+
+/// Write the core file of `zone`, with the notes of its vcpus, to the dump
+/// buffer and notify the root zone. RAM regions which don't fit in the buffer
+/// are cut short.
+///
+/// The memory of the zone and of the buffer is looked up first, and copied
+/// without holding any lock: the vcpus of the zone are stopped, and the caller
+/// keeps a reference to it, so its frames stay allocated.
+pub fn write(zone: &RwLock<Zone>, notes: &[u8]) -> HvResult {
+    let buffer = {
+        let guard = DUMP_BUFFER.lock();
+        let buffer = match *guard {
+            Some(buffer) => buffer,
+            None => return hv_result_err!(ENODEV, "the root zone set no core dump buffer"),
+        };
+        // cleared once the dump is written, the root zone can't replace the
+        // buffer meanwhile
+        if WRITING.swap(true, Ordering::AcqRel) {
+            return hv_result_err!(EBUSY, "another core dump is being written");
+        }
+        buffer
+    };
+    let written = write_to(buffer, zone, notes);
+    WRITING.store(false, Ordering::Release);
+    let (zone_id, size) = written?;
+    info!("zone {}: core dump of {:#x} bytes written", zone_id, size);
+
+    if buffer.irq != 0 {
+        send_zone_irq(ROOT_ZONE_ID, buffer.irq as _);
+    }
+    Ok(())
+}
+
+/// Write the core file of `zone` to `buffer`, return the zone id and the size
+/// of the file.
+fn write_to(buffer: DumpBuffer, zone: &RwLock<Zone>, notes: &[u8]) -> HvResult<(usize, usize)> {
+    let target = {
+        let root = root_zone();
+        let root = root.read();
+        let mut header = [0; HEADER_SIZE];
+        root.copy_from_guest(buffer.ipa, &mut header)?;
+        if header[8..].iter().any(|&b| b != 0) {
+            return hv_result_err!(EBUSY, "the root zone didn't save the previous core dump");
+        }
+        host_ranges(&root, buffer.ipa, buffer.size, MemFlags::WRITE)?
+    };
+    let (zone_id, ram) = {
+        let zone = zone.read();
+        let ram = zone
+            .gpm
+            .iter()
+            .filter(|region| !region.flags.contains(MemFlags::IO))
+            .map(|region| (region.start, region.size, region.flags))
+            .collect::<Vec<_>>();
+        (zone.id, ram)
+    };
+    let phnum = 1 + ram.len();
+    let notes_offset = ELF64_EHDR_SIZE + phnum * ELF64_PHDR_SIZE;
+    let capacity = buffer.size - HEADER_SIZE;
+    let mut offset = align_up(notes_offset + notes.len());
+    if offset > capacity {
+        return hv_result_err!(
+            E2BIG,
+            format!("core dump headers of {:#x} bytes, buffer of {:#x}", offset, capacity)
+        );
+    }
+
+    let mut headers = Vec::with_capacity(notes_offset);
+    headers.extend_from_slice(ELF_MAGIC);
+    headers.extend_from_slice(&[ELFCLASS64, ELFDATA2LSB, EV_CURRENT]);
+    headers.resize(16, 0);
+    headers.extend_from_slice(&ET_CORE.to_le_bytes());
+    headers.extend_from_slice(&EM_HOST.to_le_bytes());
+    headers.extend_from_slice(&(EV_CURRENT as u32).to_le_bytes());
+    headers.extend_from_slice(&0u64.to_le_bytes()); // e_entry
+    headers.extend_from_slice(&(ELF64_EHDR_SIZE as u64).to_le_bytes());
+    headers.extend_from_slice(&0u64.to_le_bytes()); // e_shoff
+    headers.extend_from_slice(&0u32.to_le_bytes()); // e_flags
+    for field in [ELF64_EHDR_SIZE, ELF64_PHDR_SIZE, phnum, 0, 0, 0] {
+        headers.extend_from_slice(&(field as u16).to_le_bytes());
+    }
+    push_phdr(&mut headers, PT_NOTE, 0, notes_offset, 0, notes.len(), 0);
+    let mut segments = Vec::with_capacity(ram.len());
+    for &(ipa, size, flags) in &ram {
+        let file_size = size.min(capacity - offset);
+        if file_size < size {
+            warn!(
+                "zone {} core dump: {:#x} bytes of {:#x?} don't fit",
+                zone_id,
+                size - file_size,
+                ipa..ipa + size
+            );
+        }
+        push_phdr(&mut headers, PT_LOAD, segment_flags(flags), offset, ipa, file_size, size);
+        segments.push((ipa, offset, file_size));
+        offset += file_size;
+    }
+    let sources = {
+        let zone = zone.read();
+        segments
+            .iter()
+            .map(|&(ipa, file_offset, len)| {
+                host_ranges(&zone, ipa, len, MemFlags::READ).map(|ranges| (file_offset, ranges))
+            })
+            .collect::<HvResult<Vec<_>>>()?
+    };
+
+    let elf = HEADER_SIZE;
+    copy_to_ranges(&target, elf, &headers);
+    copy_to_ranges(&target, elf + notes_offset, notes);
+    for (mut file_offset, ranges) in sources {
+        for range in ranges {
+            let data =
+                unsafe { core::slice::from_raw_parts(range.start as *const u8, range.len()) };
+            copy_to_ranges(&target, elf + file_offset, data);
+            file_offset += range.len();
+        }
+    }
+
+    // the root zone may take the dump once the size is set
+    fence(Ordering::SeqCst);
+    let header = CoreDumpHeader {
+        magic: CORE_DUMP_MAGIC,
+        zone_id: zone_id as u32,
+        size: offset as u64,
+    };
+    let mut bytes = [0; HEADER_SIZE];
+    bytes[..4].copy_from_slice(&header.magic.to_le_bytes());
+    bytes[4..8].copy_from_slice(&header.zone_id.to_le_bytes());
+    bytes[8..].copy_from_slice(&header.size.to_le_bytes());
+    copy_to_ranges(&target, 0, &bytes);
+    Ok((zone_id, offset))
+}
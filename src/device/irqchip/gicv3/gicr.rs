@@ -4,7 +4,7 @@
 
 //! GICC Driver - GIC CPU interface.
 
-use crate::{arch::cpu::this_cpu_data, hypercall::SGI_EVENT_ID};
+use crate::{arch::cpu::this_cpu_id, hypercall::SGI_IPI_ID};
 
 use super::{
     gicd::{
@@ -17,6 +17,8 @@ use super::{
 pub const GICR_CTLR: usize = 0x0000;
 pub const GICR_IIDR: usize = 0x0004;
 pub const GICR_TYPER: usize = 0x0008;
+/// Upper half of GICR_TYPER, accessed alone by 32-bit reads.
+pub const GICR_TYPER_AFFINITY: usize = 0x000c;
 pub const GICR_STATUSR: usize = 0x0010;
 pub const GICR_WAKER: usize = 0x0014;
 pub const GICR_SYNCR: usize = 0x00c0;
@@ -32,6 +34,7 @@ pub const GICR_ISACTIVER: usize = GICD_ISACTIVER;
 pub const GICR_ICACTIVER: usize = GICD_ICACTIVER;
 pub const GICR_IPRIORITYR: usize = GICD_IPRIORITYR;
 pub const GICR_ICFGR: usize = GICD_ICFGR;
+pub const GICR_IGRPMODR: usize = 0x0d00;
 pub const GICR_TYPER_LAST: usize = 1 << 4;
 
 pub fn enable_ipi() {
@@ -59,7 +62,7 @@ pub fn enable_ipi() {
     //     let gicr_waker = (base + GICR_WAKER) as *mut u32;
     //     gicr_waker.write_volatile(gicr_waker.read_volatile() & !0x02);
     // }
-    let base = host_gicr_base(this_cpu_data()) + GICR_SGI_BASE;
+    let base = host_gicr_base(this_cpu_id()) + GICR_SGI_BASE;
 
     unsafe {
         let gicr_waker = (base + GICR_WAKER) as *mut u32;
@@ -84,3 +87,28 @@ pub fn enable_ipi() {
         }
     }
 }
+
+/// Enable the private peripheral interrupt `irq_id` of the current cpu for
+/// the hypervisor.
+pub fn enable_ppi(irq_id: usize) {
+    let base = host_gicr_base(this_cpu_id()) + GICR_SGI_BASE;
+
+    unsafe {
+        let gicr_igroupr0 = (base + GICR_IGROUPR) as *mut u32;
+        gicr_igroupr0.write_volatile(gicr_igroupr0.read_volatile() | (1 << irq_id));
+
+        let gicr_isenabler0 = (base + GICR_ISENABLER) as *mut u32;
+        gicr_isenabler0.write_volatile(1 << irq_id);
+
+        let gicr_ipriorityr0 = (base + GICR_IPRIORITYR) as *mut u32;
+        {
+            let reg = irq_id / 4;
+            let offset = irq_id % 4 * 8;
+            let mask = ((1 << 8) - 1) << offset;
+            let p = gicr_ipriorityr0.add(reg as _);
+            let prio = p.read_volatile();
+
+            p.write_volatile((prio & !mask) | (0x01 << offset));
+        }
+    }
+}
@@ -79,6 +79,7 @@
 pub mod gicd;
 pub mod gicr;
 pub mod vgic;
+pub mod vgic_emul;
 
 use core::arch::asm;
 use core::ptr::write_volatile;
@@ -89,12 +90,15 @@ use fdt::Fdt;
 use spin::Once;
 
 use self::gicd::{enable_gic_are_ns, GICD_ICACTIVER, GICD_ICENABLER};
-use self::gicr::enable_ipi;
+use self::gicr::{enable_ipi, enable_ppi};
+use self::vgic_emul::MAINTENANCE_IRQ;
 use crate::arch::aarch64::sysreg::{read_sysreg, smc_arg1, write_sysreg};
+use crate::arch::aarch64::vcpu::SCHED_TIMER_IRQ;
 use crate::consts::MAX_CPU_NUM;
 
 use crate::event::check_events;
 use crate::hypercall::SGI_IPI_ID;
+use crate::scheduler::{route_irq, timer_tick};
 use crate::zone::Zone;
 
 //TODO: add Distributor init
@@ -127,7 +131,7 @@ fn gicv3_clear_pending_irqs() {
     for i in 0..lr_num {
         write_lr(i, 0) //clear lr
     }
-    let num_priority_bits = (vtr >> 29) + 1;
+    let num_priority_bits = ((vtr >> 26) & 0x7) + 1;
     /* Clear active priority bits */
     if num_priority_bits >= 5 {
         write_sysreg!(ICH_AP1R0_EL2, 0); //Interrupt Controller Hyp Active Priorities Group 1 Register 0 No interrupt active
@@ -174,13 +178,21 @@ pub fn gicv3_handle_irq_el1() {
                 trace!("SGI_IPI_ID");
                 ipi_handled = check_events();
             }
-            if !ipi_handled {
+            if !ipi_handled && !vgic_emul::handle_hw_irq(irq_id) {
                 trace!("sgi get {}, inject", irq_id);
                 inject_irq(irq_id, false);
             }
         } else if irq_id < 16 {
             warn!("skip sgi {}", irq_id);
             deactivate_irq(irq_id);
+        } else if irq_id == SCHED_TIMER_IRQ {
+            write_sysreg!(icc_eoir1_el1, irq_id as u64);
+            write_sysreg!(icc_dir_el1, irq_id as u64);
+            timer_tick();
+        } else if irq_id == MAINTENANCE_IRQ {
+            write_sysreg!(icc_eoir1_el1, irq_id as u64);
+            write_sysreg!(icc_dir_el1, irq_id as u64);
+            vgic_emul::handle_maintenance_irq();
         } else {
             if irq_id == 27 {
                 // virtual timer interrupt
@@ -195,7 +207,10 @@ pub fn gicv3_handle_irq_el1() {
                 debug!("*** get spi_irq id = {}", irq_id);
             }
             deactivate_irq(irq_id);
-            inject_irq(irq_id, true);
+            // the owner may be a vcpu switched out of this cpu
+            if !vgic_emul::handle_hw_irq(irq_id) && !route_irq(irq_id, true) {
+                inject_irq(irq_id, true);
+            }
         }
     }
     trace!("handle done")
@@ -219,7 +234,12 @@ fn deactivate_irq(irq_id: usize) {
     //write_sysreg!(icc_dir_el1, irq_id as usize);
 }
 
-fn read_lr(id: usize) -> u64 {
+/// Number of list registers implemented.
+pub(crate) fn lr_num() -> usize {
+    (read_sysreg!(ich_vtr_el2) as usize & 0xf) + 1
+}
+
+pub(crate) fn read_lr(id: usize) -> u64 {
     let id = id as u64;
     match id {
         //TODO get lr size from gic reg
@@ -246,7 +266,7 @@ fn read_lr(id: usize) -> u64 {
     }
 }
 
-fn write_lr(id: usize, val: u64) {
+pub(crate) fn write_lr(id: usize, val: u64) {
     let id = id as u64;
     match id {
         0 => write_sysreg!(ich_lr0_el2, val),
@@ -272,6 +292,51 @@ fn write_lr(id: usize, val: u64) {
     }
 }
 
+/// Number of active priority registers per group, given by the preemption
+/// bits (PREbits) of ICH_VTR_EL2.
+pub(crate) fn apr_num() -> usize {
+    let num_priority_bits = ((read_sysreg!(ich_vtr_el2) >> 26) & 0x7) + 1;
+    match num_priority_bits {
+        5 => 1,
+        6 => 2,
+        _ => 4,
+    }
+}
+
+pub(crate) fn read_apr(group: usize, id: usize) -> u64 {
+    match (group, id) {
+        (0, 0) => read_sysreg!(ICH_AP0R0_EL2),
+        (0, 1) => read_sysreg!(ICH_AP0R1_EL2),
+        (0, 2) => read_sysreg!(ICH_AP0R2_EL2),
+        (0, 3) => read_sysreg!(ICH_AP0R3_EL2),
+        (1, 0) => read_sysreg!(ICH_AP1R0_EL2),
+        (1, 1) => read_sysreg!(ICH_AP1R1_EL2),
+        (1, 2) => read_sysreg!(ICH_AP1R2_EL2),
+        (1, 3) => read_sysreg!(ICH_AP1R3_EL2),
+        _ => unreachable!("no ICH_AP{}R{}_EL2", group, id),
+    }
+}
+
+pub(crate) fn write_apr(group: usize, id: usize, val: u64) {
+    match (group, id) {
+        (0, 0) => write_sysreg!(ICH_AP0R0_EL2, val),
+        (0, 1) => write_sysreg!(ICH_AP0R1_EL2, val),
+        (0, 2) => write_sysreg!(ICH_AP0R2_EL2, val),
+        (0, 3) => write_sysreg!(ICH_AP0R3_EL2, val),
+        (1, 0) => write_sysreg!(ICH_AP1R0_EL2, val),
+        (1, 1) => write_sysreg!(ICH_AP1R1_EL2, val),
+        (1, 2) => write_sysreg!(ICH_AP1R2_EL2, val),
+        (1, 3) => write_sysreg!(ICH_AP1R3_EL2, val),
+        _ => unreachable!("no ICH_AP{}R{}_EL2", group, id),
+    }
+}
+
+/// Deactivate a hardware irq acknowledged by the hypervisor which will never
+/// reach a guest.
+pub fn drop_hw_irq(irq_id: usize) {
+    write_sysreg!(icc_dir_el1, irq_id as u64);
+}
+
 pub fn inject_irq(irq_id: usize, is_hardware: bool) {
     // mask
     const LR_VIRTIRQ_MASK: usize = (1 << 32) - 1;
@@ -386,6 +451,8 @@ pub fn primary_init_late() {
 pub fn percpu_init() {
     gicc_init();
     enable_ipi();
+    enable_ppi(SCHED_TIMER_IRQ);
+    enable_ppi(MAINTENANCE_IRQ);
 }
 
 impl Zone {
@@ -400,5 +467,8 @@ impl Zone {
                 write_volatile((gicd_base + GICD_ICACTIVER + idx * 4) as *mut u32, mask);
             }
         }
+        if let Some(vgic) = &self.vgic {
+            vgic.reset();
+        }
     }
 }
@@ -1,12 +1,13 @@
-use alloc::sync::Arc;
-
+use super::vgic_emul::{vgic_emul_dist_handler, vgic_emul_redist_handler};
 use super::{gicd::GICD_LOCK, is_spi, Gic};
 use crate::{
+    arch::vcpu::SCHED_TIMER_IRQ,
     consts::MAX_CPU_NUM,
     device::irqchip::gicv3::{gicd::*, gicr::*, host_gicd_base, host_gicr_base, PER_GICR_SIZE},
     error::HvResult,
-    memory::{mmio_perform_access, MMIOAccess},
-    percpu::{get_cpu_data, this_zone},
+    memory::{mmio_perform_access, MMIOAccess, MMIOHandler},
+    percpu::this_zone,
+    platform::assigned_board_devices,
     zone::Zone,
 };
 
@@ -17,11 +18,15 @@ pub fn reg_range(base: usize, n: usize, size: usize) -> core::ops::Range<usize>
 impl Zone {
     pub fn vgicv3_mmio_init(&mut self, fdt: &fdt::Fdt) {
         let gic = Gic::new(fdt);
-        self.mmio_region_register(gic.gicd_base, gic.gicd_size, vgicv3_dist_handler, 0);
+        let (dist_handler, redist_handler): (MMIOHandler, MMIOHandler) = match self.vgic {
+            Some(_) => (vgic_emul_dist_handler, vgic_emul_redist_handler),
+            None => (vgicv3_dist_handler, vgicv3_redist_handler),
+        };
+        self.mmio_region_register(gic.gicd_base, gic.gicd_size, dist_handler, 0);
         for cpu in 0..MAX_CPU_NUM {
             let gicr_base = host_gicr_base(cpu);
             debug!("registering gicr {} at {:#x?}", cpu, gicr_base);
-            self.mmio_region_register(gicr_base, PER_GICR_SIZE, vgicv3_redist_handler, cpu);
+            self.mmio_region_register(gicr_base, PER_GICR_SIZE, redist_handler, cpu);
         }
     }
 
@@ -34,42 +39,16 @@ impl Zone {
                 for int_n in int_iter {
                     // When interrupt cell-size = 3, the first cell is a flag indicating if the interrupt is an SPI
                     // So we need to bitwise-and with u32::MAX to get the real interrupt number
-                    let real_int_n = (int_n & u32::MAX as usize) + u32;
+                    let real_int_n = (int_n & u32::MAX as usize) + 32;
                     self.insert_irq_to_bitmap(real_int_n as u32);
                 }
             }
         }
-        self.insert_irq_to_bitmap(96); // gpio 0
-        self.insert_irq_to_bitmap(97); // gpio 0
-        self.insert_irq_to_bitmap(98); // gpio 1
-        self.insert_irq_to_bitmap(99); // gpio 1
-        self.insert_irq_to_bitmap(100); // gpio 2
-        self.insert_irq_to_bitmap(101); // gpio 2
-        self.insert_irq_to_bitmap(102); // gpio 3
-        self.insert_irq_to_bitmap(103); // gpio 3
-        self.insert_irq_to_bitmap(104); // gpio 4
-        self.insert_irq_to_bitmap(105); // gpio 4
-
-        self.insert_irq_to_bitmap(150); // ethernet1
-        self.insert_irq_to_bitmap(151); // ethernet1
-        self.insert_irq_to_bitmap(152); // ethernet1
-
-        self.insert_irq_to_bitmap(166); // ethernet2
-        self.insert_irq_to_bitmap(167); // ethernet2
-
-        self.insert_irq_to_bitmap(52); // caam_secvio
-        self.insert_irq_to_bitmap(51); // snvs-rtc-l
-        self.insert_irq_to_bitmap(36); // snvs-powerkey
-        self.insert_irq_to_bitmap(54); // mmc0
-        self.insert_irq_to_bitmap(55); // mmc1
-        self.insert_irq_to_bitmap(56); // mmc2
-
-        self.insert_irq_to_bitmap(59); // serial@30890000
-        self.insert_irq_to_bitmap(43);
-
-        self.insert_irq_to_bitmap(69); // i2c
-        self.insert_irq_to_bitmap(39); // pmu
-        self.insert_irq_to_bitmap(34); // pmu
+        for dev in assigned_board_devices(fdt) {
+            for &irq in dev.irqs {
+                self.insert_irq_to_bitmap(irq);
+            }
+        }
 
         for (index, &word) in self.irq_bitmap.iter().enumerate() {
             for bit_position in 0..32 {
@@ -84,6 +63,13 @@ impl Zone {
         }
     }
 
+    pub fn irq_bitmap_init_from_config(&mut self, irqs: &[u32]) {
+        for &irq in irqs {
+            self.insert_irq_to_bitmap(irq);
+        }
+        info!("Zone {} owns irqs: {:?}", self.id, irqs);
+    }
+
     fn insert_irq_to_bitmap(&mut self, irq: u32) {
         assert!(irq < 1020); // 1024 is the maximum number of interrupts supported by GICv3 (GICD_TYPER.ITLinesNumber)
         let irq_index = irq / 32;
@@ -160,6 +146,12 @@ pub fn vgicv3_redist_handler(mmio: &mut MMIOAccess, cpu: usize) -> HvResult {
             if cpu == MAX_CPU_NUM - 1 {
                 mmio.value |= GICR_TYPER_LAST;
             }
+            if mmio.size == 8 {
+                mmio.value = mmio.value & 0xffff_ffff | redist_affinity(cpu) << 32;
+            }
+        }
+        GICR_TYPER_AFFINITY => {
+            mmio.value = redist_affinity(cpu);
         }
         GICR_IIDR | 0xffd0..=0xfffc => {
             // Read-only registers that might be used by a zone to find the redistributor corresponding to a CPU. Keep them accessible.
@@ -169,8 +161,14 @@ pub fn vgicv3_redist_handler(mmio: &mut MMIOAccess, cpu: usize) -> HvResult {
             mmio.value = 0;
         }
         _ => {
-            if Arc::ptr_eq(&this_zone(), get_cpu_data(cpu).zone.as_ref().unwrap()) {
+            if this_zone().read().cpu_set.contains_cpu(cpu) {
                 // ignore access to foreign redistributors
+                let mask = sched_timer_mask(mmio);
+                if mmio.is_write && mask != 0 {
+                    // the scheduler tick of the cpu stays enabled, in group 1
+                    // and at its priority
+                    keep_bits(gicr_base, mmio, mask);
+                }
                 mmio_perform_access(gicr_base, mmio);
             } else {
                 trace!("*** gicv3_gicr_mmio_handler: ignore access to foreign redistributors ***");
@@ -180,6 +178,50 @@ pub fn vgicv3_redist_handler(mmio: &mut MMIOAccess, cpu: usize) -> HvResult {
     HvResult::Ok(())
 }
 
+/// Bits of the access to an SGI frame register holding the enable, group or
+/// priority of the scheduler tick.
+fn sched_timer_mask(mmio: &MMIOAccess) -> usize {
+    let reg = match mmio.address.checked_sub(GICR_SGI_BASE) {
+        Some(reg) => reg,
+        None => return 0,
+    };
+    match reg {
+        GICR_ICENABLER | GICR_IGROUPR | GICR_IGRPMODR => 1 << SCHED_TIMER_IRQ,
+        _ if (GICR_IPRIORITYR..GICR_IPRIORITYR + 32).contains(&reg) => {
+            match SCHED_TIMER_IRQ.checked_sub(reg - GICR_IPRIORITYR) {
+                Some(byte) if byte < mmio.size => 0xff << (byte * 8),
+                _ => 0,
+            }
+        }
+        _ => 0,
+    }
+}
+
+/// Keep the `mask` bits of the register written by `mmio` as they are.
+fn keep_bits(gicr_base: usize, mmio: &mut MMIOAccess, mask: usize) {
+    if mmio.address == GICR_SGI_BASE + GICR_ICENABLER {
+        // write-1-to-clear
+        mmio.value &= !mask;
+        return;
+    }
+    let mut current = MMIOAccess {
+        is_write: false,
+        value: 0,
+        ..*mmio
+    };
+    mmio_perform_access(gicr_base, &mut current);
+    mmio.value = mmio.value & !mask | current.value & mask;
+}
+
+/// Affinity reported by the redistributor of `cpu`. Guests find their own
+/// redistributor by virtual MPIDR, the ones of foreign cpus match no vcpu.
+fn redist_affinity(cpu: usize) -> usize {
+    match this_zone().read().cpu_set.vcpu_id(cpu) {
+        Some(vcpu_id) => vcpu_id,
+        None => 0xffff_ffff,
+    }
+}
+
 // The return value should be the register value to be read.
 fn vgicv3_handle_irq_ops(mmio: &mut MMIOAccess, irq: u32) -> HvResult {
     let zone = this_zone();
@@ -212,7 +254,11 @@ fn vgicv3_dist_misc_access(mmio: &mut MMIOAccess, gicd_base: usize) -> HvResult
             mmio_perform_access(gicd_base, mmio);
         }
     } else {
-        todo!()
+        // RAZ/WI
+        warn!("gicd-mmio: unhandled access to reg {:#x?}", reg);
+        if !mmio.is_write {
+            mmio.value = 0;
+        }
     }
 
     Ok(())
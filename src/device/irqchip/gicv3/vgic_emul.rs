@@ -0,0 +1,720 @@
+//! Software GIC distributor and redistributors, for the zones created with
+//! `HV_ZONE_FLAG_VGIC_EMUL`.
+//!
+//! The guest never reaches the physical GICD and GICRs: its accesses act on a
+//! model of the enable, pending, active, priority, config and routing state of
+//! its irqs, and a pending irq goes to a list register of the cpu running its
+//! target vcpu. A virtual SPI is backed by a physical SPI, whose INTID may
+//! differ, or is only raised by the hypervisor. The physical SPI follows the
+//! enable, config and routing of the virtual one. SGIs are virtual, PPIs are
+//! the ones of the physical cpu.
+//!
+//! An irq leaves the model once it gets a list register, its active state is
+//! then the one of the list register. When they're all taken, the underflow
+//! maintenance irq tells when to go on.
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ptr::{read_volatile, write_volatile};
+use spin::Mutex;
+
+use super::gicd::*;
+use super::gicr::*;
+use super::{drop_hw_irq, host_gicd_base, host_gicr_base, lr_num, read_lr, write_lr};
+use crate::arch::cpu::this_cpu_id;
+use crate::arch::sysreg::{read_sysreg, write_sysreg};
+use crate::arch::vcpu::SCHED_TIMER_IRQ;
+use crate::config::HvZoneConfig;
+use crate::consts::MAX_CPU_NUM;
+use crate::error::HvResult;
+use crate::event::{send_event, IPI_EVENT_VGIC_FLUSH};
+use crate::hypercall::SGI_IPI_ID;
+use crate::memory::{mmio_perform_access, MMIOAccess};
+use crate::percpu::{this_cpu_data, CpuSet};
+use crate::zone::find_vgic_of_irq;
+
+/// INTIDs of the model: SGIs, PPIs and SPIs up to 1019.
+const NR_IRQS: usize = 1020;
+const NR_PRIVATE_IRQS: usize = 32;
+/// Raised by the GIC on the underflow of the list registers.
+pub const MAINTENANCE_IRQ: usize = 25;
+
+/// IDbits of 10 and ITLinesNumber for `NR_IRQS`.
+const VGICD_TYPER: usize = 9 << 19 | ((NR_IRQS + 31) / 32 - 1);
+/// Implemented by Arm.
+const VGIC_IIDR: usize = 0x43b;
+/// GICv3
+const VGIC_PIDR2: usize = 0x3 << 4;
+const VGICD_CTLR_ENABLE_G1: usize = 1 << 0;
+const VGICD_CTLR_ENABLE_G1A: usize = 1 << 1;
+const VGICD_CTLR_ARE_NS: usize = 1 << 4;
+const GICD_IROUTER_IRM: u64 = 1 << 31;
+const GICR_WAKER_PROCESSOR_SLEEP: usize = 1 << 1;
+const GICR_WAKER_CHILDREN_ASLEEP: usize = 1 << 2;
+/// ICFGR0 of the SGIs, all edge-triggered.
+const SGI_ICFGR: usize = 0xaaaa_aaaa;
+/// Priority of the physical SPIs backing virtual ones, taken by the
+/// hypervisor before the guest.
+const HW_SPI_PRIORITY: u8 = 0xa0;
+
+const ICH_HCR_UIE: u64 = 1 << 1;
+const ICH_LR_VINTID_MASK: u64 = 0xffff_ffff;
+const ICH_LR_PINTID_SHIFT: u64 = 32;
+const ICH_LR_PRIORITY_SHIFT: u64 = 48;
+const ICH_LR_GROUP1: u64 = 1 << 60;
+const ICH_LR_HW: u64 = 1 << 61;
+const ICH_LR_PENDING: u64 = 1 << 62;
+const ICH_LR_ACTIVE: u64 = 1 << 63;
+
+#[derive(Debug, Clone, Copy)]
+struct IrqState {
+    enabled: bool,
+    /// Waiting for a list register.
+    pending: bool,
+    /// Set by the guest through ISACTIVER, the active state of a delivered irq
+    /// is in its list register.
+    active: bool,
+    /// The physical irq raising a pending one is still active, to be
+    /// deactivated by the guest through the list register.
+    hw_held: bool,
+    edge: bool,
+    priority: u8,
+    /// GICD_IROUTER of an SPI, vcpus having their id as affinity.
+    route: u64,
+}
+
+const IRQ_RESET: IrqState = IrqState {
+    enabled: false,
+    pending: false,
+    active: false,
+    hw_held: false,
+    edge: false,
+    priority: 0,
+    route: 0,
+};
+
+#[derive(Clone, Copy)]
+enum Field {
+    Enabled,
+    Pending,
+    Active,
+}
+
+struct VGicState {
+    /// EnableGrp1 and EnableGrp1A of GICD_CTLR.
+    ctlr: usize,
+    /// SGIs and PPIs of each vcpu.
+    private: Vec<[IrqState; NR_PRIVATE_IRQS]>,
+    /// By INTID from 32.
+    spis: Vec<IrqState>,
+    /// GICR_WAKER.ProcessorSleep of each vcpu.
+    asleep: Vec<bool>,
+}
+
+impl VGicState {
+    fn new(nr_vcpus: usize) -> Self {
+        let mut sgi = IRQ_RESET;
+        sgi.edge = true;
+        let mut private = [IRQ_RESET; NR_PRIVATE_IRQS];
+        private[..16].fill(sgi);
+        Self {
+            ctlr: 0,
+            private: vec![private; nr_vcpus],
+            spis: vec![IRQ_RESET; NR_IRQS - NR_PRIVATE_IRQS],
+            asleep: vec![true; nr_vcpus],
+        }
+    }
+
+    fn irq(&mut self, vcpu: usize, intid: usize) -> Option<&mut IrqState> {
+        match intid {
+            0..=31 => self.private.get_mut(vcpu).map(|irqs| &mut irqs[intid]),
+            _ => self.spis.get_mut(intid - NR_PRIVATE_IRQS),
+        }
+    }
+}
+
+pub struct VGic {
+    cpu_set: CpuSet,
+    /// Virtual and physical INTIDs of the SPIs, physical 0 for the ones only
+    /// raised by the hypervisor.
+    maps: Vec<(u32, u32)>,
+    state: Mutex<VGicState>,
+}
+
+impl VGic {
+    /// The vGIC of a zone created from `config`: its `interrupts` keep their
+    /// INTID, the irqs of its shared memories and mailbox are virtual.
+    pub fn new(config: &HvZoneConfig) -> Self {
+        let maps = config
+            .interrupts()
+            .iter()
+            .map(|&irq| (irq, irq))
+            .chain(config.irq_maps().iter().map(|map| (map.virq, map.pirq)))
+            .chain(config.shmems().iter().map(|shmem| (shmem.irq, 0)))
+            .chain(Some(config.msg_irq).filter(|&irq| irq != 0).map(|irq| (irq, 0)))
+            .collect();
+        let cpu_set = config.cpu_set();
+        Self {
+            cpu_set,
+            maps,
+            state: Mutex::new(VGicState::new(cpu_set.iter().count())),
+        }
+    }
+
+    /// The physical SPIs of the zone.
+    pub fn hw_irqs(&self) -> impl Iterator<Item = u32> + '_ {
+        self.maps.iter().map(|&(_, pirq)| pirq).filter(|&pirq| pirq != 0)
+    }
+
+    pub fn virq_of(&self, pirq: u32) -> Option<u32> {
+        match pirq {
+            0 => None,
+            _ => self.maps.iter().find(|&&(_, p)| p == pirq).map(|&(virq, _)| virq),
+        }
+    }
+
+    fn pirq_of(&self, virq: usize) -> Option<usize> {
+        self.maps
+            .iter()
+            .find(|&&(v, p)| v as usize == virq && p != 0)
+            .map(|&(_, pirq)| pirq as usize)
+    }
+
+    fn has_virq(&self, virq: usize) -> bool {
+        self.maps.iter().any(|&(v, _)| v as usize == virq)
+    }
+
+    /// Back to the state at reset: the physical SPIs are group 1 and disabled,
+    /// with the first cpu of the zone as target.
+    pub fn reset(&self) {
+        *self.state.lock() = VGicState::new(self.cpu_set.iter().count());
+        let gicd_base = host_gicd_base();
+        let _lock = GICD_LOCK.lock();
+        for pirq in self.hw_irqs().map(|pirq| pirq as usize) {
+            let (reg, bit) = (pirq / 32 * 4, 1 << (pirq % 32));
+            unsafe {
+                write_volatile((gicd_base + GICD_ICENABLER + reg) as *mut u32, bit);
+                write_volatile((gicd_base + GICD_ICPENDR + reg) as *mut u32, bit);
+                write_volatile((gicd_base + GICD_ICACTIVER + reg) as *mut u32, bit);
+                let igroupr = (gicd_base + GICD_IGROUPR + reg) as *mut u32;
+                write_volatile(igroupr, read_volatile(igroupr) | bit);
+                write_volatile((gicd_base + GICD_IPRIORITYR + pirq) as *mut u8, HW_SPI_PRIORITY);
+            }
+            self.hw_route(pirq, GICD_IROUTER_IRM);
+        }
+    }
+
+    /// The physical cpu running `vcpu`, or the first cpu of the zone.
+    fn cpu_of_vcpu(&self, vcpu: Option<usize>) -> usize {
+        vcpu.and_then(|vcpu| self.cpu_set.cpu_of_vcpu(vcpu))
+            .or(self.cpu_set.first_cpu())
+            .unwrap()
+    }
+
+    /// The vcpu targeted by the route of an SPI, `None` for any vcpu.
+    fn route_vcpu(route: u64) -> Option<usize> {
+        match route & GICD_IROUTER_IRM {
+            0 => Some((route & 0xff_ffff) as usize),
+            _ => None,
+        }
+    }
+
+    /// Route the physical SPI `pirq` as its virtual `route`.
+    fn hw_route(&self, pirq: usize, route: u64) {
+        let cpu = self.cpu_of_vcpu(Self::route_vcpu(route));
+        let irouter = host_gicd_base() + GICD_IROUTER + pirq * 8;
+        // cpu ids are MPIDR affinities, laid out as in GICD_IROUTER
+        unsafe { write_volatile(irouter as *mut u64, cpu as u64) };
+    }
+
+    fn hw_enable(&self, vcpu: usize, intid: usize, enable: bool) {
+        let (reg, base, pintid) = match intid {
+            0..=15 => return,
+            16..=31 if intid == SCHED_TIMER_IRQ || intid == MAINTENANCE_IRQ => return,
+            16..=31 => {
+                let base = host_gicr_base(self.cpu_of_vcpu(Some(vcpu))) + GICR_SGI_BASE;
+                let igroupr = (base + GICR_IGROUPR) as *mut u32;
+                unsafe { write_volatile(igroupr, read_volatile(igroupr) | 1 << intid) };
+                (0, base, intid)
+            }
+            _ => match self.pirq_of(intid) {
+                Some(pirq) => (pirq / 32 * 4, host_gicd_base(), pirq),
+                None => return,
+            },
+        };
+        let enabler = match enable {
+            true => GICD_ISENABLER,
+            false => GICD_ICENABLER,
+        };
+        unsafe { write_volatile((base + enabler + reg) as *mut u32, 1 << (pintid % 32)) };
+    }
+
+    fn hw_config(&self, virq: usize, edge: bool) {
+        let pirq = match self.pirq_of(virq) {
+            Some(pirq) => pirq,
+            None => return,
+        };
+        let icfgr = (host_gicd_base() + GICD_ICFGR + pirq / 16 * 4) as *mut u32;
+        let bit = 2 << (pirq % 16 * 2);
+        let _lock = GICD_LOCK.lock();
+        unsafe {
+            let value = read_volatile(icfgr) & !bit;
+            write_volatile(icfgr, value | if edge { bit } else { 0 });
+        }
+    }
+
+    /// Whether the irq `intid` of the model is visible through a private
+    /// frame or the distributor.
+    fn is_visible(&self, intid: usize, private: bool) -> bool {
+        match private {
+            true => intid < NR_PRIVATE_IRQS,
+            false => intid >= NR_PRIVATE_IRQS && self.has_virq(intid),
+        }
+    }
+
+    /// The cpu holding the list registers `intid` goes to.
+    fn target_cpu(&self, vcpu: usize, intid: usize, route: u64) -> usize {
+        match intid {
+            0..=31 => self.cpu_of_vcpu(Some(vcpu)),
+            _ => self.cpu_of_vcpu(Self::route_vcpu(route)),
+        }
+    }
+
+    fn kick(&self, cpu: usize) {
+        match cpu == this_cpu_id() {
+            true => flush_current(),
+            false => send_event(cpu, SGI_IPI_ID as _, IPI_EVENT_VGIC_FLUSH),
+        }
+    }
+
+    fn kick_all(&self) {
+        self.cpu_set.iter().for_each(|cpu| self.kick(cpu));
+    }
+
+    /// Make `intid` pending, for `vcpu` if it's private. `hw` tells it's the
+    /// physical irq acknowledged by the current cpu, which the guest
+    /// deactivates if it's delivered here at once.
+    fn raise(&self, vcpu: usize, intid: usize, hw: bool) {
+        let pintid = match intid {
+            0..=31 => Some(intid),
+            _ => self.pirq_of(intid),
+        };
+        let cpu = {
+            let mut state = self.state.lock();
+            let irq = match state.irq(vcpu, intid) {
+                Some(irq) => irq,
+                None => return,
+            };
+            irq.pending = true;
+            irq.hw_held = false;
+            let cpu = self.target_cpu(vcpu, intid, irq.route);
+            irq.hw_held = hw && cpu == this_cpu_id();
+            cpu
+        };
+        if hw && cpu != this_cpu_id() {
+            pintid.map(drop_hw_irq);
+        }
+        self.kick(cpu);
+        if hw && cpu == this_cpu_id() {
+            let mut state = self.state.lock();
+            let irq = state.irq(vcpu, intid).unwrap();
+            if irq.hw_held {
+                // no list register for it, don't keep the physical irq active
+                irq.hw_held = false;
+                pintid.map(drop_hw_irq);
+            }
+        }
+    }
+
+    /// Raise the virtual SPI `virq`.
+    pub fn raise_spi(&self, virq: usize) {
+        self.raise(0, virq, false);
+    }
+
+    /// Raise the SGI `sgi` for the vcpu of `cpu`.
+    pub fn raise_sgi(&self, cpu: usize, sgi: usize) {
+        if let Some(vcpu) = self.cpu_set.vcpu_id(cpu) {
+            self.raise(vcpu, sgi, false);
+        }
+    }
+
+    /// Move the deliverable pending irqs of the vcpu on the current cpu to
+    /// the list registers.
+    fn flush(&self) {
+        let vcpu = match self.cpu_set.vcpu_id(this_cpu_id()) {
+            Some(vcpu) => vcpu,
+            None => return,
+        };
+        let mut state = self.state.lock();
+        if state.ctlr & VGICD_CTLR_ENABLE_G1A == 0 {
+            return;
+        }
+        for intid in 0..NR_IRQS {
+            let irq = state.irq(vcpu, intid).unwrap();
+            if !irq.pending || !irq.enabled {
+                continue;
+            }
+            let routed_away = Self::route_vcpu(irq.route).is_some_and(|v| v != vcpu);
+            if intid >= NR_PRIVATE_IRQS && routed_away {
+                continue;
+            }
+            let pintid = match (irq.hw_held, intid) {
+                (false, _) => None,
+                (true, 0..=31) => Some(intid),
+                (true, _) => self.pirq_of(intid),
+            };
+            match lr_push(intid, irq.priority, pintid) {
+                LrPush::Done => {
+                    irq.pending = false;
+                    irq.hw_held = false;
+                }
+                LrPush::Busy => {}
+                LrPush::Full => {
+                    write_sysreg!(ich_hcr_el2, read_sysreg!(ich_hcr_el2) | ICH_HCR_UIE);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Emulate an access to the registers shared by the distributor and the
+    /// SGI frames of the redistributors, `private` for the latter. Return
+    /// whether `reg` is one of them.
+    fn irq_regs_access(&self, mmio: &mut MMIOAccess, vcpu: usize, reg: usize, private: bool) -> bool {
+        let bitmap = |base: usize| match (base..base + NR_IRQS / 8).contains(&reg) {
+            true => Some((reg - base) / 4 * 32),
+            false => None,
+        };
+        if let Some(first) = bitmap(GICD_IGROUPR) {
+            // group 1 only
+            mmio.value = match mmio.is_write {
+                true => mmio.value,
+                false => (0..32)
+                    .filter(|i| self.is_visible(first + i, private))
+                    .fold(0, |value, i| value | 1 << i),
+            };
+        } else if let Some(first) = bitmap(GICD_ISENABLER) {
+            self.bitmap_access(mmio, vcpu, first, private, Field::Enabled, true);
+        } else if let Some(first) = bitmap(GICD_ICENABLER) {
+            self.bitmap_access(mmio, vcpu, first, private, Field::Enabled, false);
+        } else if let Some(first) = bitmap(GICD_ISPENDR) {
+            self.bitmap_access(mmio, vcpu, first, private, Field::Pending, true);
+        } else if let Some(first) = bitmap(GICD_ICPENDR) {
+            self.bitmap_access(mmio, vcpu, first, private, Field::Pending, false);
+        } else if let Some(first) = bitmap(GICD_ISACTIVER) {
+            self.bitmap_access(mmio, vcpu, first, private, Field::Active, true);
+        } else if let Some(first) = bitmap(GICD_ICACTIVER) {
+            self.bitmap_access(mmio, vcpu, first, private, Field::Active, false);
+        } else if (GICD_IPRIORITYR..GICD_IPRIORITYR + NR_IRQS).contains(&reg) {
+            self.priority_access(mmio, vcpu, reg - GICD_IPRIORITYR, private);
+        } else if (GICD_ICFGR..GICD_ICFGR + NR_IRQS / 4).contains(&reg) {
+            self.config_access(mmio, vcpu, (reg - GICD_ICFGR) / 4 * 16, private);
+        } else {
+            return false;
+        }
+        true
+    }
+
+    fn bitmap_access(
+        &self,
+        mmio: &mut MMIOAccess,
+        vcpu: usize,
+        first: usize,
+        private: bool,
+        field: Field,
+        set: bool,
+    ) {
+        let mut state = self.state.lock();
+        let on_this_cpu = self.cpu_set.vcpu_id(this_cpu_id()) == Some(vcpu);
+        let visible = (0..32).filter(|&i| self.is_visible(first + i, private));
+        if !mmio.is_write {
+            mmio.value = 0;
+            for i in visible {
+                let intid = first + i;
+                let irq = state.irq(vcpu, intid).unwrap();
+                // delivered irqs are in the list registers of their cpu
+                let (lr_pending, lr_active) = match on_this_cpu || !private {
+                    true => lr_state(intid),
+                    false => (false, false),
+                };
+                let bit = match field {
+                    Field::Enabled => irq.enabled,
+                    Field::Pending => irq.pending || lr_pending,
+                    Field::Active => irq.active || lr_active,
+                };
+                mmio.value |= (bit as usize) << i;
+            }
+            return;
+        }
+
+        let mut changed = false;
+        for i in visible.filter(|i| mmio.value & 1 << i != 0) {
+            let intid = first + i;
+            let irq = state.irq(vcpu, intid).unwrap();
+            match field {
+                Field::Enabled => {
+                    if irq.enabled != set {
+                        irq.enabled = set;
+                        self.hw_enable(vcpu, intid, set);
+                    }
+                }
+                Field::Pending => irq.pending = set,
+                Field::Active => irq.active = set,
+            }
+            changed = true;
+        }
+        drop(state);
+        if changed && set && !matches!(field, Field::Active) {
+            self.kick_all();
+        }
+    }
+
+    fn priority_access(&self, mmio: &mut MMIOAccess, vcpu: usize, first: usize, private: bool) {
+        let mut state = self.state.lock();
+        if !mmio.is_write {
+            mmio.value = 0;
+        }
+        for i in (0..mmio.size).filter(|&i| self.is_visible(first + i, private)) {
+            let irq = state.irq(vcpu, first + i).unwrap();
+            match mmio.is_write {
+                true => irq.priority = (mmio.value >> (i * 8)) as u8,
+                false => mmio.value |= (irq.priority as usize) << (i * 8),
+            }
+        }
+    }
+
+    fn config_access(&self, mmio: &mut MMIOAccess, vcpu: usize, first: usize, private: bool) {
+        if private && first == 0 {
+            // SGIs
+            mmio.value = SGI_ICFGR;
+            return;
+        }
+        let mut state = self.state.lock();
+        if !mmio.is_write {
+            mmio.value = 0;
+        }
+        for i in (0..16).filter(|&i| self.is_visible(first + i, private)) {
+            let irq = state.irq(vcpu, first + i).unwrap();
+            let edge = mmio.value & 2 << (i * 2) != 0;
+            match mmio.is_write {
+                true if irq.edge != edge => {
+                    irq.edge = edge;
+                    self.hw_config(first + i, edge);
+                }
+                true => {}
+                false => mmio.value |= (irq.edge as usize) << (i * 2 + 1),
+            }
+        }
+    }
+
+    fn router_access(&self, mmio: &mut MMIOAccess, offset: usize) {
+        let virq = offset / 8;
+        if !self.is_visible(virq, false) {
+            mmio.value = 0;
+            return;
+        }
+        // a 64-bit register, or one of its halves
+        let (shift, mask) = match (mmio.size, offset % 8) {
+            (8, _) => (0, u64::MAX),
+            (_, 0) => (0, 0xffff_ffff),
+            _ => (32, 0xffff_ffff << 32),
+        };
+        let mut state = self.state.lock();
+        let irq = state.irq(0, virq).unwrap();
+        if !mmio.is_write {
+            mmio.value = ((irq.route & mask) >> shift) as usize;
+            return;
+        }
+        irq.route = irq.route & !mask | (mmio.value as u64) << shift & mask;
+        let route = irq.route;
+        drop(state);
+        if let Some(pirq) = self.pirq_of(virq) {
+            self.hw_route(pirq, route);
+        }
+        self.kick_all();
+    }
+
+    fn dist_access(&self, mmio: &mut MMIOAccess) {
+        let reg = mmio.address;
+        let vcpu = self.cpu_set.vcpu_id(this_cpu_id()).unwrap();
+        if self.irq_regs_access(mmio, vcpu, reg, false) {
+            return;
+        }
+        match reg {
+            GICD_CTLR => match mmio.is_write {
+                true => {
+                    self.state.lock().ctlr = mmio.value & (VGICD_CTLR_ENABLE_G1 | VGICD_CTLR_ENABLE_G1A);
+                    self.kick_all();
+                }
+                false => mmio.value = self.state.lock().ctlr | VGICD_CTLR_ARE_NS,
+            },
+            GICD_TYPER => mmio.value = VGICD_TYPER,
+            GICD_IIDR => mmio.value = VGIC_IIDR,
+            GICDV3_PIDR2 => mmio.value = VGIC_PIDR2,
+            reg if (GICD_IROUTER..GICD_IROUTER + NR_IRQS * 8).contains(&reg) => {
+                self.router_access(mmio, reg - GICD_IROUTER)
+            }
+            GICDV3_PIDR4..=0xfffc if !mmio.is_write => {
+                mmio_perform_access(host_gicd_base(), mmio)
+            }
+            _ => {
+                debug!("vgicd: ignore access {:#x?}", mmio);
+                mmio.value = 0;
+            }
+        }
+    }
+
+    fn redist_access(&self, mmio: &mut MMIOAccess, cpu: usize) {
+        let vcpu = self.cpu_set.vcpu_id(cpu);
+        // foreign redistributors match no vcpu
+        let affinity = vcpu.unwrap_or(0xffff_ffff);
+        let last = match cpu == MAX_CPU_NUM - 1 {
+            true => GICR_TYPER_LAST,
+            false => 0,
+        };
+        match (mmio.address, vcpu) {
+            (GICR_TYPER, _) if mmio.size == 8 => {
+                mmio.value = affinity << 32 | (vcpu.unwrap_or(0) & 0xffff) << 8 | last
+            }
+            (GICR_TYPER, _) => mmio.value = (vcpu.unwrap_or(0) & 0xffff) << 8 | last,
+            (GICR_TYPER_AFFINITY, _) => mmio.value = affinity,
+            (GICR_IIDR, _) => mmio.value = VGIC_IIDR,
+            (GICR_PIDR2, _) => mmio.value = VGIC_PIDR2,
+            (0xffd0..=0xfffc, _) if !mmio.is_write => {
+                mmio_perform_access(host_gicr_base(cpu), mmio)
+            }
+            (GICR_WAKER, Some(vcpu)) => {
+                let mut state = self.state.lock();
+                match mmio.is_write {
+                    true => state.asleep[vcpu] = mmio.value & GICR_WAKER_PROCESSOR_SLEEP != 0,
+                    false => {
+                        mmio.value = match state.asleep[vcpu] {
+                            true => GICR_WAKER_PROCESSOR_SLEEP | GICR_WAKER_CHILDREN_ASLEEP,
+                            false => 0,
+                        }
+                    }
+                }
+            }
+            (reg, Some(vcpu))
+                if reg >= GICR_SGI_BASE
+                    && self.irq_regs_access(mmio, vcpu, reg - GICR_SGI_BASE, true) => {}
+            _ => {
+                trace!("vgicr({}): ignore access {:#x?}", cpu, mmio);
+                mmio.value = 0;
+            }
+        }
+    }
+}
+
+enum LrPush {
+    Done,
+    /// Already in a list register, where it can't be made pending again.
+    Busy,
+    Full,
+}
+
+/// Put the pending `intid` in a list register of the current cpu, mapped to
+/// the physical `pintid` if any.
+fn lr_push(intid: usize, priority: u8, pintid: Option<usize>) -> LrPush {
+    let elrsr = read_sysreg!(ich_elrsr_el2);
+    let mut free = None;
+    for i in 0..lr_num() {
+        if elrsr & 1 << i != 0 {
+            free = free.or(Some(i));
+            continue;
+        }
+        let lr = read_lr(i);
+        if lr & ICH_LR_VINTID_MASK != intid as u64 {
+            continue;
+        }
+        return match lr & (ICH_LR_PENDING | ICH_LR_HW) {
+            0 => {
+                // active, and now pending again
+                write_lr(i, lr | ICH_LR_PENDING);
+                LrPush::Done
+            }
+            ICH_LR_PENDING => LrPush::Done,
+            _ => LrPush::Busy,
+        };
+    }
+    let i = match free {
+        Some(i) => i,
+        None => return LrPush::Full,
+    };
+    let mut lr = intid as u64 | (priority as u64) << ICH_LR_PRIORITY_SHIFT | ICH_LR_GROUP1;
+    if let Some(pintid) = pintid {
+        lr |= ICH_LR_HW | (pintid as u64) << ICH_LR_PINTID_SHIFT;
+    }
+    write_lr(i, lr | ICH_LR_PENDING);
+    LrPush::Done
+}
+
+/// Whether `intid` is pending and active in a list register of the current
+/// cpu.
+fn lr_state(intid: usize) -> (bool, bool) {
+    let elrsr = read_sysreg!(ich_elrsr_el2);
+    (0..lr_num())
+        .filter(|i| elrsr & 1 << i == 0)
+        .map(read_lr)
+        .find(|lr| lr & ICH_LR_VINTID_MASK == intid as u64)
+        .map_or((false, false), |lr| {
+            (lr & ICH_LR_PENDING != 0, lr & ICH_LR_ACTIVE != 0)
+        })
+}
+
+/// The vGIC of the zone loaded on the current cpu, if it has one.
+fn current_vgic() -> Option<Arc<VGic>> {
+    this_cpu_data()
+        .zone
+        .as_ref()
+        .and_then(|zone| zone.read().vgic.clone())
+}
+
+/// Deliver what the vcpu loaded on the current cpu can take.
+pub fn flush_current() {
+    if let Some(vgic) = current_vgic() {
+        vgic.flush();
+    }
+}
+
+/// Called on the maintenance irq: list registers were freed.
+pub fn handle_maintenance_irq() {
+    write_sysreg!(ich_hcr_el2, read_sysreg!(ich_hcr_el2) & !ICH_HCR_UIE);
+    flush_current();
+}
+
+/// Take the physical `irq` acknowledged by the current cpu, if it's raising
+/// an irq of a zone with a vGIC. Return whether it did.
+pub fn handle_hw_irq(irq: usize) -> bool {
+    if irq < NR_PRIVATE_IRQS {
+        let vgic = match current_vgic() {
+            Some(vgic) => vgic,
+            None => return false,
+        };
+        match vgic.cpu_set.vcpu_id(this_cpu_id()) {
+            // SGIs from the hypervisor are virtual ones
+            Some(vcpu) => vgic.raise(vcpu, irq, irq >= 16),
+            None => drop_hw_irq(irq),
+        }
+        return true;
+    }
+    let vgic = match find_vgic_of_irq(irq as _) {
+        Some(vgic) => vgic,
+        None => return false,
+    };
+    let virq = vgic.virq_of(irq as _).unwrap();
+    vgic.raise(0, virq as _, true);
+    true
+}
+
+pub fn vgic_emul_dist_handler(mmio: &mut MMIOAccess, _arg: usize) -> HvResult {
+    trace!("vgicd mmio = {:#x?}", mmio);
+    current_vgic().unwrap().dist_access(mmio);
+    Ok(())
+}
+
+pub fn vgic_emul_redist_handler(mmio: &mut MMIOAccess, cpu: usize) -> HvResult {
+    trace!("vgicr({}) mmio = {:#x?}", cpu, mmio);
+    current_vgic().unwrap().redist_access(mmio, cpu);
+    Ok(())
+}
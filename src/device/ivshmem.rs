@@ -0,0 +1,151 @@
+//! Inter-zone shared memory with doorbells, after QEMU's ivshmem.
+//!
+//! Zones attaching a device by the same name get the same pages from the guest
+//! memory pool, mapped at an address of their choice. The pages live until
+//! the last zone attached is gone. Each zone also gets a page of doorbell
+//! registers:
+//!
+//! - `IVPOSITION` (0x0, read-only): the id of the zone.
+//! - `DOORBELL` (0x4, write-only): the id of a peer, which gets the SPI it
+//!   configured for the device.
+
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::config::HvConfigShmem;
+use crate::error::HvResult;
+use crate::event::send_zone_irq;
+use crate::memory::{Frame, GuestPhysAddr, MMIOAccess, MemFlags, MemoryRegion, PAGE_SIZE};
+use crate::percpu::this_zone;
+use crate::zone::Zone;
+
+const IVPOSITION: usize = 0x0;
+const DOORBELL: usize = 0x4;
+
+pub struct Shmem {
+    name: String,
+    frame: Frame,
+    /// Attached zones and the irq each of them wants.
+    peers: Mutex<Vec<(usize, u32)>>,
+}
+
+/// A zone's handle on a shared memory device, detaching it when dropped.
+pub struct ShmemAttachment {
+    shmem: Arc<Shmem>,
+    zone_id: usize,
+}
+
+impl Drop for ShmemAttachment {
+    fn drop(&mut self) {
+        self.shmem
+            .peers
+            .lock()
+            .retain(|&(zone_id, _)| zone_id != self.zone_id);
+    }
+}
+
+impl ShmemAttachment {
+    pub fn frame(&self) -> &Frame {
+        &self.shmem.frame
+    }
+}
+
+static SHMEMS: Mutex<Vec<Weak<Shmem>>> = Mutex::new(Vec::new());
+
+/// Find the device named by `config`, or create it.
+fn shmem_get(config: &HvConfigShmem) -> HvResult<Arc<Shmem>> {
+    let mut shmems = SHMEMS.lock();
+    shmems.retain(|shmem| shmem.strong_count() > 0);
+    if let Some(shmem) = shmems
+        .iter()
+        .filter_map(|shmem| shmem.upgrade())
+        .find(|shmem| shmem.name == config.name())
+    {
+        if shmem.frame.size() != config.size as usize {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "shmem {} has {:#x} bytes, not {:#x}",
+                    shmem.name,
+                    shmem.frame.size(),
+                    config.size
+                )
+            );
+        }
+        return Ok(shmem);
+    }
+    let mut frame = Frame::new_guest(config.size as usize / PAGE_SIZE, 0)?;
+    frame.clear();
+    info!("shmem {} created at {:#x}", config.name(), frame.start_paddr());
+    let shmem = Arc::new(Shmem {
+        name: String::from(config.name()),
+        frame,
+        peers: Mutex::new(Vec::new()),
+    });
+    shmems.push(Arc::downgrade(&shmem));
+    Ok(shmem)
+}
+
+impl Zone {
+    /// Attach the zone to the shared memory devices of its config: map the
+    /// memory and register the doorbell.
+    pub fn shmem_init(&mut self, shmems: &[HvConfigShmem]) -> HvResult {
+        for config in shmems {
+            let shmem = shmem_get(config)?;
+            info!(
+                "zone {} attach shmem {} at ipa {:#x}, doorbell {:#x}",
+                self.id, shmem.name, config.ipa, config.doorbell_ipa
+            );
+            self.gpm.insert(MemoryRegion::new_with_offset_mapper(
+                config.ipa as GuestPhysAddr,
+                shmem.frame.start_paddr(),
+                config.size as _,
+                MemFlags::READ | MemFlags::WRITE | MemFlags::COMMUNICATION,
+            ))?;
+            shmem.peers.lock().push((self.id, config.irq));
+            self.mmio_region_register(
+                config.doorbell_ipa as _,
+                PAGE_SIZE,
+                ivshmem_doorbell_handler,
+                self.shmem.len(),
+            );
+            self.shmem.push(ShmemAttachment {
+                shmem,
+                zone_id: self.id,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// Doorbell registers of the `index`-th shared memory device of the zone.
+pub fn ivshmem_doorbell_handler(mmio: &mut MMIOAccess, index: usize) -> HvResult {
+    let zone = this_zone();
+    let zone_r = zone.read();
+    let attachment = &zone_r.shmem[index];
+    match (mmio.address, mmio.is_write) {
+        (IVPOSITION, false) => mmio.value = zone_r.id,
+        (DOORBELL, true) => {
+            let peer = mmio.value as u32 as usize;
+            let irq = attachment
+                .shmem
+                .peers
+                .lock()
+                .iter()
+                .find(|&&(zone_id, _)| zone_id == peer)
+                .map(|&(_, irq)| irq);
+            match irq {
+                Some(irq) => send_zone_irq(peer, irq as _),
+                None => debug!(
+                    "zone {} rings shmem {} peer {}, not attached",
+                    zone_r.id, attachment.shmem.name, peer
+                ),
+            }
+        }
+        (_, false) => mmio.value = 0,
+        _ => {}
+    }
+    Ok(())
+}
@@ -1,4 +1,5 @@
 pub mod common;
 pub mod irqchip;
+pub mod ivshmem;
 pub mod uart;
 pub mod virtio_trampoline;
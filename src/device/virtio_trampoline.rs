@@ -11,6 +11,7 @@ use crate::device::irqchip::gicv3::inject_irq;
 use crate::event::send_event;
 use crate::event::IPI_EVENT_WAKEUP_VIRTIO_DEVICE;
 use crate::hypercall::SGI_IPI_ID;
+use crate::scheduler::route_irq;
 use crate::zone::root_zone;
 use crate::zone::this_zone_id;
 use crate::{error::HvResult, memory::MMIOAccess};
@@ -93,7 +94,9 @@ pub fn handle_virtio_irq() {
     let irq_list = map.get_mut(&this_cpu_id()).unwrap();
     let len = irq_list[0] as usize;
     for irq_id in irq_list[1..=len].iter() {
-        inject_irq(*irq_id as _, false);
+        if !route_irq(*irq_id as _, false) {
+            inject_irq(*irq_id as _, false);
+        }
     }
     irq_list[0] = 0;
 }
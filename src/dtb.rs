@@ -0,0 +1,362 @@
+//! Device trees handed to zones.
+//!
+//! A zone created from a config doesn't get the device tree passed by the root
+//! zone as is, but a copy trimmed to its resources: the devices outside of its
+//! memory and MMIO windows and the cpus beyond its own are dropped, and its
+//! memory nodes are rewritten from the config, and the initrd and reserved
+//! memory of the root zone outside of its memory are dropped. The root zone's
+//! device tree describes the whole board: the board devices reserved for other
+//! zones are dropped from it, and the memory of the hypervisor, of the guest
+//! memory pool and of the other zones is added to it as `no-map` reserved
+//! memory.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use fdt::node::{CellSizes, FdtNode, NodeProperty};
+use fdt::writer::{FdtEditor, FdtWriter};
+use fdt::{Fdt, FdtError};
+
+use crate::config::HvZoneConfig;
+use crate::consts::{guest_mem_pool_start, hv_end, hv_start, GUEST_MEM_POOL_SIZE};
+use crate::error::HvResult;
+use crate::memory::addr::align_up;
+use crate::memory::{Frame, PAGE_SIZE};
+use crate::platform::{BoardDevice, ZoneReservation};
+
+/// Room for the generated device tree of a zone whose original one is `fdt`.
+pub fn zone_dtb_size(fdt: &Fdt) -> usize {
+    align_up(fdt.total_size() + PAGE_SIZE)
+}
+
+/// Split `value` into `cells` big-endian cells.
+fn push_cells(cells: &mut Vec<u32>, value: u64, count: usize) {
+    if count == 2 {
+        cells.push((value >> 32) as u32);
+    }
+    cells.push(value as u32);
+}
+
+fn write_reg(
+    writer: &mut FdtWriter,
+    (address_cells, size_cells): (usize, usize),
+    ranges: &[Range<u64>],
+) -> Result<(), FdtError> {
+    let mut cells = Vec::new();
+    for range in ranges {
+        push_cells(&mut cells, range.start, address_cells);
+        push_cells(&mut cells, range.end - range.start, size_cells);
+    }
+    writer.property_cells("reg", &cells)
+}
+
+fn cell_sizes(sizes: CellSizes) -> (usize, usize) {
+    (sizes.address_cells, sizes.size_cells)
+}
+
+fn is_memory(node: FdtNode) -> bool {
+    node.property("device_type").and_then(|p| p.as_str()) == Some("memory")
+}
+
+fn cpu_id(node: FdtNode) -> Option<u64> {
+    node.property("reg")
+        .and_then(|p| p.as_usize())
+        .map(|id| id as u64)
+}
+
+fn reg_start(node: FdtNode) -> Option<u64> {
+    node.reg()
+        .and_then(|mut reg| reg.next())
+        .map(|reg| reg.starting_address as u64)
+}
+
+fn is_simple_bus(node: FdtNode) -> bool {
+    node.compatible()
+        .map_or(false, |c| c.all().any(|c| c == "simple-bus"))
+}
+
+/// Write the device tree edited by `editor` to `buf`, return its size.
+pub(crate) fn edit(
+    fdt: &Fdt,
+    buf: &mut [u8],
+    editor: &mut impl FdtEditor,
+) -> Result<usize, FdtError> {
+    let mut writer = FdtWriter::new(buf)?;
+    writer.copy_tree(fdt, editor)?;
+    writer.finish()
+}
+
+/// Write the device tree edited by `editor` into a new frame of `size` bytes
+/// from the guest memory pool.
+fn generate(fdt: &Fdt, size: usize, editor: &mut impl FdtEditor) -> HvResult<Frame> {
+    let mut frame = Frame::new_guest(size / PAGE_SIZE, 0)?;
+    edit(fdt, frame.as_slice_mut(), editor).map_err(|e| {
+        hv_err!(
+            ENOMEM,
+            format!("zone dtb doesn't fit in {:#x} bytes: {}", size, e)
+        )
+    })?;
+    Ok(frame)
+}
+
+pub(crate) struct ZoneDtbEditor<'a> {
+    config: &'a HvZoneConfig,
+    num_cpus: u64,
+    /// Cells of the root node.
+    cells: (usize, usize),
+    /// Some cpus are dropped, and so is the cpu topology referring to them.
+    drop_cpu_map: bool,
+    /// Simple buses below the root, whose devices are filtered too. They're
+    /// expected to map the addresses of their children 1:1.
+    buses: Vec<String>,
+}
+
+impl<'a> ZoneDtbEditor<'a> {
+    pub(crate) fn new(config: &'a HvZoneConfig, fdt: &Fdt) -> Self {
+        let num_cpus = config.cpu_set().iter().count() as u64;
+        Self {
+            config,
+            num_cpus,
+            cells: cell_sizes(fdt.root().cell_sizes()),
+            drop_cpu_map: fdt.cpus().any(|cpu| cpu.ids().first() as u64 >= num_cpus),
+            buses: Vec::new(),
+        }
+    }
+
+    /// Whether the zone reaches a device at `addr`.
+    fn is_assigned(&self, addr: u64) -> bool {
+        let config = self.config;
+        config
+            .mmio_regions()
+            .iter()
+            .chain(config.memory_regions())
+            .any(|region| (region.ipa..region.ipa + region.size).contains(&addr))
+            || config.shmems().iter().any(|shmem| {
+                (shmem.ipa..shmem.ipa + shmem.size).contains(&addr)
+                    || shmem.doorbell_ipa == addr & !(PAGE_SIZE as u64 - 1)
+            })
+    }
+
+    fn keep_device(&self, node: FdtNode) -> bool {
+        // the gic and virtio devices are emulated
+        if node.property("interrupt-controller").is_some() || node.name.starts_with("virtio_mmio") {
+            return true;
+        }
+        match reg_start(node) {
+            Some(addr) if !self.is_assigned(addr) => {
+                debug!("zone {} dtb: drop {}", self.config.zone_id, node.name);
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether a reserved memory node of the root zone is kept: the zone gets
+    /// the ones inside its memory and the dynamically placed ones.
+    fn keep_reserved_memory(&self, node: FdtNode) -> bool {
+        let reg = match node.reg().and_then(|mut reg| reg.next()) {
+            Some(reg) => reg,
+            None => return true,
+        };
+        let size = reg.size.unwrap_or(0) as u64;
+        let keep = self
+            .config
+            .memory_region_of(reg.starting_address as u64, size)
+            .is_some();
+        if !keep {
+            debug!(
+                "zone {} dtb: drop reserved {}",
+                self.config.zone_id, node.name
+            );
+        }
+        keep
+    }
+}
+
+impl FdtEditor for ZoneDtbEditor<'_> {
+    fn keep_node(&mut self, path: &[&str], node: FdtNode) -> bool {
+        match path {
+            [_] if is_memory(node) => false,
+            [name] if matches!(*name, "cpus" | "chosen" | "aliases" | "reserved-memory") => true,
+            [name] => {
+                let keep = self.keep_device(node);
+                if keep && is_simple_bus(node) {
+                    self.buses.push(String::from(*name));
+                }
+                keep
+            }
+            ["cpus", "cpu-map"] => !self.drop_cpu_map,
+            ["cpus", name] if name.starts_with("cpu@") => {
+                matches!(cpu_id(node), Some(id) if id < self.num_cpus)
+            }
+            ["reserved-memory", _] => self.keep_reserved_memory(node),
+            [bus, _] if self.buses.iter().any(|b| b == bus) => self.keep_device(node),
+            _ => true,
+        }
+    }
+
+    fn write_property(
+        &mut self,
+        path: &[&str],
+        prop: NodeProperty,
+        writer: &mut FdtWriter,
+    ) -> Result<(), FdtError> {
+        match (path, prop.name) {
+            // the initrd of the root zone, in root zone memory
+            (["chosen"], "linux,initrd-start" | "linux,initrd-end") => Ok(()),
+            _ => writer.property(prop.name, prop.value),
+        }
+    }
+
+    fn add_nodes(&mut self, path: &[&str], writer: &mut FdtWriter) -> Result<(), FdtError> {
+        if !path.is_empty() {
+            return Ok(());
+        }
+        for region in self.config.memory_regions() {
+            writer.begin_node(&format!("memory@{:x}", region.ipa))?;
+            writer.property_str("device_type", "memory")?;
+            write_reg(writer, self.cells, &[region.ipa..region.ipa + region.size])?;
+            writer.end_node()?;
+        }
+        Ok(())
+    }
+}
+
+/// Generate the device tree of the zone described by `config` from `fdt`, the
+/// one passed by the root zone. Its vcpus have MPIDRs from 0, so the cpu nodes
+/// from `reg = <0>` up to the number of cpus of the zone are kept.
+pub fn zone_dtb(config: &HvZoneConfig, fdt: &Fdt) -> HvResult<Frame> {
+    generate(
+        fdt,
+        zone_dtb_size(fdt),
+        &mut ZoneDtbEditor::new(config, fdt),
+    )
+}
+
+pub(crate) struct RootDtbEditor<'a> {
+    reserved: Vec<(String, Range<u64>)>,
+    /// Board devices of other zones, whose nodes are dropped.
+    hidden: Vec<&'a BoardDevice>,
+    /// Cells of the node holding the reserved memory nodes.
+    cells: (usize, usize),
+    has_reserved_memory: bool,
+    /// Simple buses below the root, whose devices are hidden too.
+    buses: Vec<String>,
+}
+
+impl<'a> RootDtbEditor<'a> {
+    pub(crate) fn new(
+        fdt: &Fdt,
+        reserved: Vec<(String, Range<u64>)>,
+        hidden: Vec<&'a BoardDevice>,
+    ) -> Self {
+        let reserved_memory = fdt.find_node("/reserved-memory");
+        Self {
+            reserved,
+            hidden,
+            cells: match reserved_memory {
+                Some(node) => cell_sizes(node.cell_sizes()),
+                None => cell_sizes(fdt.root().cell_sizes()),
+            },
+            has_reserved_memory: reserved_memory.is_some(),
+            buses: Vec::new(),
+        }
+    }
+
+    fn is_hidden(&self, node: FdtNode) -> bool {
+        let hidden = self.hidden.iter().any(|dev| dev.covers(node));
+        if hidden {
+            debug!("root zone dtb: drop {}", node.name);
+        }
+        hidden
+    }
+
+    fn write_reserved(&self, writer: &mut FdtWriter) -> Result<(), FdtError> {
+        for (name, range) in &self.reserved {
+            writer.begin_node(&format!("{}@{:x}", name, range.start))?;
+            write_reg(writer, self.cells, &[range.clone()])?;
+            writer.property_empty("no-map")?;
+            writer.end_node()?;
+        }
+        Ok(())
+    }
+}
+
+impl FdtEditor for RootDtbEditor<'_> {
+    fn keep_node(&mut self, path: &[&str], node: FdtNode) -> bool {
+        match path {
+            [name] => {
+                let keep = !self.is_hidden(node);
+                if keep && is_simple_bus(node) {
+                    self.buses.push(String::from(*name));
+                }
+                keep
+            }
+            [bus, _] if self.buses.iter().any(|b| b == bus) => !self.is_hidden(node),
+            _ => true,
+        }
+    }
+
+    fn add_nodes(&mut self, path: &[&str], writer: &mut FdtWriter) -> Result<(), FdtError> {
+        match path {
+            ["reserved-memory"] => self.write_reserved(writer),
+            [] if !self.has_reserved_memory => {
+                writer.begin_node("reserved-memory")?;
+                writer.property_u32("#address-cells", self.cells.0 as u32)?;
+                writer.property_u32("#size-cells", self.cells.1 as u32)?;
+                writer.property_empty("ranges")?;
+                self.write_reserved(writer)?;
+                writer.end_node()
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Patch `fdt`, the device tree of the root zone, to keep the root zone off
+/// the memory of the hypervisor and of the guest memory pool, and off the
+/// resources of `zones`: their devices among `board_devices` are dropped and
+/// their memory is reserved.
+///
+/// The root zone still maps the memory of the other zones, which its virtio
+/// backend accesses, and keeps all cpus: it offlines the cpus of a zone before
+/// starting it.
+pub fn root_zone_dtb(
+    fdt: &Fdt,
+    board_devices: &'static [BoardDevice],
+    zones: &[ZoneReservation],
+) -> HvResult<Frame> {
+    let mut reserved = vec![
+        (String::from("hvisor"), hv_start() as u64..hv_end() as u64),
+        (
+            String::from("hvisor-guest-pool"),
+            guest_mem_pool_start() as u64..(guest_mem_pool_start() + GUEST_MEM_POOL_SIZE) as u64,
+        ),
+    ];
+    let mut hidden = Vec::new();
+    for zone in zones {
+        for range in zone.memory {
+            reserved.push((
+                format!("hvisor-zone{}", zone.zone_id),
+                range.start as u64..range.end as u64,
+            ));
+        }
+        for name in zone.devices {
+            match board_devices.iter().find(|dev| dev.name == *name) {
+                Some(dev) => hidden.push(dev),
+                None => {
+                    return hv_result_err!(
+                        EINVAL,
+                        format!(
+                            "zone {} reserves unknown board device {}",
+                            zone.zone_id, name
+                        )
+                    )
+                }
+            }
+        }
+    }
+    let mut editor = RootDtbEditor::new(fdt, reserved, hidden);
+    generate(fdt, zone_dtb_size(fdt), &mut editor)
+}
@@ -1,20 +1,35 @@
 use crate::{
-    arch::ipi::arch_send_event,
+    arch::{coredump::handle_dump_request, cpu::this_cpu_id, ipi::arch_send_event},
+    consts::MAX_CPU_NUM,
+    control::handle_suspend,
     device::{
-        irqchip::gicv3::inject_irq,
+        irqchip::gicv3::{inject_irq, vgic_emul::flush_current},
         virtio_trampoline::{handle_virtio_irq, IRQ_WAKEUP_VIRTIO_DEVICE},
     },
+    hypercall::SGI_IPI_ID,
     percpu::this_cpu_data,
+    scheduler::{route_irq, schedule},
+    zone::find_zone,
 };
 use alloc::{collections::VecDeque, vec::Vec};
+use core::sync::atomic::Ordering;
 use spin::{Mutex, Once};
 
 pub const IPI_EVENT_WAKEUP: usize = 0;
 pub const IPI_EVENT_SHUTDOWN: usize = 1;
 pub const IPI_EVENT_VIRTIO_INJECT_IRQ: usize = 2;
 pub const IPI_EVENT_WAKEUP_VIRTIO_DEVICE: usize = 3;
+pub const IPI_EVENT_SUSPEND: usize = 4;
+pub const IPI_EVENT_RESCHEDULE: usize = 5;
+pub const IPI_EVENT_ZONE_IRQ: usize = 6;
+pub const IPI_EVENT_DUMP: usize = 7;
+pub const IPI_EVENT_VGIC_FLUSH: usize = 8;
 static EVENT_MANAGER: Once<EventManager> = Once::new();
 
+/// Irqs raised by [`send_zone_irq`], by target cpu.
+static ZONE_IRQS: [Mutex<Vec<usize>>; MAX_CPU_NUM] =
+    [const { Mutex::new(Vec::new()) }; MAX_CPU_NUM];
+
 struct EventManager {
     pub inner: Vec<Mutex<VecDeque<usize>>>
 }
@@ -64,12 +79,22 @@ pub fn init(max_cpus: usize) {
 }
 
 pub fn check_events() -> bool {
+    if crate::panic::panicking() {
+        crate::panic::stop_this_cpu();
+    }
     let cpu_data = this_cpu_data();
     match fetch_event(cpu_data.id) {
         Some(IPI_EVENT_WAKEUP) => {
-            cpu_data.arch_cpu.run();
+            // stale if the vcpu it was meant for was switched out meanwhile
+            if cpu_data.arch_cpu.psci_on_pending {
+                cpu_data.arch_cpu.run();
+            }
+            true
         }
         Some(IPI_EVENT_SHUTDOWN) => {
+            // out of the guest, its memory may go
+            cpu_data.left_zone.store(true, Ordering::Release);
+            schedule();
             cpu_data.arch_cpu.idle();
         }
         Some(IPI_EVENT_VIRTIO_INJECT_IRQ) => {
@@ -80,6 +105,27 @@ pub fn check_events() -> bool {
             inject_irq(IRQ_WAKEUP_VIRTIO_DEVICE, false);
             true
         }
+        Some(IPI_EVENT_SUSPEND) => {
+            handle_suspend();
+            true
+        }
+        Some(IPI_EVENT_RESCHEDULE) => {
+            schedule();
+            true
+        }
+        Some(IPI_EVENT_ZONE_IRQ) => {
+            let irqs = core::mem::take(&mut *ZONE_IRQS[cpu_data.id].lock());
+            irqs.into_iter().for_each(inject_zone_irq);
+            true
+        }
+        Some(IPI_EVENT_DUMP) => {
+            handle_dump_request();
+            true
+        }
+        Some(IPI_EVENT_VGIC_FLUSH) => {
+            flush_current();
+            true
+        }
         _ => false,
     }
 }
@@ -88,3 +134,39 @@ pub fn send_event(cpu_id: usize, ipi_int_id: usize, event_id: usize) {
     add_event(cpu_id, event_id);
     arch_send_event(cpu_id as _, ipi_int_id as _);
 }
+
+fn inject_zone_irq(irq: usize) {
+    if !route_irq(irq, false) {
+        inject_irq(irq, false);
+    }
+}
+
+/// Raise the virtual `irq` in `zone_id`, on the first cpu of the zone.
+pub fn send_zone_irq(zone_id: usize, irq: usize) {
+    let zone = match find_zone(zone_id) {
+        Some(zone) => zone,
+        None => return,
+    };
+    let (cpu, vgic) = {
+        let zone = zone.read();
+        (zone.cpu_set.first_cpu(), zone.vgic.clone())
+    };
+    if let Some(vgic) = vgic {
+        vgic.raise_spi(irq);
+        return;
+    }
+    let cpu = match cpu {
+        Some(cpu) => cpu,
+        None => return,
+    };
+    if cpu == this_cpu_id() {
+        inject_zone_irq(irq);
+        return;
+    }
+    let mut irqs = ZONE_IRQS[cpu].lock();
+    if !irqs.contains(&irq) {
+        irqs.push(irq);
+        drop(irqs);
+        send_event(cpu, SGI_IPI_ID as _, IPI_EVENT_ZONE_IRQ);
+    }
+}
@@ -1,24 +1,24 @@
 #![allow(dead_code)]
-use crate::consts::{DTB_IPA, INVALID_ADDRESS, PAGE_SIZE};
+use crate::config::{HvZoneConfig, HvZoneInfo};
+use crate::coredump;
+use crate::consts::{PAGE_SIZE, ROOT_ZONE_ID};
 use crate::device::virtio_trampoline::{VIRTIO_BRIDGE, MAX_DEVS, MAX_REQ, VIRTIO_IRQS};
 use crate::error::HvResult;
-use crate::percpu::{get_cpu_data, PerCpu};
-use crate::zone::{find_zone, is_this_root_zone, remove_zone, zone_create};
+use crate::mailbox;
+use crate::percpu::{get_cpu_data, this_zone, PerCpu};
+use crate::scheduler;
+use crate::zone::{
+    find_zone, is_this_root_zone, root_zone, zone_create_from_config, zone_list_info,
+    zone_reboot, zone_shutdown, zone_suspend, ZoneState,
+};
 
-use crate::event::{send_event, IPI_EVENT_SHUTDOWN, IPI_EVENT_VIRTIO_INJECT_IRQ, IPI_EVENT_WAKEUP};
+use crate::event::{send_event, IPI_EVENT_VIRTIO_INJECT_IRQ, IPI_EVENT_WAKEUP};
 use core::convert::TryFrom;
+use core::mem::size_of;
 use core::sync::atomic::{fence, Ordering};
 
 use numeric_enum_macro::numeric_enum;
 
-#[repr(C)]
-#[derive(Debug)]
-pub struct ZoneInfo {
-    id: u64,
-    image_phys_addr: u64,
-    dtb_phys_addr: u64,
-}
-
 numeric_enum! {
     #[repr(u64)]
     #[derive(Debug, Eq, PartialEq, Copy, Clone)]
@@ -27,6 +27,13 @@ numeric_enum! {
         HvVirtioInjectIrq = 1,
         HvZoneStart = 2,
         HvZoneShutdown = 3,
+        HvZoneList = 4,
+        HvZonePause = 5,
+        HvZoneResume = 6,
+        HvZoneReboot = 7,
+        HvMsgSend = 8,
+        HvMsgRecv = 9,
+        HvCoreDumpSetup = 10,
     }
 }
 pub const SGI_IPI_ID: u64 = 7;
@@ -42,7 +49,7 @@ impl<'a> HyperCall<'a> {
         Self { cpu_data }
     }
 
-    pub fn hypercall(&mut self, code: u64, arg0: u64, _arg1: u64) -> HyperCallResult {
+    pub fn hypercall(&mut self, code: u64, arg0: u64, arg1: u64, arg2: u64) -> HyperCallResult {
         let code = match HyperCallCode::try_from(code) {
             Ok(code) => code,
             Err(_) => {
@@ -50,13 +57,18 @@ impl<'a> HyperCall<'a> {
                 return Ok(0);
             }
         };
-        unsafe {
-            match code {
-                HyperCallCode::HvVirtioInit => self.hv_virtio_init(arg0),
-                HyperCallCode::HvVirtioInjectIrq => self.hv_virtio_inject_irq(),
-                HyperCallCode::HvZoneStart => self.hv_zone_start(&*(arg0 as *const ZoneInfo)),
-                HyperCallCode::HvZoneShutdown => self.hv_zone_shutdown(arg0),
-            }
+        match code {
+            HyperCallCode::HvVirtioInit => self.hv_virtio_init(arg0),
+            HyperCallCode::HvVirtioInjectIrq => self.hv_virtio_inject_irq(),
+            HyperCallCode::HvZoneStart => self.hv_zone_start(arg0),
+            HyperCallCode::HvZoneShutdown => self.hv_zone_shutdown(arg0),
+            HyperCallCode::HvZoneList => self.hv_zone_list(arg0, arg1),
+            HyperCallCode::HvZonePause => self.hv_zone_pause(arg0),
+            HyperCallCode::HvZoneResume => self.hv_zone_resume(arg0),
+            HyperCallCode::HvZoneReboot => self.hv_zone_reboot(arg0),
+            HyperCallCode::HvMsgSend => self.hv_msg_send(arg0, arg1, arg2),
+            HyperCallCode::HvMsgRecv => self.hv_msg_recv(arg0, arg1),
+            HyperCallCode::HvCoreDumpSetup => self.hv_coredump_setup(arg0, arg1, arg2),
         }
     }
 
@@ -131,28 +143,37 @@ impl<'a> HyperCall<'a> {
         HyperCallResult::Ok(0)
     }
 
-    pub fn hv_zone_start(&mut self, zone_info: &ZoneInfo) -> HyperCallResult {
-        info!("handle hvc zone start");
+    /// Create and start a zone from the [`HvZoneConfig`] at `config_addr`, an
+    /// address of the root zone.
+    pub fn hv_zone_start(&mut self, config_addr: u64) -> HyperCallResult {
+        info!("handle hvc zone start, config={:#x}", config_addr);
         if !is_this_root_zone() {
             return hv_result_err!(
                 EPERM,
                 "Start zone operation over non-root zones: unsupported!"
             );
         }
-        let zone = zone_create(zone_info.id as _, zone_info.dtb_phys_addr as _, DTB_IPA)?;
+        // validated and used once copied, the root zone may change its own
+        let mut config: HvZoneConfig = unsafe { core::mem::zeroed() };
+        let bytes = unsafe {
+            core::slice::from_raw_parts_mut(
+                &mut config as *mut HvZoneConfig as *mut u8,
+                size_of::<HvZoneConfig>(),
+            )
+        };
+        root_zone().read().copy_from_guest(config_addr as _, bytes)?;
+        let zone = zone_create_from_config(&config)?;
+        let zone_id = zone.read().id;
         let boot_cpu = zone.read().cpu_set.first_cpu().unwrap();
+        let share_cpu = zone.read().can_share_cpu();
 
-        let target_data = get_cpu_data(boot_cpu as _);
-        let _lock = target_data.ctrl_lock.lock();
-
-        if !target_data.arch_cpu.psci_on {
-            send_event(boot_cpu, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
-        } else {
-            error!("hv_zone_start: cpu {} already on", boot_cpu);
-            return hv_result_err!(EBUSY);
-        };
-        drop(_lock);
-        HyperCallResult::Ok(0)
+        if let Err(e) = wake_boot_cpu(zone_id, boot_cpu, share_cpu) {
+            // nothing ran in the zone yet, take it down with its resources
+            zone_shutdown(zone)?;
+            return Err(e);
+        }
+        zone.write().set_state(ZoneState::Running)?;
+        HyperCallResult::Ok(zone_id)
     }
 
     fn hv_zone_shutdown(&mut self, zone_id: u64) -> HyperCallResult {
@@ -170,22 +191,115 @@ impl<'a> HyperCall<'a> {
             Some(zone) => zone,
             _ => return hv_result_err!(EEXIST),
         };
-        let zone_r = zone.read();
+        zone_shutdown(zone)?;
+        HyperCallResult::Ok(0)
+    }
 
-        // // return zone's cpus to root_zone
-        zone_r.cpu_set.iter().for_each(|cpu_id| {
-            let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
-            get_cpu_data(cpu_id).zone = None;
-            get_cpu_data(cpu_id).cpu_on_entry = INVALID_ADDRESS;
-            send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
-        });
+    /// Copy at most `max_zones` [`HvZoneInfo`] entries to `buf`, an address of
+    /// the root zone. Return the number of zones, which may exceed `max_zones`.
+    fn hv_zone_list(&mut self, buf: u64, max_zones: u64) -> HyperCallResult {
+        info!("handle hvc zone list");
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "List zones over non-root zones: unsupported!");
+        }
+        let infos = zone_list_info();
+        let count = infos.len().min(max_zones as usize);
+        let bytes = unsafe {
+            core::slice::from_raw_parts(
+                infos.as_ptr() as *const u8,
+                count * size_of::<HvZoneInfo>(),
+            )
+        };
+        root_zone().read().copy_to_guest(buf as _, bytes)?;
+        HyperCallResult::Ok(infos.len())
+    }
 
-        zone_r.arch_irqchip_reset();
+    fn hv_zone_pause(&mut self, zone_id: u64) -> HyperCallResult {
+        info!("handle hvc zone pause, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Pause zone over non-root zones: unsupported!");
+        }
+        if zone_id == ROOT_ZONE_ID as u64 {
+            return hv_result_err!(EINVAL, "the root zone can't be paused");
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        zone.write().set_state(ZoneState::Paused)?;
+        zone_suspend(&zone);
+        HyperCallResult::Ok(0)
+    }
 
-        drop(zone_r);
-        drop(zone);
-        remove_zone(zone_id as _);
+    fn hv_zone_resume(&mut self, zone_id: u64) -> HyperCallResult {
+        info!("handle hvc zone resume, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Resume zone over non-root zones: unsupported!");
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        zone.write().set_state(ZoneState::Running)?;
+        zone.read().resume();
+        HyperCallResult::Ok(0)
+    }
+
+    fn hv_zone_reboot(&mut self, zone_id: u64) -> HyperCallResult {
+        info!("handle hvc zone reboot, id={}", zone_id);
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Reboot zone over non-root zones: unsupported!");
+        }
+        if zone_id == ROOT_ZONE_ID as u64 {
+            return hv_result_err!(EINVAL, "the root zone can't be rebooted");
+        }
+        let zone = match find_zone(zone_id as _) {
+            Some(zone) => zone,
+            _ => return hv_result_err!(ENOENT),
+        };
+        zone_reboot(&zone)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Send the `len` bytes at `buf` to the mailbox of `dst_zone`.
+    fn hv_msg_send(&mut self, dst_zone: u64, buf: u64, len: u64) -> HyperCallResult {
+        debug!("handle hvc msg send, dst={}, len={:#x}", dst_zone, len);
+        mailbox::send(&this_zone().read(), dst_zone as _, buf as _, len as _)?;
+        HyperCallResult::Ok(0)
+    }
+
+    /// Copy the oldest message of the calling zone to `buf`, of `len` bytes.
+    /// Return the sender in the upper 32 bits and the size in the lower ones.
+    fn hv_msg_recv(&mut self, buf: u64, len: u64) -> HyperCallResult {
+        debug!("handle hvc msg recv, len={:#x}", len);
+        let (src, size) = mailbox::recv(&this_zone().read(), buf as _, len as _)?;
+        HyperCallResult::Ok((src << 32) | size)
+    }
 
+    /// Write the core dumps of failed zones to the `size` bytes of the root
+    /// zone at `buf`, and raise `irq` in it after each one.
+    fn hv_coredump_setup(&mut self, buf: u64, size: u64, irq: u64) -> HyperCallResult {
+        info!("handle hvc coredump setup, buf={:#x}, size={:#x}, irq={}", buf, size, irq);
+        if !is_this_root_zone() {
+            return hv_result_err!(EPERM, "Core dump setup over non-root zones: unsupported!");
+        }
+        coredump::setup(&this_zone().read(), buf as _, size as _, irq as _)?;
         HyperCallResult::Ok(0)
     }
 }
+
+/// Start the boot cpu of the zone `zone_id` just created.
+fn wake_boot_cpu(zone_id: usize, boot_cpu: usize, share_cpu: bool) -> HvResult {
+    if share_cpu {
+        return scheduler::wake_vcpu(zone_id, boot_cpu);
+    }
+    let target_data = get_cpu_data(boot_cpu as _);
+    let _lock = target_data.ctrl_lock.lock();
+    if target_data.arch_cpu.psci_on {
+        error!("hv_zone_start: cpu {} already on", boot_cpu);
+        return hv_result_err!(EBUSY);
+    }
+    target_data.arch_cpu.psci_on_pending = true;
+    send_event(boot_cpu, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
+    Ok(())
+}
@@ -0,0 +1,169 @@
+//! ELF64 executables, for bare-metal payloads.
+//!
+//! The `PT_LOAD` segments are copied to their physical address in the zone,
+//! the rest of their memory is zeroed, and the boot cpu enters at `e_entry`.
+
+use super::{read_u16, read_u32, read_u64};
+use crate::config::{HvZoneConfig, HV_CONFIG_IMAGE_ENTRY};
+use crate::error::HvResult;
+use crate::memory::MemFlags;
+
+pub const ELF_MAGIC: &[u8] = b"\x7fELF";
+pub const ELFCLASS64: u8 = 2;
+pub const ELFDATA2LSB: u8 = 1;
+const ET_EXEC: u16 = 2;
+// the host-side tests check aarch64 images
+#[cfg(any(target_arch = "aarch64", test))]
+pub const EM_HOST: u16 = 183; // EM_AARCH64
+#[cfg(target_arch = "riscv64")]
+pub const EM_HOST: u16 = 243; // EM_RISCV
+pub const ELF64_EHDR_SIZE: usize = 64;
+pub const ELF64_PHDR_SIZE: usize = 56;
+pub const PT_LOAD: u32 = 1;
+pub const PF_X: u32 = 1 << 0;
+
+pub struct Elf64<'a> {
+    image: &'a [u8],
+    pub entry: u64,
+    phoff: usize,
+    phnum: usize,
+    phentsize: usize,
+}
+
+/// A `PT_LOAD` segment.
+pub struct Segment<'a> {
+    pub ipa: usize,
+    /// Bytes of the segment in the file.
+    pub data: &'a [u8],
+    /// Bytes zeroed after `data`.
+    pub bss: usize,
+    pub executable: bool,
+}
+
+impl<'a> Elf64<'a> {
+    /// Read the headers of `image`, if it's an ELF file.
+    pub fn parse(image: &'a [u8]) -> HvResult<Option<Self>> {
+        if !image.starts_with(ELF_MAGIC) {
+            return Ok(None);
+        }
+        if image.len() < ELF64_EHDR_SIZE || image[4] != ELFCLASS64 || image[5] != ELFDATA2LSB {
+            return hv_result_err!(EINVAL, "zone image is not a little-endian ELF64 file");
+        }
+        let (e_type, e_machine) = (read_u16(image, 0x10), read_u16(image, 0x12));
+        if e_type != ET_EXEC || e_machine != EM_HOST {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "zone image is an ELF file of type {} for machine {}, not an executable for this one",
+                    e_type, e_machine
+                )
+            );
+        }
+        let elf = Self {
+            image,
+            entry: read_u64(image, 0x18),
+            phoff: read_u64(image, 0x20) as usize,
+            phentsize: read_u16(image, 0x36) as usize,
+            phnum: read_u16(image, 0x38) as usize,
+        };
+        let phdrs_end = elf
+            .phnum
+            .checked_mul(elf.phentsize)
+            .and_then(|size| size.checked_add(elf.phoff));
+        if elf.phentsize < ELF64_PHDR_SIZE || !matches!(phdrs_end, Some(end) if end <= image.len())
+        {
+            return hv_result_err!(EINVAL, "bad ELF program headers in zone image");
+        }
+        for i in 0..elf.phnum {
+            let phdr = elf.phdr(i);
+            if read_u32(phdr, 0) != PT_LOAD {
+                continue;
+            }
+            let (offset, paddr) = (read_u64(phdr, 0x8), read_u64(phdr, 0x18));
+            let (filesz, memsz) = (read_u64(phdr, 0x20), read_u64(phdr, 0x28));
+            let in_file =
+                matches!(offset.checked_add(filesz), Some(end) if end <= image.len() as u64);
+            if !in_file || filesz > memsz || paddr.checked_add(memsz).is_none() {
+                return hv_result_err!(EINVAL, format!("bad ELF segment {} in zone image", i));
+            }
+            // one segment would overwrite the other when loaded
+            let overlapped = (0..i).map(|j| elf.phdr(j)).any(|other| {
+                let start = read_u64(other, 0x18);
+                read_u32(other, 0) == PT_LOAD
+                    && start < paddr + memsz
+                    && paddr < start + read_u64(other, 0x28)
+            });
+            if overlapped {
+                return hv_result_err!(
+                    EINVAL,
+                    format!("ELF segment {} overlaps an earlier one in zone image", i)
+                );
+            }
+        }
+        Ok(Some(elf))
+    }
+
+    /// Program header `i`, its bounds checked by `parse`.
+    fn phdr(&self, i: usize) -> &'a [u8] {
+        &self.image[self.phoff + i * self.phentsize..]
+    }
+
+    /// The whole file.
+    pub fn image(&self) -> &'a [u8] {
+        self.image
+    }
+
+    /// The `PT_LOAD` segments, once `parse` checked them.
+    pub fn segments(&self) -> impl Iterator<Item = Segment<'a>> + '_ {
+        (0..self.phnum)
+            .map(|i| self.phdr(i))
+            .filter(|phdr| read_u32(phdr, 0) == PT_LOAD && read_u64(phdr, 0x28) != 0)
+            .map(|phdr| {
+                let offset = read_u64(phdr, 0x8) as usize;
+                let filesz = read_u64(phdr, 0x20) as usize;
+                Segment {
+                    ipa: read_u64(phdr, 0x18) as usize,
+                    data: &self.image[offset..offset + filesz],
+                    bss: read_u64(phdr, 0x28) as usize - filesz,
+                    executable: read_u32(phdr, 0x4) & PF_X != 0,
+                }
+            })
+    }
+
+    /// Check that every segment lies in the memory of the zone described by
+    /// `config`, the executable ones in executable memory.
+    pub fn check(&self, config: &HvZoneConfig) -> HvResult {
+        for segment in self.segments() {
+            let size = (segment.data.len() + segment.bss) as u64;
+            let region = match config.memory_region_of(segment.ipa as _, size) {
+                Some(region) => region,
+                None => {
+                    return hv_result_err!(
+                        EINVAL,
+                        format!(
+                            "ELF segment of {:#x} bytes at {:#x} is outside the zone memory",
+                            size, segment.ipa
+                        )
+                    )
+                }
+            };
+            if segment.executable && !region.flags().contains(MemFlags::EXECUTE) {
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "executable ELF segment at {:#x} in {:#x?}",
+                        segment.ipa, region
+                    )
+                );
+            }
+        }
+        config.check_image_placement(self.entry, 0)?;
+        if config.entry_point != HV_CONFIG_IMAGE_ENTRY && config.entry_point != self.entry {
+            warn!(
+                "zone entry point {:#x} ignored, the ELF entry is {:#x}",
+                config.entry_point, self.entry
+            );
+        }
+        Ok(())
+    }
+}
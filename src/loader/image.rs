@@ -0,0 +1,88 @@
+//! The Linux arm64 `Image` header, see the kernel's
+//! `Documentation/arch/arm64/booting.rst`.
+//!
+//! The image must sit `text_offset` bytes above a 2 MiB boundary of RAM, and
+//! `image_size` bytes from there, BSS included, are used by the kernel. The
+//! kernel is entered at its first byte.
+
+use super::{read_u32, read_u64};
+use crate::config::{HvZoneConfig, HV_CONFIG_IMAGE_ENTRY};
+use crate::error::HvResult;
+use crate::memory::MemFlags;
+
+const ARM64_IMAGE_MAGIC: u32 = 0x644d_5241; // "ARM\x64"
+const ARM64_IMAGE_HEADER_SIZE: usize = 64;
+const ARM64_IMAGE_ALIGN: u64 = 0x20_0000;
+/// `text_offset` of kernels older than 3.17, whose `image_size` is 0.
+const ARM64_LEGACY_TEXT_OFFSET: u64 = 0x8_0000;
+const ARM64_IMAGE_FLAG_BE: u64 = 1 << 0;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Arm64Image {
+    pub text_offset: u64,
+    /// Memory used by the kernel, 0 if unknown.
+    pub image_size: u64,
+    pub flags: u64,
+}
+
+impl Arm64Image {
+    /// Read the header of `image`, if it's an arm64 `Image`.
+    pub fn parse(image: &[u8]) -> Option<Self> {
+        if image.len() < ARM64_IMAGE_HEADER_SIZE || read_u32(image, 0x38) != ARM64_IMAGE_MAGIC {
+            return None;
+        }
+        let image_size = read_u64(image, 0x10);
+        Some(Self {
+            text_offset: match image_size {
+                0 => ARM64_LEGACY_TEXT_OFFSET,
+                _ => read_u64(image, 0x8),
+            },
+            image_size,
+            flags: read_u64(image, 0x18),
+        })
+    }
+
+    /// Check the placement of the image, of `file_size` bytes, in the memory
+    /// of the zone described by `config` and return its entry point. Without
+    /// a configured entry point, the image goes to the first 2 MiB boundary
+    /// of the lowest executable memory region.
+    pub fn entry(&self, config: &HvZoneConfig, file_size: usize) -> HvResult<u64> {
+        if self.flags & ARM64_IMAGE_FLAG_BE != 0 {
+            return hv_result_err!(EINVAL, "big-endian arm64 Image unsupported");
+        }
+        let size = self.image_size.max(file_size as u64);
+        let entry = match config.entry_point {
+            HV_CONFIG_IMAGE_ENTRY => {
+                let base = config
+                    .memory_regions()
+                    .iter()
+                    .filter(|region| region.flags().contains(MemFlags::EXECUTE))
+                    .map(|region| region.ipa)
+                    .min();
+                match base {
+                    Some(base) => {
+                        ((base + ARM64_IMAGE_ALIGN - 1) & !(ARM64_IMAGE_ALIGN - 1))
+                            + self.text_offset
+                    }
+                    None => return hv_result_err!(EINVAL, "zone has no executable memory"),
+                }
+            }
+            entry => entry,
+        };
+        if entry < self.text_offset || (entry - self.text_offset) % ARM64_IMAGE_ALIGN != 0 {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "arm64 Image at {:#x} is not {:#x} bytes above a 2 MiB boundary",
+                    entry, self.text_offset
+                )
+            );
+        }
+        config.check_image_placement(entry, size)?;
+        info!(
+            "arm64 Image at {:#x}, text_offset {:#x}, {:#x} bytes",
+            entry, self.text_offset, size
+        );
+        Ok(entry)
+    }
+}
@@ -0,0 +1,151 @@
+//! Recognition and loading of the zone images passed with `HvZoneStart`.
+//!
+//! The root zone hands over the image as it is in a file. A raw binary is
+//! loaded at the configured `entry_point`, a known format is checked against
+//! the memory of the zone first and may supply the entry point itself.
+
+pub mod elf;
+pub mod image;
+
+use crate::config::{HvZoneConfig, HV_CONFIG_IMAGE_ENTRY};
+use crate::error::HvResult;
+use crate::zone::Zone;
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap())
+}
+
+/// Check that the `len` bytes at `ipa` in `zone` don't overlap `src`, the
+/// image they're copied from. With `in_place`, `src` may be exactly at `ipa`
+/// already, in which case `true` is returned.
+fn check_overlap(
+    zone: &Zone,
+    ipa: usize,
+    len: usize,
+    src: &[u8],
+    in_place: bool,
+) -> HvResult<bool> {
+    let src_start = src.as_ptr() as usize;
+    let src_end = src_start + src.len();
+    let (mut done, mut placed) = (0, 0);
+    while done < len {
+        let (pa, _, page_size) = unsafe { zone.gpm.page_table_query(ipa + done)? };
+        let chunk = (page_size as usize - page_size.page_offset(ipa + done)).min(len - done);
+        if pa < src_end && src_start < pa + chunk {
+            if !in_place || pa != src_start + done {
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "zone image at {:#x?} overlaps its destination ipa {:#x}",
+                        src_start..src_end,
+                        ipa + done
+                    )
+                );
+            }
+            placed += chunk;
+        }
+        done += chunk;
+    }
+    match placed {
+        0 => Ok(false),
+        _ if placed == len => Ok(true),
+        _ => hv_result_err!(EINVAL, "zone image is only partly at its ipa"),
+    }
+}
+
+pub enum ZoneImage<'a> {
+    /// Copied as is to `entry`, an arm64 `Image` included.
+    Raw {
+        entry: u64,
+        data: &'a [u8],
+    },
+    Elf(elf::Elf64<'a>),
+}
+
+impl<'a> ZoneImage<'a> {
+    /// Recognize `image`, the image of the zone described by `config`, and
+    /// check where it goes.
+    pub fn parse(config: &HvZoneConfig, image: &'a [u8]) -> HvResult<Self> {
+        if let Some(elf) = elf::Elf64::parse(image)? {
+            elf.check(config)?;
+            return Ok(Self::Elf(elf));
+        }
+        let entry = match image::Arm64Image::parse(image) {
+            Some(header) => header.entry(config, image.len())?,
+            None if config.entry_point == HV_CONFIG_IMAGE_ENTRY => {
+                return hv_result_err!(
+                    EINVAL,
+                    "zone image has no header to take the entry point from"
+                )
+            }
+            None => {
+                config.check_image_placement(config.entry_point, image.len() as _)?;
+                config.entry_point
+            }
+        };
+        Ok(Self::Raw { entry, data: image })
+    }
+
+    /// Where the boot cpu enters the guest.
+    pub fn entry(&self) -> u64 {
+        match self {
+            Self::Raw { entry, .. } => *entry,
+            Self::Elf(elf) => elf.entry,
+        }
+    }
+
+    /// Copy the image into the memory of `zone`.
+    pub fn load(&self, zone: &Zone) -> HvResult {
+        match self {
+            Self::Raw { data, .. } if data.is_empty() => Ok(()),
+            Self::Raw { entry, data } => {
+                if !check_overlap(zone, *entry as _, data.len(), data, true)? {
+                    info!(
+                        "copy zone image {:#x?} to ipa {:#x}",
+                        data.as_ptr_range(),
+                        entry
+                    );
+                    zone.copy_to_guest(*entry as _, data)?;
+                }
+                Ok(())
+            }
+            Self::Elf(elf) => {
+                // the headers and later segments are read after each copy
+                for segment in elf.segments() {
+                    let len = segment.data.len() + segment.bss;
+                    check_overlap(zone, segment.ipa, len, elf.image(), false)?;
+                }
+                for segment in elf.segments() {
+                    info!(
+                        "load ELF segment of {:#x} bytes to ipa {:#x}, {:#x} bytes zeroed",
+                        segment.data.len(),
+                        segment.ipa,
+                        segment.bss
+                    );
+                    zone.copy_to_guest(segment.ipa, segment.data)?;
+                    zone.zero_guest(segment.ipa + segment.data.len(), segment.bss)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Keep a copy of the image in `zone`, to restore on reboot.
+    pub fn save(&self, zone: &mut Zone) -> HvResult {
+        match self {
+            Self::Raw { data, .. } if data.is_empty() => Ok(()),
+            Self::Raw { entry, data } => zone.save_boot_image(*entry as _, data, 0),
+            Self::Elf(elf) => elf.segments().try_for_each(|segment| {
+                zone.save_boot_image(segment.ipa, segment.data, segment.bss)
+            }),
+        }
+    }
+}
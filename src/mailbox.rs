@@ -0,0 +1,112 @@
+//! Messages between zones.
+//!
+//! `HvMsgSend` copies up to a page from the sender into the mailbox of the
+//! receiver, held by the hypervisor, and raises the message irq of the
+//! receiver if it configured one. `HvMsgRecv` copies the oldest message out.
+//! A zone may only send to the zones in its `msg_peers`, the root zone may
+//! send to any zone.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+use crate::consts::{PAGE_SIZE, ROOT_ZONE_ID};
+use crate::error::HvResult;
+use crate::event::send_zone_irq;
+use crate::memory::GuestPhysAddr;
+use crate::zone::{find_zone, Zone};
+
+/// Largest message, in bytes.
+pub const MSG_MAX_SIZE: usize = PAGE_SIZE;
+/// Messages a mailbox holds before senders get `EBUSY`.
+const MAILBOX_DEPTH: usize = 8;
+
+struct Message {
+    src: usize,
+    data: Vec<u8>,
+}
+
+/// Mailbox of every zone, by zone id.
+static MAILBOXES: Mutex<BTreeMap<usize, VecDeque<Message>>> = Mutex::new(BTreeMap::new());
+
+pub fn create(zone_id: usize) {
+    MAILBOXES.lock().insert(zone_id, VecDeque::new());
+}
+
+pub fn release(zone_id: usize) {
+    MAILBOXES.lock().remove(&zone_id);
+}
+
+/// Drop the messages waiting for `zone_id`.
+pub fn flush(zone_id: usize) {
+    if let Some(mailbox) = MAILBOXES.lock().get_mut(&zone_id) {
+        mailbox.clear();
+    }
+}
+
+fn may_send(src: &Zone, dst: usize) -> bool {
+    src.id == ROOT_ZONE_ID || (dst < 64 && src.msg_peers & (1 << dst) != 0)
+}
+
+/// Queue the `len` bytes at `buf` in `src` for the zone `dst`.
+pub fn send(src: &Zone, dst: usize, buf: GuestPhysAddr, len: usize) -> HvResult {
+    if len > MSG_MAX_SIZE {
+        return hv_result_err!(
+            E2BIG,
+            format!("message of {:#x} bytes, at most {:#x}", len, MSG_MAX_SIZE)
+        );
+    }
+    if !may_send(src, dst) {
+        return hv_result_err!(
+            EPERM,
+            format!("zone {} may not send messages to zone {}", src.id, dst)
+        );
+    }
+    let irq = match find_zone(dst) {
+        Some(zone) => zone.read().msg_irq,
+        None => return hv_result_err!(ENOENT, format!("no zone {}", dst)),
+    };
+    let mut data = vec![0; len];
+    src.copy_from_guest(buf, &mut data)?;
+
+    let mut mailboxes = MAILBOXES.lock();
+    let mailbox = match mailboxes.get_mut(&dst) {
+        Some(mailbox) => mailbox,
+        None => return hv_result_err!(ENOENT, format!("no zone {}", dst)),
+    };
+    if mailbox.len() >= MAILBOX_DEPTH {
+        return hv_result_err!(EBUSY, format!("mailbox of zone {} is full", dst));
+    }
+    mailbox.push_back(Message { src: src.id, data });
+    drop(mailboxes);
+
+    trace!("zone {} sent {:#x} bytes to zone {}", src.id, len, dst);
+    if irq != 0 {
+        send_zone_irq(dst, irq as _);
+    }
+    Ok(())
+}
+
+/// Copy the oldest message of `dst` to `buf`, of `len` bytes, and return the
+/// sender and the size of the message. A message larger than `len` is left in
+/// the mailbox.
+pub fn recv(dst: &Zone, buf: GuestPhysAddr, len: usize) -> HvResult<(usize, usize)> {
+    let mut mailboxes = MAILBOXES.lock();
+    let mailbox = match mailboxes.get_mut(&dst.id) {
+        Some(mailbox) => mailbox,
+        None => return hv_result_err!(ENOENT, format!("zone {} has no mailbox", dst.id)),
+    };
+    let msg = match mailbox.front() {
+        Some(msg) => msg,
+        None => return hv_result_err!(ENOENT),
+    };
+    if msg.data.len() > len {
+        return hv_result_err!(
+            E2BIG,
+            format!("message of {:#x} bytes, buffer of {:#x}", msg.data.len(), len)
+        );
+    }
+    dst.copy_to_guest(buf, &msg.data)?;
+    let msg = mailbox.pop_front().unwrap();
+    Ok((msg.src, msg.data.len()))
+}
@@ -13,6 +13,7 @@
 #![feature(asm_const)]
 #![feature(naked_functions)] //  surpport naked function
 #![feature(core_panic)]
+#![feature(inline_const)]
 // #![deny(warnings, missing_docs)]
 #[macro_use]
 extern crate alloc;
@@ -26,20 +27,29 @@ extern crate lazy_static;
 #[macro_use]
 mod logging;
 mod arch;
+mod config;
 mod consts;
+mod control;
+mod coredump;
 mod device;
+mod dtb;
 mod event;
 mod hypercall;
+mod loader;
+mod mailbox;
 mod memory;
 mod panic;
 mod percpu;
 mod platform;
+mod resource;
+mod scheduler;
+mod symbols;
 mod zone;
 
 use crate::arch::mm::setup_parange;
-use crate::consts::{DTB_IPA, MAX_CPU_NUM};
-use crate::platform::qemu_aarch64::ROOT_ZONE_DTB_ADDR;
-use crate::zone::zone_create;
+use crate::consts::{DTB_IPA, MAX_CPU_NUM, ROOT_ZONE_ID};
+use crate::platform::ROOT_ZONE_DTB_ADDR;
+use crate::zone::{zone_create, ZoneState};
 use arch::{cpu::cpu_start, entry::arch_entry};
 use core::sync::atomic::{AtomicI32, AtomicU32, Ordering};
 use percpu::PerCpu;
@@ -102,8 +112,10 @@ fn primary_init_early(dtb: usize) {
 
     device::irqchip::primary_init_early(&host_fdt);
     crate::arch::mm::init_hv_page_table(&host_fdt).unwrap();
+    resource::init();
 
-    zone_create(0, ROOT_ZONE_DTB_ADDR as _, DTB_IPA).unwrap();
+    let root_zone = zone_create(ROOT_ZONE_ID, ROOT_ZONE_DTB_ADDR as _, DTB_IPA).unwrap();
+    root_zone.write().set_state(ZoneState::Running).unwrap();
     INIT_EARLY_OK.store(1, Ordering::Release);
 }
 
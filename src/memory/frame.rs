@@ -6,7 +6,7 @@ use bitmap_allocator::BitAlloc;
 use spin::Mutex;
 
 use super::addr::{align_down, align_up, is_aligned, PhysAddr};
-use crate::consts::PAGE_SIZE;
+use crate::consts::{GUEST_MEM_POOL_SIZE, PAGE_SIZE};
 use crate::error::HvResult;
 
 // Support max 1M * 4096 = 1GB memory.
@@ -14,6 +14,7 @@ type FrameAlloc = bitmap_allocator::BitAlloc1M;
 
 struct FrameAllocator {
     base: PhysAddr,
+    size: usize,
     inner: FrameAlloc,
 }
 
@@ -26,20 +27,29 @@ pub struct Frame {
 
 static FRAME_ALLOCATOR: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::empty());
 
+/// Backs the RAM of zones which don't bring their own physical memory.
+static GUEST_FRAME_ALLOCATOR: Mutex<FrameAllocator> = Mutex::new(FrameAllocator::empty());
+
 impl FrameAllocator {
     const fn empty() -> Self {
         Self {
             base: 0,
+            size: 0,
             inner: FrameAlloc::DEFAULT,
         }
     }
 
     fn init(&mut self, base: PhysAddr, size: usize) {
         self.base = align_up(base);
-        let page_count = align_up(size) / PAGE_SIZE;
+        self.size = align_up(size);
+        let page_count = self.size / PAGE_SIZE;
         self.inner.insert(0..page_count);
     }
 
+    fn contains(&self, paddr: PhysAddr) -> bool {
+        (self.base..self.base + self.size).contains(&paddr)
+    }
+
     /// # Safety
     ///
     /// This function is unsafe because you need to deallocate manually.
@@ -127,6 +137,23 @@ impl Frame {
         }
     }
 
+    /// Allocate contiguous physical frames from the guest memory pool.
+    pub fn new_guest(frame_count: usize, align_log2: usize) -> HvResult<Self> {
+        unsafe {
+            GUEST_FRAME_ALLOCATOR
+                .lock()
+                .alloc_contiguous(frame_count, align_log2)
+                .map(|start_paddr| Self {
+                    start_paddr,
+                    frame_count,
+                })
+                .ok_or(hv_err!(
+                    ENOMEM,
+                    format!("guest memory pool can't provide {} frames", frame_count)
+                ))
+        }
+    }
+
     /// Constructs a frame from a raw physical address without automatically calling the destructor.
     ///
     /// # Safety
@@ -212,13 +239,16 @@ impl Frame {
 
 impl Drop for Frame {
     fn drop(&mut self) {
+        let mut allocator = if GUEST_FRAME_ALLOCATOR.lock().contains(self.start_paddr) {
+            GUEST_FRAME_ALLOCATOR.lock()
+        } else {
+            FRAME_ALLOCATOR.lock()
+        };
         unsafe {
             match self.frame_count {
                 0 => {} // Do not deallocate when use Frame::from_paddr()
-                1 => FRAME_ALLOCATOR.lock().dealloc(self.start_paddr),
-                _ => FRAME_ALLOCATOR
-                    .lock()
-                    .dealloc_contiguous(self.start_paddr, self.frame_count),
+                1 => allocator.dealloc(self.start_paddr),
+                _ => allocator.dealloc_contiguous(self.start_paddr, self.frame_count),
             }
         }
     }
@@ -235,6 +265,15 @@ pub fn init() {
         "Frame allocator initialization finished: {:#x?}",
         mem_pool_start..mem_pool_end
     );
+
+    let guest_pool_start = crate::consts::guest_mem_pool_start();
+    GUEST_FRAME_ALLOCATOR
+        .lock()
+        .init(guest_pool_start, GUEST_MEM_POOL_SIZE);
+    info!(
+        "Guest frame allocator initialization finished: {:#x?}",
+        guest_pool_start..guest_pool_start + GUEST_MEM_POOL_SIZE
+    );
 }
 
 pub fn test() {
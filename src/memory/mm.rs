@@ -119,6 +119,10 @@ where
         }
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = &MemoryRegion<PT::VA>> {
+        self.regions.values()
+    }
+
     pub fn clear(&mut self) {
         for region in self.regions.values() {
             self.pt.unmap(region).unwrap();
@@ -0,0 +1,140 @@
+//! RAM layout described by a device tree.
+
+use alloc::vec::Vec;
+use core::ops::Range;
+
+use super::addr::{align_down, align_up};
+use super::MemFlags;
+use crate::error::HvResult;
+
+#[derive(Debug, Clone)]
+pub struct RamRegion {
+    pub range: Range<usize>,
+    pub flags: MemFlags,
+}
+
+fn reg_ranges<'a>(node: fdt::node::FdtNode<'_, 'a>) -> impl Iterator<Item = Range<usize>> + 'a {
+    node.reg().into_iter().flatten().filter_map(|reg| {
+        let start = reg.starting_address as usize;
+        reg.size.map(|size| start..start + size)
+    })
+}
+
+/// Remove `hole` from `range`, leaving at most two pieces.
+fn cut(range: Range<usize>, hole: &Range<usize>) -> impl Iterator<Item = Range<usize>> {
+    let below = range.start..range.end.min(hole.start);
+    let above = range.start.max(hole.end)..range.end;
+    [below, above].into_iter().filter(|r| !r.is_empty())
+}
+
+/// Sort `ranges` and merge the ones that overlap or touch.
+fn merge(mut ranges: Vec<Range<usize>>) -> Vec<Range<usize>> {
+    ranges.sort_unstable_by_key(|r| r.start);
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges.into_iter().filter(|r| !r.is_empty()) {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Cut `hole`, widened to whole pages, out of `regions`.
+pub fn remove_range(regions: Vec<RamRegion>, hole: &Range<usize>) -> Vec<RamRegion> {
+    let hole = align_down(hole.start)..align_up(hole.end);
+    regions
+        .into_iter()
+        .flat_map(|region| {
+            cut(region.range, &hole).map(move |range| RamRegion {
+                range,
+                flags: region.flags,
+            })
+        })
+        .collect()
+}
+
+/// Fail if a `no-map` child of `/reserved-memory` overlaps `range`, which
+/// must stay mapped.
+pub fn check_no_map(fdt: &fdt::Fdt, range: Range<usize>) -> HvResult {
+    let reserved = match fdt.find_node("/reserved-memory") {
+        Some(reserved) => reserved,
+        None => return Ok(()),
+    };
+    for child in reserved.children() {
+        if child.property("no-map").is_none() {
+            continue;
+        }
+        for hole in reg_ranges(child) {
+            if hole.start < range.end && range.start < hole.end {
+                return hv_result_err!(
+                    EINVAL,
+                    format!(
+                        "no-map reserved-memory {} at {:#x?} overlaps {:#x?}",
+                        child.name, hole, range
+                    )
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Collect every reg tuple of every memory node of `fdt`, sorted and with
+/// overlapping banks merged.
+///
+/// Children of `/reserved-memory` with `no-map` are cut out as holes. The other
+/// reserved regions stay mapped, and their parts lying outside of the memory
+/// nodes are returned as shared regions without execute permission.
+pub fn fdt_ram_regions(fdt: &fdt::Fdt) -> Vec<RamRegion> {
+    let mut ram = merge(
+        fdt.all_nodes()
+            .filter(|node| node.property("device_type").and_then(|p| p.as_str()) == Some("memory"))
+            .flat_map(reg_ranges)
+            .collect(),
+    );
+    let mut shared = Vec::new();
+
+    if let Some(reserved) = fdt.find_node("/reserved-memory") {
+        for child in reserved.children() {
+            let no_map = child.property("no-map").is_some();
+            for range in reg_ranges(child) {
+                let range = align_down(range.start)..align_up(range.end);
+                if no_map {
+                    debug!("reserved-memory {}: no-map hole {:#x?}", child.name, range);
+                    ram = ram.into_iter().flat_map(|r| cut(r, &range)).collect();
+                } else {
+                    // only the part outside of the memory nodes is added
+                    let mut outside = vec![range];
+                    for r in &ram {
+                        outside = outside.into_iter().flat_map(|o| cut(o, r)).collect();
+                    }
+                    for range in outside {
+                        let range = align_down(range.start)..align_up(range.end);
+                        debug!(
+                            "reserved-memory {}: shared region {:#x?}",
+                            child.name, range
+                        );
+                        shared.push(range);
+                    }
+                }
+            }
+        }
+    }
+
+    let mut regions: Vec<RamRegion> = ram
+        .into_iter()
+        .map(|r| align_up(r.start)..align_down(r.end))
+        .filter(|r| !r.is_empty())
+        .map(|range| RamRegion {
+            range,
+            flags: MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE,
+        })
+        .chain(merge(shared).into_iter().map(|range| RamRegion {
+            range,
+            flags: MemFlags::READ | MemFlags::WRITE,
+        }))
+        .collect();
+    regions.sort_unstable_by_key(|region| region.range.start);
+    regions
+}
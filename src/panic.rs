@@ -1,7 +1,80 @@
+//! Hypervisor panics.
+//!
+//! The first cpu to panic stops the others with a stop SGI: each one prints
+//! its registers and zone, one cpu after the other, and halts. The panicking
+//! cpu waits for them, then halts the board, or resets it if built with the
+//! `panic_reset` feature. A cpu stuck in the hypervisor with its irqs masked
+//! doesn't take the SGI, it's reported once `STOP_TIMEOUT_MS` elapsed.
+
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::arch::cpu::this_cpu_id;
+use crate::arch::panic::{current_time_ms, dump_cpu_state, halt, reset_board, send_stop};
+use crate::consts::MAX_CPU_NUM;
+use crate::percpu::PerCpu;
+
+const NO_CPU: usize = usize::MAX;
+const STOP_TIMEOUT_MS: u64 = 1000;
+
+/// The cpu which panicked first.
+static PANIC_CPU: AtomicUsize = AtomicUsize::new(NO_CPU);
+/// Cpus which printed their state and halted.
+static STOPPED_CPUS: AtomicU32 = AtomicU32::new(0);
+/// Keeps the report of each cpu in one piece.
+static REPORT_LOCK: Mutex<()> = Mutex::new(());
+
+/// Whether a cpu panicked, the others having to stop.
+pub fn panicking() -> bool {
+    PANIC_CPU.load(Ordering::Acquire) != NO_CPU
+}
+
+/// Called on a cpu which took the stop SGI, or panicked itself, while the
+/// panic of another cpu is reported.
+pub fn stop_this_cpu() -> ! {
+    let report = REPORT_LOCK.lock();
+    dump_cpu_state();
+    drop(report);
+    STOPPED_CPUS.fetch_add(1, Ordering::AcqRel);
+    halt()
+}
 
 #[panic_handler]
 fn on_panic(info: &PanicInfo) -> ! {
-    error!("panic occured {:#?}", info);
-    loop {}
+    let cpu = this_cpu_id();
+    if let Err(first) = PANIC_CPU.compare_exchange(NO_CPU, cpu, Ordering::AcqRel, Ordering::Acquire)
+    {
+        if first == cpu {
+            // a panic while reporting the panic
+            halt();
+        }
+        stop_this_cpu();
+    }
+
+    error!("panic occured on cpu {}: {}", cpu, info);
+    (0..MAX_CPU_NUM)
+        .filter(|&other| other != cpu)
+        .for_each(send_stop);
+    let report = REPORT_LOCK.lock();
+    dump_cpu_state();
+    drop(report);
+
+    let others = PerCpu::entered_cpus().saturating_sub(1);
+    let deadline = current_time_ms() + STOP_TIMEOUT_MS;
+    while STOPPED_CPUS.load(Ordering::Acquire) < others && current_time_ms() < deadline {
+        core::hint::spin_loop();
+    }
+    let _report = REPORT_LOCK.lock();
+    let stopped = STOPPED_CPUS.load(Ordering::Acquire);
+    if stopped < others {
+        error!("{} of {} other cpus didn't stop", others - stopped, others);
+    }
+    if cfg!(feature = "panic_reset") {
+        error!("resetting the board");
+        reset_board()
+    } else {
+        error!("halted");
+        halt()
+    }
 }
@@ -7,7 +7,7 @@ use crate::memory::addr::VirtAddr;
 use crate::zone::Zone;
 use crate::ENTERED_CPUS;
 use core::fmt::Debug;
-use core::sync::atomic::Ordering;
+use core::sync::atomic::{AtomicBool, Ordering};
 
 // global_asm!(include_str!("./arch/aarch64/page_table.S"),);
 
@@ -19,6 +19,17 @@ pub struct PerCpu {
     pub zone: Option<Arc<RwLock<Zone>>>,
     pub ctrl_lock: Mutex<()>,
     pub boot_cpu: bool,
+    /// Set by the cpu pausing this one, cleared to resume it.
+    pub need_suspend: AtomicBool,
+    /// Whether this cpu is held in EL2 by a pause.
+    pub suspended: AtomicBool,
+    /// Restart the zone when leaving the pause.
+    pub need_reset: AtomicBool,
+    /// Turn the vcpu off when leaving the pause, until PSCI CPU_ON.
+    pub need_park: AtomicBool,
+    /// Cleared by the cpu shutting down the zone of this one, set once this
+    /// cpu has left the guest for good.
+    pub left_zone: AtomicBool,
     // percpu stack
 }
 
@@ -34,6 +45,11 @@ impl PerCpu {
                 zone: None,
                 ctrl_lock: Mutex::new(()),
                 boot_cpu: false,
+                need_suspend: AtomicBool::new(false),
+                suspended: AtomicBool::new(false),
+                need_reset: AtomicBool::new(false),
+                need_park: AtomicBool::new(false),
+                left_zone: AtomicBool::new(true),
             })
         }
         #[cfg(target_arch = "riscv64")]
@@ -109,6 +125,17 @@ impl CpuSet {
     pub fn iter<'a>(&'a self) -> impl Iterator<Item = usize> + 'a {
         (0..=self.max_cpu_id).filter(move |&i| self.contains_cpu(i))
     }
+    /// Index of `id` among the cpus of the set, the cpu id seen by the guest.
+    pub fn vcpu_id(&self, id: usize) -> Option<usize> {
+        match self.contains_cpu(id) {
+            true => Some(self.iter().take_while(|&i| i != id).count()),
+            false => None,
+        }
+    }
+    /// The cpu seen by the guest as `vcpu_id`.
+    pub fn cpu_of_vcpu(&self, vcpu_id: usize) -> Option<usize> {
+        self.iter().nth(vcpu_id)
+    }
     pub fn iter_except<'a>(&'a self, id: usize) -> impl Iterator<Item = usize> + 'a {
         (0..=self.max_cpu_id).filter(move |&i| self.contains_cpu(i) && i != id)
     }
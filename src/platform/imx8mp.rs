@@ -0,0 +1,115 @@
+// where the boot loader puts the root zone, in the DRAM at 0x40000000
+pub const ROOT_ZONE_DTB_ADDR: usize = 0xb0000000;
+pub const ROOT_ZONE_ENTRY: usize = 0xa0000000;
+
+use super::{BoardDevice, ZoneReservation};
+
+pub const BOARD_DEVICES: &[BoardDevice] = &[
+    BoardDevice {
+        name: "gpio",
+        paddr: 0x30200000,
+        size: 0x50000,
+        irqs: &[96, 97, 98, 99, 100, 101, 102, 103, 104, 105],
+    },
+    BoardDevice {
+        name: "iomuxc",
+        paddr: 0x30330000,
+        size: 0x10000,
+        irqs: &[],
+    },
+    BoardDevice {
+        name: "iomuxc-gpr",
+        paddr: 0x30340000,
+        size: 0x10000,
+        irqs: &[],
+    },
+    BoardDevice {
+        name: "ocotp-ctrl",
+        paddr: 0x30350000,
+        size: 0x10000,
+        irqs: &[],
+    },
+    BoardDevice {
+        name: "anatop",
+        paddr: 0x30360000,
+        size: 0x10000,
+        irqs: &[],
+    },
+    BoardDevice {
+        name: "snvs",
+        paddr: 0x30370000,
+        size: 0x10000,
+        // snvs-powerkey, snvs-rtc-l, caam_secvio
+        irqs: &[36, 51, 52],
+    },
+    BoardDevice {
+        name: "clock-controller",
+        paddr: 0x30380000,
+        size: 0x10000,
+        irqs: &[],
+    },
+    // the rest of the pinctrl & analog window
+    BoardDevice {
+        name: "analog",
+        paddr: 0x30390000,
+        size: 0x40000,
+        irqs: &[],
+    },
+    BoardDevice {
+        name: "bus@30400000",
+        paddr: 0x30400000,
+        size: 0x400000,
+        irqs: &[],
+    },
+    BoardDevice {
+        name: "serial@30860000",
+        paddr: 0x30860000,
+        size: 0x10000,
+        irqs: &[58],
+    },
+    BoardDevice {
+        name: "serial@30880000",
+        paddr: 0x30880000,
+        size: 0x10000,
+        irqs: &[60],
+    },
+    BoardDevice {
+        name: "serial@30890000",
+        paddr: 0x30890000,
+        size: 0x10000,
+        irqs: &[59, 43],
+    },
+    BoardDevice {
+        name: "bus@30a00000",
+        paddr: 0x30a00000,
+        size: 0x200000,
+        // i2c, mmc0-2, ethernet1, ethernet2
+        irqs: &[69, 54, 55, 56, 150, 151, 152, 166, 167],
+    },
+    BoardDevice {
+        name: "bus@30c00000",
+        paddr: 0x30c00000,
+        size: 0x400000,
+        irqs: &[],
+    },
+    BoardDevice {
+        name: "bus@32c00000",
+        paddr: 0x32c00000,
+        size: 0x40000,
+        irqs: &[],
+    },
+    BoardDevice {
+        name: "dma-apbh",
+        paddr: 0x33000000,
+        size: 0x2000,
+        irqs: &[],
+    },
+    BoardDevice {
+        name: "pmu",
+        paddr: 0,
+        size: 0,
+        irqs: &[34, 39],
+    },
+];
+
+pub const ZONE_RESERVATIONS: &[ZoneReservation] = &[];
@@ -1,5 +1,89 @@
 #[cfg(target_arch = "riscv64")]
 pub mod qemu_riscv64;
 
-#[cfg(target_arch = "aarch64")]
+#[cfg(all(feature = "platform_qemu", target_arch = "aarch64"))]
 pub mod qemu_aarch64;
+
+#[cfg(all(feature = "platform_imx8mp", target_arch = "aarch64"))]
+pub mod imx8mp;
+
+#[cfg(all(
+    target_arch = "aarch64",
+    not(any(feature = "platform_qemu", feature = "platform_imx8mp"))
+))]
+compile_error!("no platform selected, enable platform_qemu or platform_imx8mp");
+
+#[cfg(all(
+    target_arch = "aarch64",
+    feature = "platform_qemu",
+    feature = "platform_imx8mp"
+))]
+compile_error!("platform_qemu and platform_imx8mp can't be enabled together");
+
+#[cfg(all(feature = "platform_qemu", target_arch = "aarch64"))]
+pub use qemu_aarch64::{BOARD_DEVICES, ROOT_ZONE_DTB_ADDR, ROOT_ZONE_ENTRY, ZONE_RESERVATIONS};
+
+#[cfg(all(feature = "platform_imx8mp", target_arch = "aarch64"))]
+pub use imx8mp::{BOARD_DEVICES, ROOT_ZONE_DTB_ADDR, ROOT_ZONE_ENTRY, ZONE_RESERVATIONS};
+
+use core::ops::Range;
+
+/// A device window of the board which can be passed through to a zone.
+#[derive(Debug)]
+pub struct BoardDevice {
+    pub name: &'static str,
+    pub paddr: usize,
+    /// Size of the MMIO window. A device without MMIO (size 0) is matched by
+    /// its node name instead of its reg.
+    pub size: usize,
+    /// SPIs raised by the devices inside the window.
+    pub irqs: &'static [u32],
+}
+
+/// Board resources statically handed to a zone other than the root zone,
+/// which are hidden from the device tree of the root zone.
+#[derive(Debug)]
+pub struct ZoneReservation {
+    pub zone_id: usize,
+    /// Names of the `BOARD_DEVICES` passed through to the zone.
+    pub devices: &'static [&'static str],
+    /// RAM of the zone at fixed physical addresses.
+    pub memory: &'static [Range<usize>],
+}
+
+impl BoardDevice {
+    /// Whether `node` lives inside the device window.
+    pub fn covers(&self, node: fdt::node::FdtNode) -> bool {
+        if self.size == 0 {
+            return node.name.split('@').next() == Some(self.name);
+        }
+        node.reg()
+            .and_then(|mut reg| reg.next())
+            .map_or(false, |reg| {
+                let addr = reg.starting_address as usize;
+                (self.paddr..self.paddr + self.size).contains(&addr)
+            })
+    }
+
+    /// A zone is assigned the device if an enabled node of its device tree
+    /// lives inside the device window.
+    fn assigned_to(&self, fdt: &fdt::Fdt) -> bool {
+        fdt.all_nodes()
+            .filter(|node| node.property("status").and_then(|s| s.as_str()) != Some("disabled"))
+            .any(|node| self.covers(node))
+    }
+}
+
+/// The board device raising the SPI `irq`, if any.
+#[cfg(target_arch = "aarch64")]
+pub fn board_device_of_irq(irq: u32) -> Option<&'static BoardDevice> {
+    BOARD_DEVICES.iter().find(|dev| dev.irqs.contains(&irq))
+}
+
+/// Board devices assigned to the zone described by `fdt`.
+#[cfg(target_arch = "aarch64")]
+pub fn assigned_board_devices<'a>(
+    fdt: &'a fdt::Fdt,
+) -> impl Iterator<Item = &'static BoardDevice> + 'a {
+    BOARD_DEVICES.iter().filter(move |dev| dev.assigned_to(fdt))
+}
@@ -1,2 +1,34 @@
 pub const ROOT_ZONE_DTB_ADDR: usize = 0xb0000000;
 pub const ROOT_ZONE_ENTRY: usize = 0xa0000000;
+
+use super::{BoardDevice, ZoneReservation};
+
+pub const BOARD_DEVICES: &[BoardDevice] = &[
+    BoardDevice {
+        name: "pl011",
+        paddr: 0x9000000,
+        size: 0x1000,
+        irqs: &[33],
+    },
+    BoardDevice {
+        name: "pl031",
+        paddr: 0x9010000,
+        size: 0x1000,
+        irqs: &[34],
+    },
+    BoardDevice {
+        name: "pl061",
+        paddr: 0x9030000,
+        size: 0x1000,
+        irqs: &[39],
+    },
+];
+
+/// Zone 1 runs images/aarch64/devicetree/linux2.dts, without passthrough
+/// devices.
+#[allow(clippy::single_range_in_vec_init)]
+pub const ZONE_RESERVATIONS: &[ZoneReservation] = &[ZoneReservation {
+    zone_id: 1,
+    devices: &[],
+    memory: &[0x70000000..0x90000000],
+}];
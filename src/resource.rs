@@ -0,0 +1,230 @@
+//! Registry of the host resources handed out to zones.
+//!
+//! A zone claims its host memory, MMIO windows, cpus and irqs here before it is
+//! installed. Ranges used by the hypervisor itself and resources of other
+//! non-root zones can't be claimed twice. The root zone's device tree describes
+//! the whole board, so its memory, MMIO windows and irqs are lent to the zones
+//! created later. Cpus are only shared between zones with a single cpu, other
+//! than the root zone, which are then time-sliced by [`crate::scheduler`].
+
+use alloc::vec::Vec;
+use core::fmt::{Display, Formatter};
+use core::ops::Range;
+
+use spin::Mutex;
+
+use crate::consts::{core_end, guest_mem_pool_start, hv_end, hv_start, mem_pool_start};
+use crate::consts::{GUEST_MEM_POOL_SIZE, MAX_CPU_NUM, ROOT_ZONE_ID};
+use crate::error::HvResult;
+use crate::memory::MemFlags;
+use crate::zone::Zone;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Owner {
+    Hypervisor,
+    Zone(usize),
+}
+
+impl Display for Owner {
+    fn fmt(&self, f: &mut Formatter) -> core::fmt::Result {
+        match self {
+            Owner::Hypervisor => write!(f, "the hypervisor"),
+            Owner::Zone(id) => write!(f, "zone {}", id),
+        }
+    }
+}
+
+struct RangeClaim {
+    name: &'static str,
+    range: Range<usize>,
+    owner: Owner,
+}
+
+struct CpuClaim {
+    cpu: usize,
+    zone_id: usize,
+    shared: bool,
+}
+
+struct ResourceRegistry {
+    ranges: Vec<RangeClaim>,
+    cpus: Vec<CpuClaim>,
+    irqs: Vec<(u32, usize)>,
+}
+
+static RESOURCES: Mutex<ResourceRegistry> = Mutex::new(ResourceRegistry {
+    ranges: Vec::new(),
+    cpus: Vec::new(),
+    irqs: Vec::new(),
+});
+
+/// Host resources of a zone, collected from its stage-2 mappings.
+#[derive(Debug, Default)]
+pub struct ZoneResources {
+    pub memory: Vec<Range<usize>>,
+    pub mmio: Vec<Range<usize>>,
+    pub cpus: Vec<usize>,
+    /// The cpus may be time-shared with other zones allowing it.
+    pub share_cpus: bool,
+    pub irqs: Vec<u32>,
+}
+
+impl ZoneResources {
+    pub fn of_zone(zone: &Zone) -> Self {
+        let mut res = Self::default();
+        for region in zone.gpm.iter() {
+            let pa = region.mapper.map_fn(region.start);
+            let range = pa..pa + region.size;
+            // guest RAM and shared memory from the pool are owned by the zone
+            // by construction
+            let pool_backed = zone
+                .ram_frames
+                .iter()
+                .chain(zone.shmem.iter().map(|shmem| shmem.frame()))
+                .any(|f| f.start_paddr() <= pa && range.end <= f.start_paddr() + f.size());
+            if pool_backed {
+                continue;
+            }
+            if region.flags.contains(MemFlags::IO) {
+                res.mmio.push(range);
+            } else {
+                res.memory.push(range);
+            }
+        }
+        res.cpus = zone.cpu_set.iter().collect();
+        res.share_cpus = zone.can_share_cpu();
+        res.irqs = (32..1024).filter(|&irq| zone.irq_in_zone(irq)).collect();
+        res
+    }
+}
+
+fn is_overlap(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+impl ResourceRegistry {
+    /// Whether `owner` already holds a resource that `zone_id` wants.
+    fn conflicts(owner: Owner, zone_id: usize) -> bool {
+        match owner {
+            Owner::Hypervisor => true,
+            Owner::Zone(id) => id != zone_id && id != ROOT_ZONE_ID,
+        }
+    }
+
+    fn check_range(&self, zone_id: usize, name: &str, range: &Range<usize>) -> HvResult {
+        if let Some(claim) = self.ranges.iter().find(|claim| {
+            Self::conflicts(claim.owner, zone_id) && is_overlap(&claim.range, range)
+        }) {
+            return hv_result_err!(
+                EBUSY,
+                format!(
+                    "zone {} {} {:#x?} overlaps {} {:#x?} owned by {}",
+                    zone_id, name, range, claim.name, claim.range, claim.owner
+                )
+            );
+        }
+        Ok(())
+    }
+
+    fn check(&self, zone_id: usize, res: &ZoneResources) -> HvResult {
+        for range in &res.memory {
+            self.check_range(zone_id, "memory", range)?;
+        }
+        for range in &res.mmio {
+            self.check_range(zone_id, "mmio", range)?;
+        }
+        for &cpu in &res.cpus {
+            if cpu >= MAX_CPU_NUM {
+                return hv_result_err!(EINVAL, format!("zone {} wants cpu {}", zone_id, cpu));
+            }
+            if let Some(claim) = self.cpus.iter().find(|claim| {
+                claim.cpu == cpu && claim.zone_id != zone_id && !(claim.shared && res.share_cpus)
+            }) {
+                return hv_result_err!(
+                    EBUSY,
+                    format!("zone {} cpu {} is owned by zone {}", zone_id, cpu, claim.zone_id)
+                );
+            }
+        }
+        for &irq in &res.irqs {
+            if let Some(&(_, owner)) = self.irqs.iter().find(|&&(i, owner)| {
+                i == irq && Self::conflicts(Owner::Zone(owner), zone_id)
+            }) {
+                return hv_result_err!(
+                    EBUSY,
+                    format!("zone {} irq {} is owned by zone {}", zone_id, irq, owner)
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+fn reserve_hv(name: &'static str, range: Range<usize>) {
+    info!("reserve {}: {:#x?}", name, range);
+    RESOURCES.lock().ranges.push(RangeClaim {
+        name,
+        range,
+        owner: Owner::Hypervisor,
+    });
+}
+
+/// Reserve the memory and devices used by the hypervisor itself.
+pub fn init() {
+    reserve_hv("hypervisor image", hv_start()..core_end());
+    reserve_hv("per-cpu area", core_end()..mem_pool_start());
+    reserve_hv("hypervisor memory pool", mem_pool_start()..hv_end());
+    reserve_hv(
+        "guest memory pool",
+        guest_mem_pool_start()..guest_mem_pool_start() + GUEST_MEM_POOL_SIZE,
+    );
+    #[cfg(target_arch = "aarch64")]
+    {
+        use crate::device::irqchip::gicv3::{
+            host_gicd_base, host_gicd_size, host_gicr_base, host_gicr_size,
+        };
+        reserve_hv("gic distributor", host_gicd_base()..host_gicd_base() + host_gicd_size());
+        reserve_hv(
+            "gic redistributors",
+            host_gicr_base(0)..host_gicr_base(0) + host_gicr_size(),
+        );
+    }
+}
+
+/// Check the resources of `zone_id` against every other owner and record them.
+pub fn claim(zone_id: usize, res: &ZoneResources) -> HvResult {
+    let mut registry = RESOURCES.lock();
+    registry.check(zone_id, res)?;
+
+    for range in &res.memory {
+        registry.ranges.push(RangeClaim {
+            name: "memory",
+            range: range.clone(),
+            owner: Owner::Zone(zone_id),
+        });
+    }
+    for range in &res.mmio {
+        registry.ranges.push(RangeClaim {
+            name: "mmio",
+            range: range.clone(),
+            owner: Owner::Zone(zone_id),
+        });
+    }
+    registry.cpus.extend(res.cpus.iter().map(|&cpu| CpuClaim {
+        cpu,
+        zone_id,
+        shared: res.share_cpus,
+    }));
+    registry.irqs.extend(res.irqs.iter().map(|&irq| (irq, zone_id)));
+    Ok(())
+}
+
+/// Give back everything claimed by `zone_id`.
+pub fn release(zone_id: usize) {
+    let mut registry = RESOURCES.lock();
+    registry
+        .ranges
+        .retain(|claim| claim.owner != Owner::Zone(zone_id));
+    registry.cpus.retain(|claim| claim.zone_id != zone_id);
+    registry.irqs.retain(|&(_, owner)| owner != zone_id);
+}
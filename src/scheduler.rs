@@ -0,0 +1,327 @@
+//! Time-shared vcpus.
+//!
+//! A zone with a single cpu, other than the root zone, gets a [`VCpu`] which
+//! may share its physical cpu with the vcpus of other such zones. The vcpus of
+//! a cpu are picked round-robin: the EL2 timer ends the slice of the running
+//! one whenever another is runnable. Zones with several cpus keep their cpus
+//! to themselves and never enter the scheduler.
+//!
+//! The state of the loaded vcpu lives in its [`PerCpu`], as for a dedicated
+//! cpu, and moves into the [`VCpu`] when it's switched out. The configuration
+//! of SGIs and PPIs in the redistributor is shared by all vcpus of a cpu.
+
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::{Mutex, RwLock};
+
+use crate::arch::cpu::this_cpu_id;
+use crate::arch::vcpu::{sched_timer_start, sched_timer_stop, VcpuContext};
+use crate::consts::{INVALID_ADDRESS, MAX_CPU_NUM};
+use crate::device::irqchip::gicv3::vgic_emul::flush_current;
+use crate::device::irqchip::gicv3::{drop_hw_irq, inject_irq};
+use crate::error::HvResult;
+use crate::event::{send_event, IPI_EVENT_RESCHEDULE, IPI_EVENT_WAKEUP};
+use crate::hypercall::SGI_IPI_ID;
+use crate::percpu::{get_cpu_data, this_cpu_data};
+use crate::zone::Zone;
+
+/// How long a vcpu runs before the next runnable one gets its cpu.
+const TIME_SLICE_MS: u64 = 10;
+
+struct VCpuState {
+    cpu_on_entry: usize,
+    /// The guest is running, or should be started from `cpu_on_entry`.
+    psci_on: bool,
+    paused: bool,
+    /// Guest state of a started vcpu, `None` before its first run.
+    context: Option<Box<VcpuContext>>,
+    /// Irqs which arrived while the vcpu was switched out.
+    pending_irqs: Vec<(usize, bool)>,
+}
+
+pub struct VCpu {
+    zone: Arc<RwLock<Zone>>,
+    zone_id: usize,
+    state: Mutex<VCpuState>,
+}
+
+struct RunQueue {
+    vcpus: Vec<Arc<VCpu>>,
+    /// The vcpu loaded in the `PerCpu` of the cpu.
+    current: Option<Arc<VCpu>>,
+}
+
+impl RunQueue {
+    const fn new() -> Self {
+        Self {
+            vcpus: Vec::new(),
+            current: None,
+        }
+    }
+
+    fn find(&self, zone_id: usize) -> Option<&Arc<VCpu>> {
+        self.vcpus.iter().find(|vcpu| vcpu.zone_id == zone_id)
+    }
+
+    fn is_current(&self, zone_id: usize) -> bool {
+        matches!(&self.current, Some(vcpu) if vcpu.zone_id == zone_id)
+    }
+
+    /// The first runnable vcpu after the current one.
+    fn pick_next(&self) -> Option<Arc<VCpu>> {
+        let start = match &self.current {
+            Some(cur) => self.vcpus.iter().position(|v| Arc::ptr_eq(v, cur)).unwrap() + 1,
+            None => 0,
+        };
+        let n = self.vcpus.len();
+        (0..n)
+            .map(|i| &self.vcpus[(start + i) % n])
+            .filter(|vcpu| !matches!(&self.current, Some(cur) if Arc::ptr_eq(vcpu, cur)))
+            .find(|vcpu| {
+                let state = vcpu.state.lock();
+                state.psci_on && !state.paused
+            })
+            .cloned()
+    }
+}
+
+static RUN_QUEUES: [Mutex<RunQueue>; MAX_CPU_NUM] =
+    [const { Mutex::new(RunQueue::new()) }; MAX_CPU_NUM];
+
+fn kick(cpu: usize) {
+    if cpu != this_cpu_id() {
+        send_event(cpu, SGI_IPI_ID as _, IPI_EVENT_RESCHEDULE);
+    }
+}
+
+/// Give `zone` a vcpu on `cpu`, its only cpu. The vcpu is loaded at once if
+/// the cpu is free, and waits for `wake_vcpu` like a dedicated cpu.
+pub fn add_vcpu(cpu: usize, zone: &Arc<RwLock<Zone>>, entry: usize) {
+    let mut rq = RUN_QUEUES[cpu].lock();
+    let vcpu = Arc::new(VCpu {
+        zone: zone.clone(),
+        zone_id: zone.read().id,
+        state: Mutex::new(VCpuState {
+            cpu_on_entry: entry,
+            psci_on: false,
+            paused: false,
+            context: None,
+            pending_irqs: Vec::new(),
+        }),
+    });
+    let cpu_data = get_cpu_data(cpu);
+    let _lock = cpu_data.ctrl_lock.lock();
+    if rq.current.is_none() && cpu_data.zone.is_none() {
+        cpu_data.zone = Some(zone.clone());
+        cpu_data.cpu_on_entry = entry;
+        cpu_data.boot_cpu = true;
+        rq.current = Some(vcpu.clone());
+    }
+    rq.vcpus.push(vcpu);
+}
+
+/// Drop the vcpu of `zone_id` on `cpu`. If it was loaded, the cpu is released
+/// as by a shutdown and `true` is returned: the caller must send it
+/// `IPI_EVENT_SHUTDOWN`.
+pub fn remove_vcpu(zone_id: usize, cpu: usize) -> bool {
+    let mut rq = RUN_QUEUES[cpu].lock();
+    rq.vcpus.retain(|vcpu| vcpu.zone_id != zone_id);
+    if !rq.is_current(zone_id) {
+        return false;
+    }
+    rq.current = None;
+    let cpu_data = get_cpu_data(cpu);
+    let _lock = cpu_data.ctrl_lock.lock();
+    cpu_data.zone = None;
+    cpu_data.cpu_on_entry = INVALID_ADDRESS;
+    true
+}
+
+/// Start the vcpu of `zone_id` on `cpu` from its entry.
+pub fn wake_vcpu(zone_id: usize, cpu: usize) -> HvResult {
+    let rq = RUN_QUEUES[cpu].lock();
+    let vcpu = match rq.find(zone_id) {
+        Some(vcpu) => vcpu,
+        None => return hv_result_err!(ENOENT, format!("zone {} has no vcpu on cpu {}", zone_id, cpu)),
+    };
+    if rq.is_current(zone_id) {
+        // parked in its cpu like on a dedicated one
+        let cpu_data = get_cpu_data(cpu);
+        let _lock = cpu_data.ctrl_lock.lock();
+        if cpu_data.arch_cpu.psci_on || cpu_data.arch_cpu.psci_on_pending {
+            return hv_result_err!(EBUSY, format!("zone {} is already on", zone_id));
+        }
+        cpu_data.arch_cpu.psci_on_pending = true;
+        drop(_lock);
+        drop(rq);
+        send_event(cpu, SGI_IPI_ID as _, IPI_EVENT_WAKEUP);
+        return Ok(());
+    }
+    let mut state = vcpu.state.lock();
+    if state.psci_on {
+        return hv_result_err!(EBUSY, format!("zone {} is already on", zone_id));
+    }
+    state.psci_on = true;
+    drop(state);
+    drop(rq);
+    kick(cpu);
+    Ok(())
+}
+
+/// Stop the vcpu of `zone_id` on `cpu` and wait until it's switched out.
+pub fn pause_vcpu(zone_id: usize, cpu: usize) {
+    match RUN_QUEUES[cpu].lock().find(zone_id) {
+        Some(vcpu) => vcpu.state.lock().paused = true,
+        None => return,
+    }
+    kick(cpu);
+    while RUN_QUEUES[cpu].lock().is_current(zone_id) {
+        core::hint::spin_loop();
+    }
+}
+
+pub fn resume_vcpu(zone_id: usize, cpu: usize) {
+    if let Some(vcpu) = RUN_QUEUES[cpu].lock().find(zone_id) {
+        vcpu.state.lock().paused = false;
+    }
+    kick(cpu);
+}
+
+/// Make the paused vcpu of `zone_id` on `cpu` start over from `entry`.
+pub fn reset_vcpu(zone_id: usize, cpu: usize, entry: usize) {
+    if let Some(vcpu) = RUN_QUEUES[cpu].lock().find(zone_id) {
+        let mut state = vcpu.state.lock();
+        state.context = None;
+        state.cpu_on_entry = entry;
+        state.psci_on = true;
+        state.paused = false;
+        state.pending_irqs.clear();
+    }
+    kick(cpu);
+}
+
+/// Queue `irq` for the vcpu of the current cpu owning it, if that vcpu isn't
+/// loaded. Return whether the irq was queued.
+pub fn route_irq(irq: usize, is_hw: bool) -> bool {
+    if irq < 32 {
+        return false;
+    }
+    let rq = RUN_QUEUES[this_cpu_id()].lock();
+    let owner = rq
+        .vcpus
+        .iter()
+        .find(|vcpu| vcpu.zone.read().irq_in_zone(irq as _));
+    match owner {
+        Some(vcpu) if !rq.is_current(vcpu.zone_id) => {
+            let mut state = vcpu.state.lock();
+            if !state.pending_irqs.contains(&(irq, is_hw)) {
+                state.pending_irqs.push((irq, is_hw));
+            }
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Called on the EL2 timer irq, after it was acknowledged.
+pub fn timer_tick() {
+    sched_timer_stop();
+    schedule();
+}
+
+/// Pick the vcpu to run on the current cpu. Return if the current vcpu keeps
+/// running, otherwise switch to the next runnable vcpu or park the cpu.
+///
+/// Must be called on a trap from the guest, whose registers are the ones to
+/// save.
+pub fn schedule() {
+    let cpu_data = this_cpu_data();
+    let mut rq = RUN_QUEUES[cpu_data.id].lock();
+    if rq.current.is_none() && cpu_data.zone.is_some() {
+        // a dedicated cpu
+        return;
+    }
+    let current_runnable = match &rq.current {
+        Some(cur) => {
+            let arch_cpu = &cpu_data.arch_cpu;
+            !cur.state.lock().paused && (arch_cpu.psci_on || arch_cpu.psci_on_pending)
+        }
+        None => false,
+    };
+    let next = match rq.pick_next() {
+        Some(next) => next,
+        None if current_runnable => {
+            sched_timer_stop();
+            return;
+        }
+        None => {
+            if let Some(cur) = rq.current.take() {
+                save_current(&cur);
+            }
+            let _lock = cpu_data.ctrl_lock.lock();
+            cpu_data.zone = None;
+            cpu_data.cpu_on_entry = INVALID_ADDRESS;
+            drop(_lock);
+            drop(rq);
+            sched_timer_stop();
+            cpu_data.arch_cpu.idle();
+        }
+    };
+
+    if let Some(cur) = rq.current.take() {
+        save_current(&cur);
+    }
+    debug!("cpu {}: switch to zone {}", cpu_data.id, next.zone_id);
+    let (context, pending_irqs) = {
+        let mut state = next.state.lock();
+        let _lock = cpu_data.ctrl_lock.lock();
+        cpu_data.zone = Some(next.zone.clone());
+        cpu_data.cpu_on_entry = state.cpu_on_entry;
+        cpu_data.boot_cpu = true;
+        (state.context.take(), core::mem::take(&mut state.pending_irqs))
+    };
+    rq.current = Some(next);
+    if rq.vcpus.len() > 1 {
+        sched_timer_start(TIME_SLICE_MS);
+    }
+    drop(rq);
+
+    match context {
+        Some(context) => {
+            cpu_data.activate_gpm();
+            let arch_cpu = &mut cpu_data.arch_cpu;
+            arch_cpu.restore_context(&context);
+            pending_irqs
+                .iter()
+                .for_each(|&(irq, is_hw)| inject_irq(irq, is_hw));
+            // and what a software GIC kept for it
+            flush_current();
+            arch_cpu.resume()
+        }
+        None => {
+            // a fresh guest starts with an empty GIC state, let the devices
+            // raise their irqs again
+            pending_irqs
+                .iter()
+                .filter(|&&(_, is_hw)| is_hw)
+                .for_each(|&(irq, _)| drop_hw_irq(irq));
+            cpu_data.arch_cpu.run()
+        }
+    }
+}
+
+/// Move the state of the loaded vcpu `cur` from the current cpu into `cur`.
+fn save_current(cur: &VCpu) {
+    let cpu_data = this_cpu_data();
+    let mut state = cur.state.lock();
+    let _lock = cpu_data.ctrl_lock.lock();
+    let arch_cpu = &mut cpu_data.arch_cpu;
+    if arch_cpu.psci_on {
+        state.context = Some(Box::new(arch_cpu.save_context()));
+    }
+    state.psci_on = arch_cpu.psci_on || arch_cpu.psci_on_pending;
+    state.cpu_on_entry = cpu_data.cpu_on_entry;
+    arch_cpu.psci_on = false;
+    arch_cpu.psci_on_pending = false;
+}
@@ -0,0 +1,74 @@
+//! Names of the hypervisor functions, for backtraces.
+//!
+//! `KSYMS` reserves the `.ksyms` section, which `scripts/ksyms.py` fills once
+//! hvisor is linked: a header {magic, count: u32}, `count` entries {address:
+//! u64, name offset: u32, name length: u32} sorted by address, then the names.
+//! Without it the section stays zeroed and addresses go without names.
+
+/// Room for the symbol table, the build fails if it doesn't fit.
+pub const KSYMS_SIZE: usize = 1024 * 1024; // 1 MB
+
+const KSYMS_MAGIC: u32 = 0x5953_5648; // "HVSY"
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 16;
+
+#[used]
+#[link_section = ".ksyms"]
+static KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+extern "C" {
+    fn stext();
+    fn etext();
+    fn __ksyms_start();
+    fn __ksyms_end();
+}
+
+/// The table as patched after the link, `KSYMS` itself is all zeros to the
+/// compiler.
+fn table() -> &'static [u8] {
+    let start = __ksyms_start as usize;
+    unsafe { core::slice::from_raw_parts(start as *const u8, __ksyms_end as usize - start) }
+}
+
+fn read_u32(table: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(table[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(table: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(table[offset..offset + 8].try_into().unwrap())
+}
+
+/// Whether `addr` is in the hypervisor code.
+pub fn is_text(addr: usize) -> bool {
+    (stext as usize..etext as usize).contains(&addr)
+}
+
+/// The function containing the code address `addr`, and the offset of `addr`
+/// in it.
+pub fn lookup(addr: usize) -> Option<(&'static str, usize)> {
+    let table = table();
+    if !is_text(addr) || read_u32(table, 0) != KSYMS_MAGIC {
+        return None;
+    }
+    let count = read_u32(table, 4) as usize;
+    let names = HEADER_SIZE + count * ENTRY_SIZE;
+    let address = |i: usize| read_u64(table, HEADER_SIZE + i * ENTRY_SIZE) as usize;
+
+    // the first symbol past addr
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let mid = (low + high) / 2;
+        match address(mid) <= addr {
+            true => low = mid + 1,
+            false => high = mid,
+        }
+    }
+    if low == 0 {
+        return None;
+    }
+    let entry = HEADER_SIZE + (low - 1) * ENTRY_SIZE;
+    let name_start = names + read_u32(table, entry + 8) as usize;
+    let name_end = name_start + read_u32(table, entry + 12) as usize;
+    let name = core::str::from_utf8(table.get(name_start..name_end)?).ok()?;
+    Some((name, addr - address(low - 1)))
+}
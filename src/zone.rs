@@ -1,54 +1,225 @@
 use alloc::sync::Arc;
 use alloc::vec::Vec;
-use spin::RwLock;
+use spin::{Mutex, RwLock};
 
+use crate::arch::cpu::this_cpu_id;
 use crate::arch::mm::new_s2_memory_set;
 use crate::arch::s2pt::Stage2PageTable;
-use crate::consts::MAX_CPU_NUM;
+use crate::config::{HvConfigMemoryRegion, HvZoneConfig, HvZoneInfo, CONFIG_MAX_MEMORY_REGIONS};
+use crate::consts::{DTB_IPA, MAX_CPU_NUM, MAX_ZONE_NUM, ROOT_ZONE_ID};
+use crate::consts::{INVALID_ADDRESS, PAGE_SIZE};
+use crate::control::{reset_cpu, resume_cpu, suspend_cpu};
+use crate::device::irqchip::gicv3::vgic_emul::VGic;
+use crate::device::ivshmem::ShmemAttachment;
+use crate::dtb;
 
 use crate::error::HvResult;
-use crate::memory::addr::GuestPhysAddr;
-use crate::memory::{MMIOConfig, MMIOHandler, MMIORegion, MemorySet};
+use crate::event::{send_event, IPI_EVENT_SHUTDOWN};
+use crate::hypercall::SGI_IPI_ID;
+use crate::loader;
+use crate::mailbox;
+use crate::memory::addr::{align_up, GuestPhysAddr};
+use crate::memory::{Frame, MMIOConfig, MMIOHandler, MMIORegion, MemFlags, MemorySet};
 use crate::percpu::{get_cpu_data, this_zone, CpuSet};
-use crate::platform::qemu_aarch64::ROOT_ZONE_ENTRY;
+use crate::resource::{self, ZoneResources};
+use crate::scheduler;
+use crate::platform::{board_device_of_irq, BOARD_DEVICES, ROOT_ZONE_ENTRY, ZONE_RESERVATIONS};
 use core::ops::Add;
+use core::sync::atomic::Ordering;
 use core::panic;
 
+/// Lifecycle of a zone, reported to the root zone by `HvZoneList`.
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ZoneState {
+    /// Resources are set up, but no cpu has entered the guest yet.
+    Created = 0,
+    Running = 1,
+    Paused = 2,
+    ShuttingDown = 3,
+    /// The guest hit a fatal condition and can only be shut down.
+    Failed = 4,
+}
+
+impl ZoneState {
+    fn can_become(self, next: ZoneState) -> bool {
+        use ZoneState::*;
+        matches!(
+            (self, next),
+            (Created, Running)
+                | (Running | Paused | Failed, Created)
+                | (Running, Paused)
+                | (Paused, Running)
+                | (Created | Running | Paused, Failed)
+                | (Created | Running | Paused | Failed, ShuttingDown)
+        )
+    }
+}
+
+/// A pristine copy of an image loaded into guest memory, restored on reboot.
+struct BootImage {
+    ipa: GuestPhysAddr,
+    size: usize,
+    /// Bytes zeroed after the image, like a BSS.
+    bss: usize,
+    frame: Option<Frame>,
+}
+
 pub struct Zone {
     pub id: usize,
+    pub state: ZoneState,
     pub mmio: Vec<MMIOConfig>,
     pub cpu_set: CpuSet,
     pub irq_bitmap: [u32; 1024 / 32],
     pub gpm: MemorySet<Stage2PageTable>,
+    pub dtb_ipa: usize,
+    /// Guest RAM allocated from the guest memory pool, freed with the zone.
+    pub ram_frames: Vec<Frame>,
+    /// Where the boot cpu enters the guest.
+    pub entry: usize,
+    boot_images: Vec<BootImage>,
+    /// Shared memory devices the zone is attached to.
+    pub shmem: Vec<ShmemAttachment>,
+    /// Bitmap of the zones it may send messages to.
+    pub msg_peers: u64,
+    /// Raised when a message arrives in its mailbox, 0 for none.
+    pub msg_irq: u32,
+    /// Software GIC, if the zone doesn't reach the physical one.
+    pub vgic: Option<Arc<VGic>>,
 }
 
 impl Zone {
     pub fn new(zoneid: usize) -> Self {
         Self {
             id: zoneid,
+            state: ZoneState::Created,
             gpm: new_s2_memory_set(),
             cpu_set: CpuSet::new(MAX_CPU_NUM as usize, 0),
             mmio: Vec::new(),
             irq_bitmap: [0; 1024 / 32],
+            dtb_ipa: DTB_IPA,
+            ram_frames: Vec::new(),
+            entry: 0,
+            boot_images: Vec::new(),
+            shmem: Vec::new(),
+            msg_peers: 0,
+            msg_irq: 0,
+            vgic: None,
         }
     }
 
-    // pub fn suspend(&self) {
-    //     trace!("suspending cpu_set = {:#x?}", self.cpu_set);
-    //     self.cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
-    //         trace!("try to suspend cpu_id = {:#x?}", cpu_id);
-    //         suspend_cpu(cpu_id);
-    //     });
-    //     info!("send sgi done!");
-    // }
+    /// Keep a copy of `data`, loaded at `ipa` and followed by `bss` zeroed
+    /// bytes, in the guest memory pool.
+    pub fn save_boot_image(&mut self, ipa: GuestPhysAddr, data: &[u8], bss: usize) -> HvResult {
+        let frame = match data.is_empty() {
+            true => None,
+            false => {
+                let mut frame = Frame::new_guest(align_up(data.len()) / PAGE_SIZE, 0)?;
+                frame.copy_data_from(data);
+                Some(frame)
+            }
+        };
+        self.boot_images.push(BootImage {
+            ipa,
+            size: data.len(),
+            bss,
+            frame,
+        });
+        Ok(())
+    }
 
-    // pub fn resume(&self) {
-    //     trace!("resuming cpu_set = {:#x?}", self.cpu_set);
-    //     self.cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
-    //         trace!("try to resume cpu_id = {:#x?}", cpu_id);
-    //         resume_cpu(cpu_id);
-    //     });
-    // }
+    fn restore_boot_images(&self) -> HvResult {
+        for image in &self.boot_images {
+            if let Some(frame) = &image.frame {
+                self.copy_to_guest(image.ipa, &frame.as_slice()[..image.size])?;
+            }
+            self.zero_guest(image.ipa + image.size, image.bss)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_state(&mut self, next: ZoneState) -> HvResult {
+        if !self.state.can_become(next) {
+            return hv_result_err!(
+                EBUSY,
+                format!("zone {} can't go from {:?} to {:?}", self.id, self.state, next)
+            );
+        }
+        info!("zone {}: {:?} -> {:?}", self.id, self.state, next);
+        self.state = next;
+        Ok(())
+    }
+
+    /// Copy `data` into guest memory at `ipa` through the stage-2 mappings.
+    pub fn copy_to_guest(&self, ipa: GuestPhysAddr, data: &[u8]) -> HvResult {
+        let mut copied = 0;
+        while copied < data.len() {
+            let (pa, flags, page_size) = unsafe { self.gpm.page_table_query(ipa + copied)? };
+            if !flags.contains(MemFlags::WRITE) || flags.contains(MemFlags::IO) {
+                return hv_result_err!(
+                    EFAULT,
+                    format!("zone {} ipa {:#x} is not writable RAM", self.id, ipa + copied)
+                );
+            }
+            let len = (page_size as usize - page_size.page_offset(ipa + copied))
+                .min(data.len() - copied);
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[copied..].as_ptr(), pa as *mut u8, len);
+            }
+            copied += len;
+        }
+        Ok(())
+    }
+
+    /// Zero `len` bytes of guest memory at `ipa`.
+    pub fn zero_guest(&self, ipa: GuestPhysAddr, len: usize) -> HvResult {
+        static ZEROES: [u8; PAGE_SIZE] = [0; PAGE_SIZE];
+        let mut zeroed = 0;
+        while zeroed < len {
+            let chunk = (len - zeroed).min(PAGE_SIZE);
+            self.copy_to_guest(ipa + zeroed, &ZEROES[..chunk])?;
+            zeroed += chunk;
+        }
+        Ok(())
+    }
+
+    /// Fill `data` from guest memory at `ipa` through the stage-2 mappings.
+    pub fn copy_from_guest(&self, ipa: GuestPhysAddr, data: &mut [u8]) -> HvResult {
+        let mut copied = 0;
+        while copied < data.len() {
+            let (pa, flags, page_size) = unsafe { self.gpm.page_table_query(ipa + copied)? };
+            if !flags.contains(MemFlags::READ) || flags.contains(MemFlags::IO) {
+                return hv_result_err!(
+                    EFAULT,
+                    format!("zone {} ipa {:#x} is not readable RAM", self.id, ipa + copied)
+                );
+            }
+            let len = (page_size as usize - page_size.page_offset(ipa + copied))
+                .min(data.len() - copied);
+            unsafe {
+                core::ptr::copy_nonoverlapping(pa as *const u8, data[copied..].as_mut_ptr(), len);
+            }
+            copied += len;
+        }
+        Ok(())
+    }
+
+    /// Whether the cpu of the zone may be time-shared with other zones, see
+    /// [`crate::scheduler`].
+    pub fn can_share_cpu(&self) -> bool {
+        self.id != ROOT_ZONE_ID && self.cpu_set.iter().count() == 1
+    }
+
+    pub fn resume(&self) {
+        trace!("resuming cpu_set = {:#x?}", self.cpu_set);
+        self.cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
+            trace!("try to resume cpu_id = {:#x?}", cpu_id);
+            match self.can_share_cpu() {
+                true => scheduler::resume_vcpu(self.id, cpu_id),
+                false => resume_cpu(cpu_id),
+            }
+        });
+    }
 
     // pub fn owns_cpu(&self, id: usize) -> bool {
     //     self.cpu_set.contains_cpu(id)
@@ -110,6 +281,33 @@ impl Zone {
 
 static ZONE_LIST: RwLock<Vec<Arc<RwLock<Zone>>>> = RwLock::new(vec![]);
 
+/// Bitmap of the zone ids in use.
+static ZONE_IDS: Mutex<u64> = Mutex::new(0);
+
+/// Reserve `zone_id`, or the lowest free id if it's `None`.
+fn alloc_zone_id(zone_id: Option<usize>) -> HvResult<usize> {
+    let mut ids = ZONE_IDS.lock();
+    let zone_id = match zone_id {
+        Some(id) if id >= MAX_ZONE_NUM => {
+            return hv_result_err!(EINVAL, format!("zone id {} out of range", id))
+        }
+        Some(id) => id,
+        None => match (0..MAX_ZONE_NUM).find(|&id| *ids & (1 << id) == 0) {
+            Some(id) => id,
+            None => return hv_result_err!(ENOMEM, "no free zone id"),
+        },
+    };
+    if *ids & (1 << zone_id) != 0 {
+        return hv_result_err!(EEXIST, format!("zone id {} is in use", zone_id));
+    }
+    *ids |= 1 << zone_id;
+    Ok(zone_id)
+}
+
+fn free_zone_id(zone_id: usize) {
+    *ZONE_IDS.lock() &= !(1 << zone_id);
+}
+
 pub fn root_zone() -> Arc<RwLock<Zone>> {
     ZONE_LIST.read().get(0).cloned().unwrap()
 }
@@ -123,16 +321,20 @@ pub fn add_zone(zone: Arc<RwLock<Zone>>) {
     ZONE_LIST.write().push(zone);
 }
 
-/// Remove zone from ZONE_LIST
-pub fn remove_zone(zone_id: usize) {
+/// Remove zone from ZONE_LIST and release its resources. Its memory is freed
+/// with the last reference to it.
+pub fn remove_zone(zone_id: usize) -> HvResult {
     let mut zone_list = ZONE_LIST.write();
-    let (idx, _) = zone_list
-        .iter()
-        .enumerate()
-        .find(|(_, zone)| zone.read().id == zone_id)
-        .unwrap();
-    let removed_zone = zone_list.remove(idx);
-    assert_eq!(Arc::strong_count(&removed_zone), 1);
+    let idx = match zone_list.iter().position(|zone| zone.read().id == zone_id) {
+        Some(idx) => idx,
+        None => return hv_result_err!(ENOENT, format!("zone {} not in the zone list", zone_id)),
+    };
+    zone_list.remove(idx);
+    drop(zone_list);
+    resource::release(zone_id);
+    mailbox::release(zone_id);
+    free_zone_id(zone_id);
+    Ok(())
 }
 
 pub fn find_zone(zone_id: usize) -> Option<Arc<RwLock<Zone>>> {
@@ -143,6 +345,52 @@ pub fn find_zone(zone_id: usize) -> Option<Arc<RwLock<Zone>>> {
         .cloned()
 }
 
+/// The software GIC of the zone the physical `irq` is mapped into, if any.
+pub fn find_vgic_of_irq(irq: u32) -> Option<Arc<VGic>> {
+    ZONE_LIST
+        .read()
+        .iter()
+        .find_map(|zone| zone.read().vgic.clone().filter(|vgic| vgic.virq_of(irq).is_some()))
+}
+
+/// Describe every zone, in the layout expected by `HvZoneList`.
+pub fn zone_list_info() -> Vec<HvZoneInfo> {
+    ZONE_LIST
+        .read()
+        .iter()
+        .map(|zone| {
+            let zone = zone.read();
+            let mut info = HvZoneInfo {
+                zone_id: zone.id as _,
+                state: zone.state as _,
+                cpus: zone.cpu_set.bitmap,
+                num_memory_regions: 0,
+                num_irqs: (32..1024).filter(|&irq| zone.irq_in_zone(irq)).count() as _,
+                memory_regions: [HvConfigMemoryRegion {
+                    ipa: 0,
+                    pa: 0,
+                    size: 0,
+                    flags: 0,
+                }; CONFIG_MAX_MEMORY_REGIONS],
+            };
+            let ram = zone
+                .gpm
+                .iter()
+                .filter(|region| !region.flags.contains(MemFlags::IO));
+            for (slot, region) in info.memory_regions.iter_mut().zip(ram) {
+                *slot = HvConfigMemoryRegion {
+                    ipa: region.start as _,
+                    pa: region.mapper.map_fn(region.start) as _,
+                    size: region.size as _,
+                    flags: region.flags.bits(),
+                };
+                info.num_memory_regions += 1;
+            }
+            info
+        })
+        .collect()
+}
+
 pub fn this_zone_id() -> usize {
     this_zone().read().id
 }
@@ -154,18 +402,29 @@ pub fn zone_create(
 ) -> HvResult<Arc<RwLock<Zone>>> {
     // we create the new zone here
     // TODO: create Zone with cpu_set
-    info!("zone_create: zone_id = {}, dtb_ptr = {:#x}, dtb_ipa = {:#x}", zone_id, dtb_ptr, dtb_ipa);
-    let guest_fdt = unsafe { fdt::Fdt::from_ptr(dtb_ptr) }.unwrap();
+    info!("zone_create: zone_id = {}, dtb_ptr = {:#x?}, dtb_ipa = {:#x}", zone_id, dtb_ptr, dtb_ipa);
+    let host_fdt = unsafe { fdt::Fdt::from_ptr(dtb_ptr) }.unwrap();
     let guest_entry = ROOT_ZONE_ENTRY;
 
     debug!("zone fdt guest_addr: {:#b}", guest_entry);
 
-    if find_zone(zone_id).is_some() {
-        return hv_result_err!(EEXIST);
+    alloc_zone_id(Some(zone_id))?;
+    let created = root_zone_init(zone_id, &host_fdt, dtb_ipa)
+        .and_then(|zone| zone_install(zone, guest_entry));
+    if created.is_err() {
+        free_zone_id(zone_id);
     }
+    created
+}
+
+fn root_zone_init(zone_id: usize, host_fdt: &fdt::Fdt, dtb_ipa: usize) -> HvResult<Zone> {
+    let dtb_frame = dtb::root_zone_dtb(host_fdt, BOARD_DEVICES, ZONE_RESERVATIONS)?;
+    let guest_fdt = unsafe { fdt::Fdt::from_ptr(dtb_frame.as_ptr()) }.unwrap();
     let mut zone = Zone::new(zone_id);
-    zone.pt_init(guest_entry, &guest_fdt, dtb_ptr as usize, dtb_ipa)
+    zone.dtb_ipa = dtb_ipa;
+    zone.pt_init(ROOT_ZONE_ENTRY, &guest_fdt, dtb_frame.start_paddr(), dtb_ipa)
         .unwrap();
+    zone.ram_frames.push(dtb_frame);
     zone.mmio_init(&guest_fdt);
     zone.irq_bitmap_init(&guest_fdt);
 
@@ -174,11 +433,135 @@ pub fn zone_create(
         zone.cpu_set.set_bit(cpu_id as usize);
     });
 
+    Ok(zone)
+}
+
+/// The `len` bytes of root zone RAM at `pa`, which the root zone maps 1:1.
+fn root_ram(pa: u64, len: u64) -> HvResult<&'static [u8]> {
+    if len == 0 {
+        return Ok(&[]);
+    }
+    let (start, len) = (pa as usize, len as usize);
+    if start.checked_add(len).is_none() {
+        return hv_result_err!(ERANGE, format!("{:#x} bytes at {:#x} overflow", len, start));
+    }
+    let root = root_zone();
+    let root = root.read();
+    let mut checked = 0;
+    while checked < len {
+        let addr = start + checked;
+        let (hpa, flags, page_size) = unsafe { root.gpm.page_table_query(addr)? };
+        if hpa != addr || !flags.contains(MemFlags::READ) || flags.contains(MemFlags::IO) {
+            return hv_result_err!(EFAULT, format!("{:#x} is not RAM of the root zone", addr));
+        }
+        checked += page_size as usize - page_size.page_offset(addr);
+    }
+    Ok(unsafe { core::slice::from_raw_parts(start as *const u8, len) })
+}
+
+/// Create a zone whose resources are all listed in `config`, a copy owned by
+/// the hypervisor. Its images are read from the RAM of the root zone.
+pub fn zone_create_from_config(config: &HvZoneConfig) -> HvResult<Arc<RwLock<Zone>>> {
+    // the id is reserved from here on, and released if the zone can't be made
+    let zone_id = alloc_zone_id(match config.zone_id {
+        0 => None,
+        id => Some(id as usize),
+    })?;
+    let created = zone_init_from_config(zone_id, config)
+        .and_then(|(zone, entry)| zone_install(zone, entry));
+    if created.is_err() {
+        free_zone_id(zone_id);
+    }
+    created
+}
+
+/// Build the zone `zone_id` of `config`, and return it with its entry.
+fn zone_init_from_config(zone_id: usize, config: &HvZoneConfig) -> HvResult<(Zone, usize)> {
+    info!(
+        "zone_create_from_config: zone_id = {}, dtb_pa = {:#x}, dtb_ipa = {:#x}",
+        zone_id, config.dtb_phys_addr, config.dtb_ipa
+    );
+    // copied, as it's parsed more than once
+    let header = root_ram(config.dtb_phys_addr, 8)?;
+    let dtb_size = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    let dtb = root_ram(config.dtb_phys_addr, dtb_size as _)?.to_vec();
+    let host_fdt =
+        fdt::Fdt::new(&dtb).map_err(|e| hv_err!(EINVAL, format!("invalid zone dtb: {}", e)))?;
+    config.validate(dtb::zone_dtb_size(&host_fdt))?;
+    if !config.vgic_emul() {
+        // the doorbells are then enabled in the distributor like device irqs,
+        // so no device may raise them
+        for irq in config.doorbell_irqs() {
+            if let Some(dev) = board_device_of_irq(irq) {
+                return hv_result_err!(
+                    EBUSY,
+                    format!("doorbell irq {} is raised by the board's {}", irq, dev.name)
+                );
+            }
+        }
+    }
+    let image = root_ram(config.image_phys_addr, config.image_size)?;
+    let image = loader::ZoneImage::parse(config, image)?;
+    let dtb_frame = dtb::zone_dtb(config, &host_fdt)?;
+    let guest_fdt = unsafe { fdt::Fdt::from_ptr(dtb_frame.as_ptr()) }
+        .map_err(|e| hv_err!(EINVAL, format!("invalid generated zone dtb: {}", e)))?;
+    let mut zone = Zone::new(zone_id);
+    zone.dtb_ipa = config.dtb_ipa as _;
+    let dtb_paddr = dtb_frame.start_paddr();
+    zone.ram_frames.push(dtb_frame);
+    zone.pt_init_from_config(config, &guest_fdt, dtb_paddr)?;
+    zone.shmem_init(config.shmems())?;
+    image.load(&zone)?;
+    let dtb =
+        unsafe { core::slice::from_raw_parts(dtb_paddr as *const u8, guest_fdt.total_size()) };
+    let saved = image
+        .save(&mut zone)
+        .and_then(|_| zone.save_boot_image(config.dtb_ipa as _, dtb, 0));
+    if let Err(e) = saved {
+        warn!("zone {} can't be rebooted, no copy of its images: {:?}", zone_id, e);
+        zone.boot_images.clear();
+    }
+    if config.vgic_emul() {
+        zone.vgic = Some(Arc::new(VGic::new(config)));
+    }
+    zone.mmio_init(&guest_fdt);
+    let irqs: Vec<u32> = match &zone.vgic {
+        // the irqs of shared memories and the mailbox are virtual
+        Some(vgic) => vgic.hw_irqs().collect(),
+        None => config
+            .interrupts()
+            .iter()
+            .copied()
+            .chain(config.doorbell_irqs())
+            .collect(),
+    };
+    zone.irq_bitmap_init_from_config(&irqs);
+    zone.cpu_set = config.cpu_set();
+    zone.msg_peers = config.msg_peers;
+    zone.msg_irq = config.msg_irq;
+
+    Ok((zone, image.entry() as _))
+}
+
+/// Claim the resources of a fully initialized zone, bind its cpus to it and
+/// add it to ZONE_LIST. Its id must be reserved already.
+fn zone_install(mut zone: Zone, guest_entry: usize) -> HvResult<Arc<RwLock<Zone>>> {
+    zone.entry = guest_entry;
+    resource::claim(zone.id, &ZoneResources::of_zone(&zone))?;
+    if let Some(vgic) = &zone.vgic {
+        // its physical irqs are now its own
+        vgic.reset();
+    }
     info!("zone cpu_set: {:#b}", zone.cpu_set.bitmap);
     let cpu_set = zone.cpu_set;
+    let zone_id = zone.id;
+    let share_cpu = zone.can_share_cpu();
 
     let new_zone_pointer = Arc::new(RwLock::new(zone));
-    {
+    if share_cpu {
+        let cpuid = cpu_set.first_cpu().unwrap();
+        scheduler::add_vcpu(cpuid, &new_zone_pointer, guest_entry);
+    } else {
         cpu_set.iter().for_each(|cpuid| {
             let cpu_data = get_cpu_data(cpuid);
             cpu_data.zone = Some(new_zone_pointer.clone());
@@ -189,7 +572,116 @@ pub fn zone_create(
             cpu_data.cpu_on_entry = guest_entry;
         });
     }
+    mailbox::create(zone_id);
     add_zone(new_zone_pointer.clone());
 
     Ok(new_zone_pointer)
 }
+
+/// Restart `zone` from its entry with the images it was started with. The
+/// stage-2 mappings and the irqs of the zone are kept.
+///
+/// The cpus of the zone other than the current one are stopped and then sent
+/// back to the guest. If the current cpu is in the zone, the caller must
+/// restart it with [`crate::control::reset_current_cpu`].
+pub fn zone_reboot(zone: &Arc<RwLock<Zone>>) -> HvResult {
+    if zone.read().boot_images.is_empty() {
+        return hv_result_err!(ENOSYS, format!("zone {} has no boot images", zone.read().id));
+    }
+    zone.write().set_state(ZoneState::Created)?;
+    zone_suspend(zone);
+    let zone_r = zone.read();
+    if let Err(e) = zone_r.restore_boot_images() {
+        drop(zone_r);
+        zone.write().set_state(ZoneState::Failed)?;
+        return Err(e);
+    }
+    zone_r.arch_irqchip_reset();
+    mailbox::flush(zone_r.id);
+    if zone_r.can_share_cpu() {
+        // the vcpu is switched out, unless it's the caller
+        if zone_r.cpu_set.contains_cpu(this_cpu_id()) {
+            get_cpu_data(this_cpu_id()).cpu_on_entry = zone_r.entry;
+        }
+        zone_r
+            .cpu_set
+            .iter_except(this_cpu_id())
+            .for_each(|cpu_id| scheduler::reset_vcpu(zone_r.id, cpu_id, zone_r.entry));
+    } else {
+        zone_r.cpu_set.iter().for_each(|cpu_id| {
+            get_cpu_data(cpu_id).cpu_on_entry = zone_r.entry;
+        });
+        zone_r
+            .cpu_set
+            .iter_except(this_cpu_id())
+            .for_each(reset_cpu);
+    }
+    info!("zone {} rebooted", zone_r.id);
+    drop(zone_r);
+    zone.write().set_state(ZoneState::Running)
+}
+
+/// Stop every cpu of `zone` at a safe point in EL2. A shared cpu is handed to
+/// the other zones instead.
+///
+/// The zone isn't locked while waiting for its cpus, which may be waiting for
+/// the lock themselves.
+pub fn zone_suspend(zone: &RwLock<Zone>) {
+    let (zone_id, cpu_set, share_cpu) = {
+        let zone = zone.read();
+        (zone.id, zone.cpu_set, zone.can_share_cpu())
+    };
+    trace!("suspending cpu_set = {:#x?}", cpu_set);
+    cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
+        trace!("try to suspend cpu_id = {:#x?}", cpu_id);
+        match share_cpu {
+            true => scheduler::pause_vcpu(zone_id, cpu_id),
+            false => suspend_cpu(cpu_id),
+        }
+    });
+    info!("zone {} suspended", zone_id);
+}
+
+/// Stop every cpu of `zone`, hand them back as parked cpus and remove the zone.
+/// The zone is removed once its other cpus have left the guest.
+///
+/// If the current cpu is in the zone, the caller must park it afterwards.
+pub fn zone_shutdown(zone: Arc<RwLock<Zone>>) -> HvResult {
+    let was_paused = zone.read().state == ZoneState::Paused;
+    zone.write().set_state(ZoneState::ShuttingDown)?;
+    let zone_r = zone.read();
+    if was_paused {
+        zone_r.resume();
+    }
+
+    // // return zone's cpus to root_zone
+    zone_r.cpu_set.iter().for_each(|cpu_id| {
+        let loaded = match zone_r.can_share_cpu() {
+            true => scheduler::remove_vcpu(zone_r.id, cpu_id),
+            false => {
+                let _lock = get_cpu_data(cpu_id).ctrl_lock.lock();
+                get_cpu_data(cpu_id).zone = None;
+                get_cpu_data(cpu_id).cpu_on_entry = INVALID_ADDRESS;
+                true
+            }
+        };
+        if loaded && cpu_id != this_cpu_id() {
+            get_cpu_data(cpu_id).left_zone.store(false, Ordering::Release);
+            send_event(cpu_id, SGI_IPI_ID as _, IPI_EVENT_SHUTDOWN);
+        }
+    });
+
+    zone_r.arch_irqchip_reset();
+
+    let (zone_id, cpu_set) = (zone_r.id, zone_r.cpu_set);
+    drop(zone_r);
+    // the other cpus may be running the guest until they take the event,
+    // or wait for the zone lock in EL2
+    cpu_set.iter_except(this_cpu_id()).for_each(|cpu_id| {
+        while !get_cpu_data(cpu_id).left_zone.load(Ordering::Acquire) {
+            core::hint::spin_loop();
+        }
+    });
+    drop(zone);
+    remove_zone(zone_id)
+}
@@ -59,36 +59,148 @@ int open_dev() {
     return fd;
 }
 
-static void get_info(char *optarg, char**path, unsigned long long *address) {
+// "image.bin,addr=0x1000"
+static void get_info(char *optarg, char **path, unsigned long long *address) {
     char *now;
-    *path = strtok(aptarg, ",");
+    *path = strtok(optarg, ",");
     now = strtok(NULL, "=");
-    if (strcmp(now, "addr") == 0) {
+    if (now != NULL && strcmp(now, "addr") == 0) {
         now = strtok(NULL, "=");
-        **address = strtoull(now, NULL, 16);
+        *address = strtoull(now, NULL, 16);
     } else {
         help(1);
     }
 }
 
-// ./hvisor zone start -kernel image.bin 0x1000 -dtb image.dtb 0x2000 -id 1
+static unsigned long long parse_number(const char *s) {
+    char *end;
+    if (s == NULL)
+        help(1);
+    unsigned long long value = strtoull(s, &end, 0);
+    if (*s == '\0' || *end != '\0')
+        help(1);
+    return value;
+}
+
+// split "key=value,..." into values, in the order of keys.
+static void parse_suboptions(char *arg, char *const keys[], char *values[]) {
+    char *value;
+    while (*arg != '\0') {
+        int i = getsubopt(&arg, keys, &value);
+        if (i < 0 || value == NULL)
+            help(1);
+        values[i] = value;
+    }
+}
+
+// "rwx", or a subset of it
+static __u64 parse_mem_flags(const char *s) {
+    __u64 flags = 0;
+    for (; *s != '\0'; s++) {
+        if (*s == 'r')
+            flags |= HVISOR_MEM_READ;
+        else if (*s == 'w')
+            flags |= HVISOR_MEM_WRITE;
+        else if (*s == 'x')
+            flags |= HVISOR_MEM_EXECUTE;
+        else
+            help(1);
+    }
+    return flags;
+}
+
+// "ipa=0x50000000,pa=0x50000000,size=0x1000[,flags=rw]", pa=pool for memory
+// allocated by the hypervisor.
+static void add_region(struct hvisor_memory_region *regions, __u32 *num, int max,
+                       char *arg, __u64 flags) {
+    char *const keys[] = {"ipa", "pa", "size", "flags", NULL};
+    char *values[4] = {NULL};
+    if (*num >= max)
+        help(1);
+    parse_suboptions(arg, keys, values);
+    struct hvisor_memory_region *region = &regions[(*num)++];
+    region->ipa = parse_number(values[0]);
+    if (values[1] != NULL && strcmp(values[1], "pool") == 0)
+        region->pa = HVISOR_CONFIG_ALLOC_PA;
+    else
+        region->pa = parse_number(values[1]);
+    region->size = parse_number(values[2]);
+    region->flags = values[3] ? parse_mem_flags(values[3]) : flags;
+}
+
+// "name=ch0,ipa=0x70000000,size=0x100000,doorbell=0x70100000,irq=80"
+static void add_shmem(struct hvisor_zone_config *config, char *arg) {
+    char *const keys[] = {"name", "ipa", "size", "doorbell", "irq", NULL};
+    char *values[5] = {NULL};
+    if (config->num_shmems >= CONFIG_MAX_SHMEMS)
+        help(1);
+    parse_suboptions(arg, keys, values);
+    struct hvisor_shmem_config *shmem = &config->shmems[config->num_shmems++];
+    if (values[0] == NULL || strlen(values[0]) >= CONFIG_SHMEM_NAME_LEN)
+        help(1);
+    strcpy(shmem->name, values[0]);
+    shmem->ipa = parse_number(values[1]);
+    shmem->size = parse_number(values[2]);
+    shmem->doorbell_ipa = parse_number(values[3]);
+    shmem->irq = parse_number(values[4]);
+}
+
+// "virq=40,pirq=72", pirq=0 for an irq only raised by the hypervisor
+static void add_irq_map(struct hvisor_zone_config *config, char *arg) {
+    char *const keys[] = {"virq", "pirq", NULL};
+    char *values[2] = {NULL};
+    if (config->num_irq_maps >= CONFIG_MAX_IRQ_MAPS)
+        help(1);
+    parse_suboptions(arg, keys, values);
+    struct hvisor_irq_map *map = &config->irq_maps[config->num_irq_maps++];
+    map->virq = parse_number(values[0]);
+    map->pirq = parse_number(values[1]);
+}
+
+// ./hvisor zone start -kernel image.bin,addr=0x50080000 -dtb zone.dtb,addr=0x60000000
+//     -dtb_ipa 0x60000000 -cpus 0x4 -mem ipa=0x50000000,pa=pool,size=0x10000000
+//     [-id 1] [-entry 0x50080000] [-mem ...] [-mmio ipa=0x9000000,pa=0x9000000,size=0x1000]
+//     [-irq 33] [-shmem name=ch0,ipa=..,size=..,doorbell=..,irq=..] [-msg_peers 0x1]
+//     [-msg_irq 77] [-vgic] [-irq_map virq=40,pirq=72]
+// options taking several values can be repeated. without -entry, the entry is taken from
+// the kernel, an arm64 Image or an ELF file. without -id, the hypervisor picks one.
 static int zone_start(int argc, char *argv[]) {
     static struct option long_options[] = {
         {"kernel", required_argument, 0, 'k'},
         {"dtb", required_argument, 0, 'd'},
         {"id", required_argument, 0, 'i'},
+        {"dtb_ipa", required_argument, 0, 'D'},
+        {"cpus", required_argument, 0, 'c'},
+        {"entry", required_argument, 0, 'e'},
+        {"mem", required_argument, 0, 'm'},
+        {"mmio", required_argument, 0, 'M'},
+        {"irq", required_argument, 0, 'q'},
+        {"shmem", required_argument, 0, 's'},
+        {"msg_peers", required_argument, 0, 'p'},
+        {"msg_irq", required_argument, 0, 'Q'},
+        {"vgic", no_argument, 0, 'v'},
+        {"irq_map", required_argument, 0, 'r'},
         {0, 0, 0, 0}
     };
-    char *optstring = "k:d:i:";
-    struct hvisor_zone_load *zone_load;
-    struct hvisor_image_desc *images;
-    int fd, err, opt, zone_id;
+    struct hvisor_zone_load zone_load = {0};
+    struct hvisor_image_desc images[2] = {0};
+    struct hvisor_zone_config *config;
+    int fd, err, opt;
     char *image_path = NULL, *dtb_path = NULL;
-    unsigned long long image_address, dtb_address;
-    zone_id = 0;
-    image_address = dtb_address = 0;
+    unsigned long long image_address = 0, dtb_address = 0;
+    int has_dtb_ipa = 0;
 
-    whil ((opt = getopt_long(argc, argv, optstring, long_options, NULL)) != -1) {
+    config = calloc(1, sizeof(*config));
+    if (config == NULL) {
+        perror("zone_start: calloc failed");
+        return -1;
+    }
+    config->magic = HVISOR_ZONE_CONFIG_MAGIC;
+    config->version = HVISOR_ZONE_CONFIG_VERSION;
+    config->entry_point = HVISOR_CONFIG_IMAGE_ENTRY;
+    // skip "hvisor zone start"
+    optind = 3;
+    while ((opt = getopt_long_only(argc, argv, "", long_options, NULL)) != -1) {
         switch (opt) {
             case 'k':
                 get_info(optarg, &image_path, &image_address);
@@ -97,34 +209,71 @@ static int zone_start(int argc, char *argv[]) {
                 get_info(optarg, &dtb_path, &dtb_address);
                 break;
             case 'i':
-                get_info(optarg, "%d", &zone_id);
+                zone_load.zone_id = parse_number(optarg);
+                break;
+            case 'D':
+                config->dtb_ipa = parse_number(optarg);
+                has_dtb_ipa = 1;
+                break;
+            case 'c':
+                config->cpus = parse_number(optarg);
+                break;
+            case 'e':
+                config->entry_point = parse_number(optarg);
+                break;
+            case 'm':
+                add_region(config->memory_regions, &config->num_memory_regions,
+                           CONFIG_MAX_MEMORY_REGIONS, optarg,
+                           HVISOR_MEM_READ | HVISOR_MEM_WRITE | HVISOR_MEM_EXECUTE);
+                break;
+            case 'M':
+                add_region(config->mmio_regions, &config->num_mmio_regions,
+                           CONFIG_MAX_MMIO_REGIONS, optarg, HVISOR_MEM_READ | HVISOR_MEM_WRITE);
+                break;
+            case 'q':
+                if (config->num_interrupts >= CONFIG_MAX_INTERRUPTS)
+                    help(1);
+                config->interrupts[config->num_interrupts++] = parse_number(optarg);
+                break;
+            case 's':
+                add_shmem(config, optarg);
+                break;
+            case 'p':
+                config->msg_peers = parse_number(optarg);
+                break;
+            case 'Q':
+                config->msg_irq = parse_number(optarg);
+                break;
+            case 'v':
+                config->flags |= HVISOR_ZONE_FLAG_VGIC_EMUL;
+                break;
+            case 'r':
+                add_irq_map(config, optarg);
                 break;
             default:
                 help(1);
         }
     }
-    if (image_path == NULL || dtb_path == NULL || zone_id == 0 || image_address == 0 || dtb_address == 0) {
+    if (optind != argc || image_path == NULL || dtb_path == NULL || image_address == 0 ||
+        dtb_address == 0 || !has_dtb_ipa || config->cpus == 0 || config->num_memory_regions == 0) {
         help(1);
     }
-    zone_load = malloc(sizeof(struct hvisor_zone_load));
-    zone_load->images_num = 2;
-    images = malloc(sizeof(struct hvisor_image_desc)*2);
 
     images[0].source_address = (unsigned long long) read_file(image_path, &images[0].size);
     images[1].source_address = (unsigned long long) read_file(dtb_path, &images[1].size);
     images[0].target_address = image_address;
     images[1].target_address = dtb_address;
-    zone_load->zone_id = zone_id;
-    zone_load->images = images;
+    zone_load.images_num = 2;
+    zone_load.images = images;
+    zone_load.config = config;
     fd = open_dev();
-    err = ioctl(fd, HVISOR_ZONE_START, zone_load);
+    err = ioctl(fd, HVISOR_ZONE_START, &zone_load);
     if (err)
         perror("zone_start: ioctl failed");
     close(fd);
-    for (int i = 0; i < zone_load->images_num; i++)
+    for (int i = 0; i < zone_load.images_num; i++)
         free((void*) images[i].source_address);
-    free(images);
-    free(zone_load);
+    free(config);
     return err;
 }
 
@@ -143,6 +292,148 @@ static int zone_shutdown(int argc, char *argv[]) {
     return err;
 }
 
+// ./hvisor zone pause -id 1, ./hvisor zone resume -id 1, ./hvisor zone reboot -id 1
+static int zone_pause_resume(int argc, char *argv[], unsigned long ioctl_cmd) {
+    if (argc != 2 || strcmp(argv[0], "-id") !=0) {
+        help(1);
+    }
+    __u64 zone_id;
+    sscanf(argv[1], "%llu", &zone_id);
+    int fd = open_dev();
+    int err = ioctl(fd, ioctl_cmd, zone_id);
+    if (err)
+        perror("zone_pause_resume: ioctl failed");
+    close(fd);
+    return err;
+}
+
+// ./hvisor zone list
+static int zone_list(void) {
+    static const char *states[] = {"created", "running", "paused", "shutting down", "failed"};
+    struct hvisor_zone_info zones[CONFIG_MAX_ZONES];
+    struct hvisor_zone_list_args args = {
+        .max_zones = CONFIG_MAX_ZONES,
+        .zones = zones,
+    };
+    int fd = open_dev();
+    int num = ioctl(fd, HVISOR_ZONE_LIST, &args);
+    close(fd);
+    if (num < 0) {
+        perror("zone_list: ioctl failed");
+        return num;
+    }
+    printf("%-8s%-16s%-10s%-10s%s\n", "ID", "STATE", "CPUS", "IRQS", "MEMORY");
+    for (int i = 0; i < num && i < CONFIG_MAX_ZONES; i++) {
+        struct hvisor_zone_info *zone = &zones[i];
+        const char *state = zone->state < 5 ? states[zone->state] : "unknown";
+        printf("%-8u%-16s%#-10llx%-10u", zone->zone_id, state, zone->cpus, zone->num_irqs);
+        for (int j = 0; j < zone->num_memory_regions; j++)
+            printf("%#llx+%#llx ", zone->memory_regions[j].ipa, zone->memory_regions[j].size);
+        printf("\n");
+    }
+    return 0;
+}
+
+// ./hvisor msg send -id 1 "hello"
+static int msg_send(int argc, char *argv[]) {
+    if (argc != 3 || strcmp(argv[0], "-id") !=0) {
+        help(1);
+    }
+    struct hvisor_msg_args args = {
+        .len = strlen(argv[2]),
+        .buf = argv[2],
+    };
+    sscanf(argv[1], "%llu", &args.zone_id);
+    int fd = open_dev();
+    int err = ioctl(fd, HVISOR_MSG_SEND, &args);
+    if (err)
+        perror("msg_send: ioctl failed");
+    close(fd);
+    return err;
+}
+
+// ./hvisor msg recv
+static int msg_recv(void) {
+    char buf[HVISOR_MSG_MAX_SIZE];
+    struct hvisor_msg_args args = {
+        .len = sizeof(buf),
+        .buf = buf,
+    };
+    int fd = open_dev();
+    int err = ioctl(fd, HVISOR_MSG_RECV, &args);
+    close(fd);
+    if (err) {
+        perror("msg_recv: ioctl failed");
+        return err;
+    }
+    printf("from zone %llu: %.*s\n", args.zone_id, (int) args.len, buf);
+    return 0;
+}
+
+// ./hvisor coredump setup -size 0x400000 [-irq 40]
+// a size of 0 frees the buffer and stops the core dumps.
+static int coredump_setup(int argc, char *argv[]) {
+    struct hvisor_coredump_args args = {0};
+    if (argc != 2 && argc != 4)
+        help(1);
+    for (int i = 0; i < argc; i += 2) {
+        if (strcmp(argv[i], "-size") == 0)
+            args.size = strtoull(argv[i + 1], NULL, 16);
+        else if (strcmp(argv[i], "-irq") == 0)
+            args.irq = strtoul(argv[i + 1], NULL, 10);
+        else
+            help(1);
+    }
+    int fd = open_dev();
+    int err = ioctl(fd, HVISOR_COREDUMP_SETUP, &args);
+    if (err)
+        perror("coredump_setup: ioctl failed");
+    close(fd);
+    return err;
+}
+
+// ./hvisor coredump save core.elf
+// write the core dump in the buffer to a file and free the buffer for the next one.
+static int coredump_save(int argc, char *argv[]) {
+    struct hvisor_coredump_header *header;
+    long page_size = sysconf(_SC_PAGESIZE);
+    if (argc != 1)
+        help(1);
+    int fd = open_dev();
+    header = mmap(NULL, page_size, PROT_READ | PROT_WRITE, MAP_SHARED, fd, HVISOR_COREDUMP_MMAP_OFFSET);
+    if (header == MAP_FAILED) {
+        perror("coredump_save: mmap failed");
+        close(fd);
+        return -1;
+    }
+    if (header->magic != HVISOR_COREDUMP_MAGIC || header->size == 0) {
+        printf("no core dump\n");
+        munmap(header, page_size);
+        close(fd);
+        return -1;
+    }
+    size_t len = sizeof(*header) + header->size;
+    void *dump = mmap(NULL, len, PROT_READ, MAP_SHARED, fd, HVISOR_COREDUMP_MMAP_OFFSET);
+    int err = -1;
+    int out = open(argv[0], O_WRONLY | O_CREAT | O_TRUNC, 0600);
+    if (dump == MAP_FAILED || out < 0) {
+        perror("coredump_save: failed");
+    } else if (write(out, (char *) dump + sizeof(*header), header->size) != (ssize_t) header->size) {
+        perror("coredump_save: write failed");
+    } else {
+        printf("core dump of zone %u saved to %s\n", header->zone_id, argv[0]);
+        header->size = 0;
+        err = 0;
+    }
+    if (out >= 0)
+        close(out);
+    if (dump != MAP_FAILED)
+        munmap(dump, len);
+    munmap(header, page_size);
+    close(fd);
+    return err;
+}
+
 int main(int argc, char *argv[])
 {
     int err;
@@ -152,8 +443,24 @@ int main(int argc, char *argv[])
 
     if (strcmp(argv[1], "zone") == 0 && strcmp(argv[2], "start") == 0) {
         err = zone_start(argc, argv);
+    } else if (strcmp(argv[1], "zone") == 0 && strcmp(argv[2], "pause") == 0) {
+        err = zone_pause_resume(argc - 3, &argv[3], HVISOR_ZONE_PAUSE);
+    } else if (strcmp(argv[1], "zone") == 0 && strcmp(argv[2], "resume") == 0) {
+        err = zone_pause_resume(argc - 3, &argv[3], HVISOR_ZONE_RESUME);
+    } else if (strcmp(argv[1], "zone") == 0 && strcmp(argv[2], "reboot") == 0) {
+        err = zone_pause_resume(argc - 3, &argv[3], HVISOR_ZONE_REBOOT);
+    } else if (strcmp(argv[1], "zone") == 0 && strcmp(argv[2], "list") == 0) {
+        err = zone_list();
     } else if (strcmp(argv[1], "zone") == 0 && strcmp(argv[2], "shutdown") == 0) {
         err = zone_shutdown(argc - 3, &argv[3]);
+    } else if (strcmp(argv[1], "msg") == 0 && strcmp(argv[2], "send") == 0) {
+        err = msg_send(argc - 3, &argv[3]);
+    } else if (strcmp(argv[1], "msg") == 0 && strcmp(argv[2], "recv") == 0) {
+        err = msg_recv();
+    } else if (strcmp(argv[1], "coredump") == 0 && strcmp(argv[2], "setup") == 0) {
+        err = coredump_setup(argc - 3, &argv[3]);
+    } else if (strcmp(argv[1], "coredump") == 0 && strcmp(argv[2], "save") == 0) {
+        err = coredump_save(argc - 3, &argv[3]);
     } else if (strcmp(argv[1], "virtio") == 0 && strcmp(argv[2], "start") == 0) {
         err = virtio_start(argc, argv);
     } else {